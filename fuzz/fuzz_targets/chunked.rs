@@ -0,0 +1,36 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use rtcp::chunked::ChunkedDecoder;
+
+fuzz_target!(|data: &[u8]| {
+    // 一次性解码和逐字节解码的结果必须一致
+    let mut decoder = ChunkedDecoder::new();
+    let mut body = BytesMut::new();
+    let whole = decoder.decode(data, Some(&mut body));
+
+    let mut split = ChunkedDecoder::new();
+    let mut split_body = BytesMut::new();
+    let mut buf = BytesMut::new();
+    let mut used = 0;
+    let mut res = Ok(0);
+    for b in data {
+        if split.is_done() {
+            break;
+        }
+        buf.extend_from_slice(&[*b]);
+        res = split.decode(&buf, Some(&mut split_body));
+        let Ok(n) = res else { break };
+        let _ = buf.split_to(n);
+        used += n;
+    }
+    match whole {
+        Ok(n) => {
+            assert!(res.is_ok());
+            assert_eq!(n, used);
+            assert_eq!(body, split_body);
+        }
+        Err(_) => assert!(res.is_err()),
+    }
+});
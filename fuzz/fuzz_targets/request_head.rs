@@ -0,0 +1,20 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use rtcp::parser::{parser_request_head_all, parser_response_head_all};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((rest, (_, headers))) = parser_request_head_all(data) {
+        assert!(data.ends_with(rest));
+        // 解析成功的头部字段重新输出后仍然可以解析
+        let mut head = BytesMut::from(&b"GET / HTTP/1.1\r\n"[..]);
+        headers.write_to(&mut head);
+        head.extend_from_slice(b"\r\n");
+        let (_, (_, again)) = parser_request_head_all(&head).unwrap();
+        assert_eq!(again.len(), headers.len());
+    }
+    if let Ok((rest, _)) = parser_response_head_all(data) {
+        assert!(data.ends_with(rest));
+    }
+});
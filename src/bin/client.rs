@@ -1,184 +1,966 @@
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use bytes::{Buf, BytesMut};
-use clap::Parser;
+use clap::{ArgGroup, Parser, Subcommand};
+use deadpool::managed::Object;
 use rtcp::{
-    protocol::{RTCPMessage, RTCPType},
-    tcp_pool::{Pool, TcpPoolManager},
+    auth,
+    config::{self, ClientConfig, InitErrorPolicy},
+    mux::Multiplexer,
+    protocol::{
+        write_msg, Codec, ConnectId, MessageReader, RTCPMessage, RTCPType, RejectReason, TunnelId,
+        TunnelMode,
+    },
+    proxy_protocol::ProxyProtocol,
+    tcp_pool::{self, Pool, TcpPoolManager, DEFAULT_CONNECT_TIMEOUT},
+    tls::{RtcpStream, StreamConnector, TlsVerify},
+    transformer::{error_response, HttpError, HttpTransformer},
+    tunnel::{pair_tunnels, renumber_tunnels, HostHeader, TunnelAccess, TunnelSpec},
 };
 use tokio::{
-    io::{self, AsyncReadExt, AsyncWriteExt},
-    net::{tcp::OwnedReadHalf, TcpSocket, TcpStream},
-    time::sleep,
+    io::{
+        self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf,
+    },
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
+    time::{sleep, timeout},
 };
+
+/// 检查配置文件是否修改的间隔
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// 连接后端失败后，等待服务器发来请求和关闭代理连接的最长时间
+const LINGER_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// 用户地址和用户连接的服务器地址，用于构造 PROXY 头，服务器没有告知时为 None
+type UserAddrs = (Option<SocketAddr>, Option<SocketAddr>);
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
+#[command(group(ArgGroup::new("access").args(["access_port", "host"])))]
 struct Args {
-    /// 被代理服务器 ip
-    #[arg(short, long)]
-    ip: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// toml 配置文件，命令行参数优先于配置文件
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// 被代理服务器 ip，设置后命令行的隧道代替配置文件中的隧道
+    #[arg(short, long, requires_all = ["port", "access"])]
+    ip: Option<String>,
 
     /// 被代理服务器端口
-    #[arg(short, long)]
-    port: u16,
+    #[arg(short, long, requires = "ip")]
+    port: Option<u16>,
 
-    /// 访问端口
-    #[arg(short, long)]
-    access_port: u16,
+    /// 访问端口，0 表示由服务器分配
+    #[arg(short, long, requires = "ip")]
+    access_port: Option<u16>,
+
+    /// 在服务器的共享 http 端口上注册的主机名，例如 `alice` 或 `alice.tunnel.example.com`
+    #[arg(long, requires = "ip", conflicts_with_all = ["access_port", "legacy_text"])]
+    host: Option<String>,
 
-    /// rtcp 服务器ip
+    /// 转发给后端时的 Host 头：`preserve` 保留原始 Host，`backend` 改写为后端 `ip:port`，其他值直接作为 Host
+    #[arg(long, default_value = "preserve", conflicts_with = "legacy_text")]
+    host_header: HostHeader,
+
+    /// 隧道的转发方式：`http` 解析请求并改写请求头，`tcp` 原样转发，用于数据库、SSH、gRPC 等协议
+    #[arg(long, default_value = "http", conflicts_with = "legacy_text")]
+    mode: TunnelMode,
+
+    /// 连接后端时先发送 PROXY protocol 头（v1 或 v2），让后端拿到用户的真实地址
+    #[arg(long, conflicts_with = "legacy_text")]
+    proxy_protocol: Option<ProxyProtocol>,
+
+    /// 额外的隧道 `backend_ip:backend_port:access_port` 或 `backend_ip:backend_port:host`，可以重复指定，
+    /// 后面可以跟 `,mode=tcp`、`,host=backend`、`,proxy=v2` 等选项
+    #[arg(long = "tunnel", conflicts_with = "legacy_text")]
+    tunnels: Vec<TunnelSpec>,
+
+    /// rtcp 服务器的主机名或 ip，可以是 IPv6 地址
     #[arg(short, long)]
-    server: String,
+    server: Option<String>,
+
+    /// rtcp 服务器控制通道的端口，对应服务器的 `--control-addr`，默认 5541
+    #[arg(long)]
+    server_port: Option<u16>,
+
+    /// rtcp 服务器代理连接的端口，对应服务器的 `--data-addr`，默认 5533
+    #[arg(long)]
+    data_port: Option<u16>,
+
+    /// 使用旧版文本协议，兼容未升级的服务器
+    #[arg(long)]
+    legacy_text: bool,
+
+    /// client 标识，服务器开启认证时必填
+    #[arg(long, requires = "token")]
+    client_id: Option<String>,
+
+    /// client 认证令牌
+    #[arg(long, requires = "client_id")]
+    token: Option<String>,
+
+    /// 使用 tls 连接服务器，并用该 CA 证书文件校验服务器证书
+    #[arg(long, conflicts_with = "tls_fingerprint")]
+    tls_ca: Option<PathBuf>,
+
+    /// 使用 tls 连接服务器，并校验服务器证书的 sha256 指纹
+    #[arg(long)]
+    tls_fingerprint: Option<String>,
+
+    /// tls 校验使用的服务器名称，默认为 rtcp 服务器ip
+    #[arg(long)]
+    tls_server_name: Option<String>,
+
+    /// 多路复用模式，所有用户连接都通过控制连接传输，不再单独建立代理连接
+    #[arg(long, conflicts_with = "legacy_text")]
+    mux: bool,
+
+    /// 服务器拒绝初始化时的处理方式，默认 auto
+    #[arg(long, value_enum)]
+    on_init_error: Option<InitErrorPolicy>,
+
+    /// 连接服务器和后端的超时时间（秒），包括域名解析和 tls 握手，默认 10
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    connect_timeout: Option<u64>,
 }
-pub struct Client {
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// 检查配置文件，有错误时输出出错的行号
+    Validate {
+        /// toml 配置文件
+        config: PathBuf,
+    },
+}
+
+/// 一次会话结束后的处理
+enum SessionEnd {
+    /// 重新连接服务器
+    Reconnect,
+    /// 停止 client
+    Exit(io::Error),
+}
+
+/// client 认证凭据
+#[derive(Debug, Clone)]
+pub struct Credential {
+    pub client_id: String,
+    pub token: String,
+}
+
+/// rtcp 服务器的地址
+#[derive(Debug, Clone)]
+pub struct ServerAddr {
+    /// 服务器主机名或 ip
+    pub host: String,
+    /// 控制通道端口
+    pub control_port: u16,
+    /// 代理连接端口
+    pub data_port: u16,
+}
+
+/// 已配置的隧道
+#[derive(Clone)]
+struct Tunnel {
+    spec: TunnelSpec,
+    /// 真实后端连接池
     back_end_pool: Pool,
-    /// rtcp 服务器ip
-    server_ip: String,
+}
+
+impl Tunnel {
+    fn new(id: TunnelId, spec: TunnelSpec, connect_timeout: Duration) -> Self {
+        let mgr = TcpPoolManager::new(
+            format!("backend_{id}"),
+            spec.backend_ip.clone(),
+            spec.backend_port,
+        )
+        .with_connect_timeout(connect_timeout);
+        Tunnel {
+            spec,
+            back_end_pool: Pool::builder(mgr).build().unwrap(),
+        }
+    }
+
+    /// 后端连接能否给之后的用户连接复用，PROXY 头和 tcp 协议的状态都绑定在连接上
+    fn reuses_backend(&self) -> bool {
+        self.spec.proxy_protocol.is_none() && self.spec.mode == TunnelMode::Http
+    }
+}
+
+/// 隧道和当前会话，重新加载配置时修改
+struct ClientState {
+    /// 隧道 id -> 隧道，0 号隧道通过 Initialize 注册，其余通过 TunnelAdd 注册
+    tunnels: HashMap<TunnelId, Tunnel>,
+    /// 下一个新增隧道的 id，同一个会话中 id 不重复使用，避免和已移除隧道的连接混淆
+    next_id: TunnelId,
+    /// 当前会话发往服务器的消息通道，未连接时为 None
+    session: Option<Sender<RTCPMessage>>,
+    /// 当前会话是否已经请求服务器告知用户地址
+    peer_addr: bool,
+}
+
+pub struct Client {
+    state: Mutex<ClientState>,
+    /// rtcp 服务器地址
+    server: ServerAddr,
 
     proxy_pool: Pool,
+    /// 控制通道消息格式
+    codec: Codec,
+    /// 认证凭据，None 表示不认证
+    credential: Option<Credential>,
+    /// 控制通道和代理连接的 tls 配置
+    connector: StreamConnector,
+    /// 是否使用多路复用模式
+    mux: bool,
+    /// 服务器拒绝初始化时的处理方式
+    on_init_error: InitErrorPolicy,
+    /// 连接服务器和后端的超时时间
+    connect_timeout: Duration,
 }
 
 impl Client {
-    pub fn new(backend_ip: String, backend_port: u16, server_ip: String) -> Self {
-        let mgr = TcpPoolManager::new("nestjs".to_string(), backend_ip, backend_port);
-        let back_end_pool = Pool::builder(mgr).build().unwrap();
-
-        let mgr_proxy = TcpPoolManager::new("mgr_proxy".to_string(), server_ip.clone(), 5533);
-        let proxy_pool = Pool::builder(mgr_proxy).build().unwrap();
+    pub fn new(
+        tunnel_specs: Vec<TunnelSpec>,
+        server: ServerAddr,
+        codec: Codec,
+        credential: Option<Credential>,
+        connector: StreamConnector,
+        mux: bool,
+        on_init_error: InitErrorPolicy,
+    ) -> Self {
+        let tunnels: HashMap<_, _> = tunnel_specs
+            .into_iter()
+            .enumerate()
+            .map(|(id, spec)| {
+                let id = id as TunnelId;
+                (id, Tunnel::new(id, spec, DEFAULT_CONNECT_TIMEOUT))
+            })
+            .collect();
+        let state = ClientState {
+            next_id: tunnels.len() as TunnelId,
+            tunnels,
+            session: None,
+            peer_addr: false,
+        };
 
         Client {
-            back_end_pool,
-            server_ip,
-            proxy_pool,
+            state: Mutex::new(state),
+            proxy_pool: Self::proxy_pool(&server, &connector, DEFAULT_CONNECT_TIMEOUT),
+            server,
+            codec,
+            credential,
+            connector,
+            mux,
+            on_init_error,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
         }
     }
 
-    /// 启动代理
-    pub async fn start(&self, access_port: u16) {
+    /// 设置连接服务器和后端的超时时间
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self.proxy_pool = Self::proxy_pool(&self.server, &self.connector, connect_timeout);
+        for (id, tunnel) in self.state.get_mut().tunnels.iter_mut() {
+            *tunnel = Tunnel::new(*id, tunnel.spec.clone(), connect_timeout);
+        }
+        self
+    }
+
+    /// 代理连接池
+    fn proxy_pool(
+        server: &ServerAddr,
+        connector: &StreamConnector,
+        connect_timeout: Duration,
+    ) -> Pool {
+        let mgr_proxy = TcpPoolManager::new(
+            "mgr_proxy".to_string(),
+            server.host.clone(),
+            server.data_port,
+        )
+        .with_connector(connector.clone())
+        .with_connect_timeout(connect_timeout);
+        Pool::builder(mgr_proxy).build().unwrap()
+    }
+
+    /// 启动代理，只有认证被拒绝或按策略放弃初始化时才返回
+    pub async fn start(&self) -> io::Result<()> {
         loop {
-            let addr = format!("{}:5541", self.server_ip).parse().unwrap();
-            let tcp = TcpSocket::new_v4().unwrap();
+            // 每次重连都重新解析，服务器地址变化后可以连上新的地址
+            let connect = tcp_pool::connect(&self.server.host, self.server.control_port);
+            let tcp_stream = match timeout(self.connect_timeout, connect).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    println!("❌连接失败，开始重试,{e}");
+                    sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+                Err(_) => {
+                    println!("❌连接超时，开始重试");
+                    sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            let mut client_stream = match self.connector.connect(tcp_stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("❌tls 握手失败，开始重试,{e:?}");
+                    sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            let mut reader = MessageReader::new(self.codec);
 
-            let connect_res = tcp.connect(addr).await;
-            if connect_res.is_err() {
-                println!("❌连接失败，开始重试,{:?}", connect_res);
+            if let Err(e) = self.authenticate(&mut client_stream, &mut reader).await {
+                if e.kind() == io::ErrorKind::PermissionDenied {
+                    println!("❌认证被拒绝，停止重试 {e}");
+                    return Err(e);
+                }
+                println!("❌认证过程出错，开始重试 {e:?}");
                 sleep(Duration::from_secs(1)).await;
                 continue;
             }
 
-            let mut client_stream = connect_res.unwrap();
-
-            self.send_init_msg(&mut client_stream, access_port).await;
-
-            let (reader_stream, mut writer_stream) = client_stream.into_split();
-            tokio::spawn(async move {
+            let (reader_stream, writer_stream) = io::split(client_stream);
+            // 发往服务器的消息统一经过该通道写出
+            let (tx, rx) = mpsc::channel::<RTCPMessage>(1000);
+            let writer_handle = tokio::spawn(Self::write_loop(writer_stream, rx, self.codec));
+            self.send_init_msg(&tx).await;
+            let heartbeat_tx = tx.clone();
+            let heartbeat_handle = tokio::spawn(async move {
                 loop {
                     sleep(Duration::from_secs(10)).await;
                     let msg = RTCPMessage::new(RTCPType::Heartbeat);
-                    writer_stream.write_all(msg.serialize().as_ref()).await;
-                    writer_stream.flush().await;
+                    if heartbeat_tx.send(msg).await.is_err() {
+                        break;
+                    }
                 }
             });
-
-            self.server_msg_handel(reader_stream).await;
+            let mux = self.mux.then(|| Multiplexer::new(tx, false));
+
+            let end = self
+                .server_msg_handel(reader_stream, reader, mux.clone())
+                .await;
+            self.state.lock().await.session = None;
+            heartbeat_handle.abort();
+            writer_handle.abort();
+            if let Some(mux) = mux {
+                mux.close_all();
+            }
+            if let SessionEnd::Exit(e) = end {
+                return Err(e);
+            }
+            sleep(Duration::from_secs(1)).await;
         }
     }
 
-    async fn send_init_msg(&self, client_stream: &mut TcpStream, access_port: u16) {
-        let init_msg = RTCPMessage::new(RTCPType::Initialize(access_port));
-
-        client_stream
-            .write_all(&init_msg.serialize())
-            .await
-            .unwrap();
-        client_stream.flush().await.unwrap();
-    }
+    /// 完成认证握手，被服务器拒绝时返回 PermissionDenied
+    async fn authenticate(
+        &self,
+        client_stream: &mut RtcpStream,
+        reader: &mut MessageReader,
+    ) -> io::Result<()> {
+        let Some(credential) = &self.credential else {
+            return Ok(());
+        };
 
-    async fn server_msg_handel(&self, mut client_stream: OwnedReadHalf) {
-        let mut buf = BytesMut::with_capacity(40 * 1024);
+        let msg = RTCPMessage::new(RTCPType::Auth(credential.client_id.clone()));
+        write_msg(client_stream, &msg, self.codec).await?;
 
         loop {
-            client_stream.read_buf(&mut buf).await.unwrap();
+            let msg = reader.read_msg(client_stream).await?;
+            match msg.message_type {
+                RTCPType::AuthChallenge(nonce) => {
+                    let response =
+                        auth::compute_response(&credential.token, &nonce, &credential.client_id);
+                    let msg = RTCPMessage::new(RTCPType::AuthResponse(response.into()));
+                    write_msg(client_stream, &msg, self.codec).await?;
+                }
+                RTCPType::AuthOk => {
+                    println!("✅认证成功");
+                    return Ok(());
+                }
+                RTCPType::Error(reason) => {
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, reason));
+                }
+                other => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unexpected message during auth: {other}"),
+                    ));
+                }
+            }
+        }
+    }
 
-            if buf.is_empty() {
-                println!("❌读取为空，5541 服务器断开连接");
+    /// 把通道中的消息写给服务器
+    async fn write_loop(
+        mut writer_stream: WriteHalf<RtcpStream>,
+        mut rx: Receiver<RTCPMessage>,
+        codec: Codec,
+    ) {
+        while let Some(msg) = rx.recv().await {
+            if let Err(e) = write_msg(&mut writer_stream, &msg, codec).await {
+                println!("❌发送消息失败{e:?}");
                 break;
             }
+        }
+    }
 
-            // 一次读取的数据中可能包含多个 msg，需要全部解析出来
-            loop {
-                let res = RTCPMessage::deserialize(&buf);
-                // 遇到错误读取错误, 退出当前循环，继续读取消息 TODO: 这里只应该处理解析长度不足的错误，其他错误都应该 rethrow
-                if res.is_err() {
-                    break;
+    /// 注册全部隧道，之后重新加载配置时通过该会话增加或移除隧道
+    async fn send_init_msg(&self, tx: &Sender<RTCPMessage>) {
+        let mut state = self.state.lock().await;
+        let mut messages = vec![];
+        if self.mux {
+            messages.push(RTCPType::Multiplex);
+        }
+        // 发送 PROXY 头需要知道每个用户连接的地址
+        state.peer_addr = state
+            .tunnels
+            .values()
+            .any(|t| t.spec.proxy_protocol.is_some());
+        if state.peer_addr {
+            messages.push(RTCPType::PeerAddr);
+        }
+        // 上一个会话结束后隧道 id 不再有效，重新编号让 id 最小的隧道通过 Initialize 注册
+        let tunnels = renumber_tunnels(std::mem::take(&mut state.tunnels));
+        state.next_id = tunnels.len() as TunnelId;
+        state.tunnels = tunnels;
+        let mut tunnel_ids: Vec<_> = state.tunnels.keys().copied().collect();
+        tunnel_ids.sort();
+        for id in tunnel_ids {
+            messages.extend(state.tunnels[&id].spec.register_msgs(id));
+        }
+        for message_type in messages {
+            let _ = tx.send(RTCPMessage::new(message_type)).await;
+        }
+        state.session = Some(tx.clone());
+    }
+
+    /// 按新的隧道配置增加、移除或修改隧道，控制连接和已经建立的用户连接不受影响。
+    /// 访问方式相同的隧道视为同一个隧道：只有后端地址变化时直接替换后端连接池，
+    /// 服务器端的选项变化时先移除再用新的 id 重新注册
+    pub async fn reload(&self, specs: Vec<TunnelSpec>) {
+        if self.codec == Codec::Text {
+            println!("❌旧版文本协议不支持重新加载隧道");
+            return;
+        }
+        let mut state = self.state.lock().await;
+        let mut old = std::mem::take(&mut state.tunnels);
+        let mut tunnels = HashMap::new();
+        let mut removes = vec![];
+        let mut adds = vec![];
+        let mut old_ids: Vec<_> = old.keys().copied().collect();
+        old_ids.sort_unstable();
+        let old_specs: Vec<_> = old_ids.iter().map(|id| old[id].spec.clone()).collect();
+        let pairs = pair_tunnels(&old_specs, &specs);
+        for (spec, pair) in specs.into_iter().zip(pairs) {
+            match pair.and_then(|index| old.remove_entry(&old_ids[index])) {
+                Some((id, tunnel)) if tunnel.spec == spec => {
+                    tunnels.insert(id, tunnel);
+                }
+                Some((id, tunnel))
+                    if tunnel.spec.mode == spec.mode
+                        && tunnel.spec.host_rewrite() == spec.host_rewrite()
+                        && (spec.proxy_protocol.is_none() || state.peer_addr) =>
+                {
+                    println!(
+                        "✅{} 的后端改为 {}:{}",
+                        spec.access, spec.backend_ip, spec.backend_port
+                    );
+                    // 已经建立的用户连接继续使用原来的连接池
+                    tunnels.insert(id, Tunnel::new(id, spec, self.connect_timeout));
                 }
+                Some((id, _)) => {
+                    removes.push(id);
+                    adds.push(spec);
+                }
+                None => adds.push(spec),
+            }
+        }
+        removes.extend(old.into_keys());
+
+        let mut messages: Vec<_> = removes.into_iter().map(RTCPType::TunnelRemove).collect();
+        if !state.peer_addr && adds.iter().any(|spec| spec.proxy_protocol.is_some()) {
+            state.peer_addr = true;
+            messages.push(RTCPType::PeerAddr);
+        }
+        for spec in adds {
+            // 0 号隧道只在会话开始时通过 Initialize 注册
+            let id = state.next_id.max(1);
+            state.next_id = id + 1;
+            println!(
+                "✅增加隧道 {id} {}:{} -> {}",
+                spec.backend_ip, spec.backend_port, spec.access
+            );
+            messages.extend(spec.register_msgs(id));
+            tunnels.insert(id, Tunnel::new(id, spec, self.connect_timeout));
+        }
+        state.tunnels = tunnels;
 
-                let (rtcp_message, size) = res.unwrap();
+        // 未连接时不需要通知服务器，下次会话开始时注册全部隧道
+        if let Some(tx) = &state.session {
+            for message_type in messages {
+                let _ = tx.send(RTCPMessage::new(message_type)).await;
+            }
+        }
+    }
+
+    /// 当前的隧道
+    async fn tunnel(&self, id: TunnelId) -> Option<Tunnel> {
+        self.state.lock().await.tunnels.get(&id).cloned()
+    }
 
-                buf.advance(size);
+    async fn server_msg_handel(
+        &self,
+        mut client_stream: ReadHalf<RtcpStream>,
+        mut reader: MessageReader,
+        mux: Option<Arc<Multiplexer>>,
+    ) -> SessionEnd {
+        loop {
+            let rtcp_message = match reader.read_msg(&mut client_stream).await {
+                Ok(msg) => msg,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    println!("❌收到损坏的消息，断开重连 {e:?}");
+                    return SessionEnd::Reconnect;
+                }
+                Err(e) => {
+                    println!("❌读取为空，服务器控制通道断开连接 {e:?}");
+                    return SessionEnd::Reconnect;
+                }
+            };
 
-                match rtcp_message.message_type {
-                    RTCPType::Initialize(_) => println!("🔥客户端不需要实现"),
-                    RTCPType::NewConnection => {
-                        self.create_proxy_connection();
+            match rtcp_message.message_type {
+                RTCPType::Initialize(_) => println!("🔥客户端不需要实现"),
+                RTCPType::InitializeOk(port) => println!("✅访问端口 {port} 注册成功"),
+                RTCPType::InitializeError(reason, message) => {
+                    return self.init_failed(reason, message);
+                }
+                RTCPType::NewConnection(tunnel) => match self.tunnel(tunnel).await {
+                    Some(tunnel) => {
+                        let addrs = (rtcp_message.peer_addr, rtcp_message.local_addr);
+                        self.create_proxy_connection(tunnel, rtcp_message.connect_id, addrs);
                         println!("✅创建连接成功");
                     }
-                    RTCPType::CloseConnection => println!("🔥客户端不需要实现"),
-                    RTCPType::Heartbeat => {}
+                    None => println!("❌未知的隧道 {tunnel}，忽略新连接"),
+                },
+                RTCPType::TunnelOk(tunnel, port) => match self.tunnel(tunnel).await {
+                    Some(Tunnel { spec, .. }) => match &spec.access {
+                        TunnelAccess::Port(_) => println!(
+                            "✅隧道 {}:{} -> 访问端口 {port} 注册成功",
+                            spec.backend_ip, spec.backend_port
+                        ),
+                        TunnelAccess::Host(host) => println!(
+                            "✅隧道 {}:{} -> 主机名 {host}（端口 {port}）注册成功",
+                            spec.backend_ip, spec.backend_port
+                        ),
+                    },
+                    None => println!("✅隧道 {tunnel} 已移除"),
+                },
+                // 主隧道用主机名注册时没有 Initialize，失败按初始化失败处理
+                RTCPType::TunnelError(0, reason, message) => {
+                    return self.init_failed(reason, message);
+                }
+                RTCPType::TunnelError(tunnel, reason, message) => {
+                    println!("❌隧道 {tunnel} 注册失败 {reason} {message}");
+                }
+                RTCPType::Error(reason) => {
+                    println!("❌服务器返回错误，断开重连 {reason}");
+                    return SessionEnd::Reconnect;
+                }
+                RTCPType::StreamOpen(id, tunnel) => match &mux {
+                    // 必须在处理后续数据帧之前注册逻辑流，未知隧道的流接受后立即关闭
+                    Some(mux) => match (mux.accept(id), self.tunnel(tunnel).await) {
+                        (Ok(stream), Some(tunnel)) => {
+                            let addrs = (rtcp_message.peer_addr, rtcp_message.local_addr);
+                            self.accept_stream(tunnel, stream, addrs)
+                        }
+                        (Ok(_), None) => println!("❌未知的隧道 {tunnel}，关闭逻辑流 {id}"),
+                        (Err(e), _) => println!("❌打开逻辑流失败 {e:?}"),
+                    },
+                    None => println!("❌未开启多路复用，忽略逻辑流 {id}"),
+                },
+                RTCPType::Heartbeat => {}
+                other => {
+                    let handled = mux.as_ref().is_some_and(|mux| mux.handle_frame(&other));
+                    if !handled {
+                        println!("🔥客户端不需要实现 {other}");
+                    }
                 }
             }
         }
     }
 
+    /// 主隧道注册失败时按策略决定重连还是退出
+    fn init_failed(&self, reason: RejectReason, message: String) -> SessionEnd {
+        if self.on_init_error.should_retry(reason) {
+            println!("❌初始化失败 {reason} {message}，断开重连");
+            return SessionEnd::Reconnect;
+        }
+        println!("❌初始化失败 {reason} {message}，停止重试");
+        SessionEnd::Exit(io::Error::other(format!("{reason}: {message}")))
+    }
+
     /// 创建后端连接池
-    fn create_proxy_connection(&self) {
-        // 真实后端连接池
-        let back_end_pool = self.back_end_pool.clone();
+    fn create_proxy_connection(&self, tunnel: Tunnel, connect_id: ConnectId, addrs: UserAddrs) {
         let proxy_pool = self.proxy_pool.clone();
+        let codec = self.codec;
 
         tokio::spawn(async move {
-            let mut b_tcp = back_end_pool.get().await.unwrap();
-            let mut proxy_stream = proxy_pool.get().await.unwrap();
+            // 连不上服务器时放弃这个用户连接，服务器等待超时后会关闭它
+            let mut proxy_stream = match proxy_pool.get().await {
+                Ok(proxy_stream) => proxy_stream,
+                Err(e) => {
+                    println!("❌建立代理连接失败 {e}");
+                    return;
+                }
+            };
 
-            let (mut back_end_reader, mut back_end_writer) = b_tcp.stream.split();
-            let (mut client_reader, mut client_writer) = proxy_stream.stream.split();
+            // 声明代理连接对应的用户请求，旧版文本协议的服务器不认识 Attach 帧
+            if let (Codec::Binary, Some(connect_id)) = (codec, connect_id) {
+                let msg = RTCPMessage::new(RTCPType::Attach(connect_id));
+                if let Err(e) = write_msg(&mut proxy_stream.stream, &msg, codec).await {
+                    println!("❌发送 Attach 帧失败 {e:?}");
+                    proxy_stream.disconnect = true;
+                    return;
+                }
+            }
 
-            let is_back_end_close = loop {
-                let (size, is_back_end_close) = tokio::select! {
-                    res = io::copy(&mut back_end_reader, &mut client_writer) => {
-                        // println!("🚌 后端读取结束并写入到代理客户端 {:?}",res);
-                        let size = res.unwrap_or_default();
+            Self::bridge(&tunnel, addrs, &mut proxy_stream.stream).await;
 
-                        (size,true)
-                    },
-                    res = io::copy(&mut client_reader, &mut back_end_writer) => {
-                        // println!("🔐 用户客户端读取并写入到后端 {:?}",res);
-                        let size = res.unwrap_or_default();
+            proxy_stream.disconnect = true;
+            let _ = proxy_stream.stream.shutdown().await;
+        });
+    }
 
-                        (size,false)
-                    },
-                };
+    /// 把服务器打开的逻辑流转发到后端
+    fn accept_stream(&self, tunnel: Tunnel, mut stream: DuplexStream, addrs: UserAddrs) {
+        tokio::spawn(async move {
+            Self::bridge(&tunnel, addrs, &mut stream).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+
+    /// 在代理连接和后端连接之间转发数据，结束后按隧道配置决定后端连接能否复用
+    async fn bridge<S>(tunnel: &Tunnel, addrs: UserAddrs, proxy_stream: &mut S)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut b_tcp = match tunnel.back_end_pool.get().await {
+            Ok(b_tcp) => b_tcp,
+            Err(e) => {
+                println!(
+                    "❌连接后端 {}:{} 失败 {e}",
+                    tunnel.spec.backend_ip, tunnel.spec.backend_port
+                );
+                // http 用户收到 502，tcp 隧道直接关闭连接
+                if tunnel.spec.mode == TunnelMode::Http {
+                    // 等请求到达后再回复，响应要跟在请求之后
+                    let mut buf = [0u8; 1024];
+                    let _ = timeout(LINGER_TIMEOUT, proxy_stream.read(&mut buf)).await;
+                    let _ = proxy_stream
+                        .write_all(&error_response(502, "Bad Gateway"))
+                        .await;
+                }
+                // 读完服务器已经转发的数据再关闭，未读的数据会让连接以 RST 结束，服务器可能收不到响应
+                let _ = proxy_stream.shutdown().await;
+                let _ = timeout(LINGER_TIMEOUT, io::copy(proxy_stream, &mut io::sink())).await;
+                return;
+            }
+        };
+        if let Some(proxy_protocol) = tunnel.spec.proxy_protocol {
+            // PROXY 头只能在连接开始时发送一次
+            b_tcp.disconnect = true;
+            let (src, dst) = addrs;
+            let header = proxy_protocol.header(src, dst);
+            if let Err(e) = b_tcp.stream.write_all(&header).await {
+                println!("❌发送 PROXY 头失败 {e:?}");
+                return;
+            }
+        }
 
-                if size == 0 {
-                    break is_back_end_close;
+        let reusable = match tunnel.spec.mode {
+            TunnelMode::Http => Self::copy_until_close(&mut b_tcp.stream, proxy_stream).await,
+            TunnelMode::Tcp => {
+                if let Err(e) = io::copy_bidirectional(&mut b_tcp.stream, proxy_stream).await {
+                    println!("❌tcp 转发中断 {e:?}");
+                }
+                false
+            }
+        };
+
+        if !tunnel.reuses_backend() {
+            // 不能复用的连接直接关闭，不再放回连接池
+            let mut b_tcp = Object::take(b_tcp);
+            let _ = b_tcp.stream.shutdown().await;
+        } else if !reusable {
+            b_tcp.disconnect = true;
+            // proxy_stream.latest_time = Some(std::time::Instant::now());
+        } else {
+            b_tcp.latest_time = Some(std::time::Instant::now());
+        }
+    }
+
+    /// 按 http 消息转发数据直到任意一端断开，返回后端连接能否复用：
+    /// 后端断开、已经升级协议（例如 WebSocket）或者还有没返回的响应时不能复用
+    async fn copy_until_close<S>(back_end: &mut RtcpStream, proxy_stream: &mut S) -> bool
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut back_end_reader, mut back_end_writer) = io::split(back_end);
+        let (mut client_reader, mut client_writer) = io::split(proxy_stream);
+        // 服务器已经改写过请求，这里只需要找出请求和响应的边界
+        let mut requests = HttpTransformer::passthrough();
+        let mut responses = requests.response_transformer(Arc::default());
+        // 已转发的请求数和已返回的响应数
+        let sent = AtomicUsize::new(0);
+        let received = AtomicUsize::new(0);
+
+        let (reusable, rejected) = {
+            let response = async {
+                loop {
+                    match responses
+                        .copy(&mut back_end_reader, &mut client_writer)
+                        .await
+                    {
+                        Ok(Some(_)) => {
+                            received.fetch_add(1, Ordering::Relaxed);
+                        }
+                        // 后端断开，或者升级之后的连接已经结束
+                        Ok(None) | Err(HttpError::Io(_)) => return,
+                        // 响应无法解析时还没有转发这条响应，由服务器告知用户
+                        Err(HttpError::Reject(status, reason)) => {
+                            println!("❌后端响应错误 {status} {reason}");
+                            let _ = client_writer
+                                .write_all(&error_response(status, reason))
+                                .await;
+                            return;
+                        }
+                    }
                 }
             };
+            tokio::pin!(response);
+            loop {
+                tokio::select! {
+                    res = requests.copy(&mut client_reader, &mut back_end_writer) => match res {
+                        // 服务器在请求之间关闭代理连接，响应都已经返回时后端连接可以复用
+                        Ok(0) => {
+                            let done = received.load(Ordering::Relaxed) == sent.load(Ordering::Relaxed);
+                            break (done && !requests.is_upgraded(), None);
+                        }
+                        Ok(_) => {
+                            sent.fetch_add(1, Ordering::Relaxed);
+                        }
+                        // 前面的响应都已经返回时由这里回复错误，例如加上转发头之后请求头超出限制
+                        Err(HttpError::Reject(status, reason))
+                            if received.load(Ordering::Relaxed) == sent.load(Ordering::Relaxed) =>
+                        {
+                            break (false, Some((status, reason)));
+                        }
+                        Err(e) => {
+                            println!("❌转发请求失败 {e:?}");
+                            break (false, None);
+                        }
+                    },
+                    _ = &mut response => break (false, None),
+                }
+            }
+        };
 
-            proxy_stream.disconnect = true;
-            proxy_stream.stream.shutdown().await;
-            if is_back_end_close {
-                b_tcp.disconnect = true;
-                // proxy_stream.latest_time = Some(std::time::Instant::now());
-            } else {
-                b_tcp.latest_time = Some(std::time::Instant::now());
+        if let Some((status, reason)) = rejected {
+            println!("❌拒绝请求 {status} {reason}");
+            let _ = client_writer
+                .write_all(&error_response(status, reason))
+                .await;
+        }
+        reusable
+    }
+}
+
+/// 读取配置文件，出错时退出
+fn load_config(path: &std::path::Path) -> ClientConfig {
+    match config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("❌配置文件错误 {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// 收到 SIGHUP 或者配置文件的修改时间变化时重新加载隧道，配置文件有错误时保留当前的隧道
+async fn watch_config(client: Arc<Client>, path: PathBuf) {
+    let (reload_tx, mut reload_rx) = mpsc::channel::<()>(1);
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let reload_tx = reload_tx.clone();
+        match signal(SignalKind::hangup()) {
+            Ok(mut hangup) => {
+                tokio::spawn(async move {
+                    while hangup.recv().await.is_some() {
+                        println!("收到 SIGHUP，重新加载配置文件");
+                        let _ = reload_tx.try_send(());
+                    }
+                });
             }
-        });
+            Err(e) => println!("❌无法监听 SIGHUP {e:?}"),
+        }
     }
+    let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified = modified(&path);
+    loop {
+        tokio::select! {
+            _ = reload_rx.recv() => {}
+            _ = sleep(CONFIG_POLL_INTERVAL) => {
+                let current = modified(&path);
+                if current == last_modified {
+                    continue;
+                }
+                println!("配置文件已修改，重新加载");
+            }
+        }
+        last_modified = modified(&path);
+        match config::load::<ClientConfig>(&path) {
+            Ok(config) => {
+                let specs = config.tunnels.into_iter().map(|tunnel| tunnel.0).collect();
+                client.reload(specs).await;
+            }
+            Err(e) => println!("❌配置文件错误，保留当前的隧道 {e}"),
+        }
+    }
+}
+
+/// 参数错误时退出
+fn exit_with(message: &str) -> ! {
+    println!("❌{message}");
+    std::process::exit(1);
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    let client = Client::new(args.ip, args.port, args.server);
-    client.start(args.access_port).await;
+    if let Some(Command::Validate { config }) = &args.command {
+        load_config(config);
+        println!("✅配置文件 {} 检查通过", config.display());
+        return;
+    }
+    let config = args.config.as_deref().map(load_config).unwrap_or_default();
+
+    let legacy_text = args.legacy_text || config.legacy_text;
+    let codec = if legacy_text {
+        Codec::Text
+    } else {
+        Codec::Binary
+    };
+    let credential = match args.client_id.zip(args.token) {
+        Some((client_id, token)) => Some(Credential { client_id, token }),
+        None => config.auth.map(|auth| Credential {
+            client_id: auth.client_id,
+            token: auth.token,
+        }),
+    };
+    let Some(server_host) = args.server.or(config.server) else {
+        exit_with("缺少 rtcp 服务器地址，请设置 --server 或配置文件中的 server");
+    };
+    let (verify, server_name) = match (args.tls_ca, args.tls_fingerprint, config.tls) {
+        (Some(ca), _, _) => (Some(TlsVerify::CaFile(ca)), None),
+        (None, Some(fingerprint), _) => (Some(TlsVerify::Fingerprint(fingerprint)), None),
+        (None, None, Some(tls)) => (Some(tls.verify), tls.server_name),
+        (None, None, None) => (None, None),
+    };
+    let connector = match verify {
+        Some(verify) => {
+            let server_name = args.tls_server_name.or(server_name);
+            let server_name = server_name.as_ref().unwrap_or(&server_host);
+            StreamConnector::tls(&verify, server_name)
+                .unwrap_or_else(|e| exit_with(&format!("tls 配置错误 {e}")))
+        }
+        None => StreamConnector::Plain,
+    };
+
+    // 命令行指定了隧道时不再使用配置文件中的隧道
+    let mut tunnel_specs: Vec<TunnelSpec> = vec![];
+    if let (Some(ip), Some(port)) = (args.ip, args.port) {
+        let access = match (args.host, args.access_port) {
+            (Some(host), _) => TunnelAccess::Host(host),
+            (None, port) => TunnelAccess::Port(port.expect("缺少访问端口")),
+        };
+        tunnel_specs.push(TunnelSpec {
+            backend_ip: ip,
+            backend_port: port,
+            access,
+            host_header: args.host_header,
+            proxy_protocol: args.proxy_protocol,
+            mode: args.mode,
+        });
+    }
+    tunnel_specs.extend(args.tunnels);
+    // 隧道来自配置文件时，修改配置文件后重新加载隧道
+    let watch_path = match tunnel_specs.is_empty() {
+        true => args.config.filter(|_| !legacy_text),
+        false => None,
+    };
+    if tunnel_specs.is_empty() {
+        tunnel_specs = config.tunnels.into_iter().map(|tunnel| tunnel.0).collect();
+    }
+    if tunnel_specs.is_empty() {
+        exit_with("没有配置隧道，请设置 --ip、--port、--access-port 或配置文件中的 tunnels");
+    }
+
+    let mux = args.mux || config.mux;
+    // 命令行和配置文件中的设置合并后再检查一次
+    if legacy_text {
+        if let Err(e) = config::check_legacy_text(mux, &tunnel_specs) {
+            exit_with(&e);
+        }
+    }
+
+    let server = ServerAddr {
+        host: server_host,
+        control_port: args.server_port.or(config.server_port).unwrap_or(5541),
+        data_port: args.data_port.or(config.data_port).unwrap_or(5533),
+    };
+    let on_init_error = args
+        .on_init_error
+        .or(config.on_init_error)
+        .unwrap_or(InitErrorPolicy::Auto);
+    let connect_timeout = args
+        .connect_timeout
+        .or(config.connect_timeout)
+        .map_or(DEFAULT_CONNECT_TIMEOUT, Duration::from_secs);
+    let client = Arc::new(
+        Client::new(
+            tunnel_specs,
+            server,
+            codec,
+            credential,
+            connector,
+            mux,
+            on_init_error,
+        )
+        .with_connect_timeout(connect_timeout),
+    );
+    if let Some(path) = watch_path {
+        tokio::spawn(watch_config(client.clone(), path));
+    }
+    if client.start().await.is_err() {
+        std::process::exit(1);
+    }
 }
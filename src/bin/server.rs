@@ -1,38 +1,404 @@
 use std::{
-    sync::Arc,
-    time::{self, SystemTime, UNIX_EPOCH},
+    collections::{hash_map::Entry, HashMap},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
+use clap::{Parser, Subcommand};
 use deadpool::unmanaged::{self, Object};
 use rtcp::{
-    protocol::{RTCPMessage, RTCPType},
+    auth::{self, TokenStore, NONCE_LEN},
+    config::{self, ServerConfig},
+    forwarded::TrustedProxies,
+    legacy::LegacyQueue,
+    mux::Multiplexer,
+    parser::parser_request_head_all,
+    ports::{PortAllocator, PortLease, PortRanges},
+    protocol::{
+        write_msg, Codec, MessageReader, RTCPMessage, RTCPType, RejectReason, TunnelId, TunnelMode,
+    },
     tcp_pool::TcpStreamData,
-    transformer::HttpTransformer,
+    tls::{RtcpStream, StreamAcceptor},
+    transformer::{
+        error_response, parse_header_field, Forwarding, HostRewrite, HttpError, HttpTransformer,
+        ResponseRules, MAX_HEAD_LEN,
+    },
+    tunnel::TunnelAccess,
+    vhost::{HostRoute, HostRouter, RegisterError},
 };
 use tokio::{
-    io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt},
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteHalf},
     net::{TcpListener, TcpStream},
-    sync::mpsc::{self, Sender},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot,
+    },
     task::JoinHandle,
+    time::timeout,
 };
 
+/// 代理连接建立后等待 Attach 帧的时间。
+/// 同一个 ip 上同时有新旧两种 client 在等待代理连接时，超时视为旧版 client 的连接
+const ATTACH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// 下发 NewConnection 后等待 client 建立对应代理连接的时间，超时关闭用户连接
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 共享 http 端口等待用户发送完整请求头的时间
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 控制通道的默认监听地址
+const CONTROL_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 5541));
+
+/// 代理连接的默认监听地址
+const DATA_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 5533));
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// toml 配置文件，命令行参数优先于配置文件
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// 控制通道的监听地址，默认 `0.0.0.0:5541`，`[::]:5541` 同时监听 IPv4 和 IPv6，也可以只监听某个网卡的地址
+    #[arg(long)]
+    control_addr: Option<SocketAddr>,
+
+    /// 代理连接的监听地址，默认 `0.0.0.0:5533`，client 需要用 `--data-port` 指定相同的端口
+    #[arg(long)]
+    data_addr: Option<SocketAddr>,
+
+    /// client 令牌文件，每行 `client_id token`，不设置时不做认证
+    #[arg(long)]
+    tokens: Option<PathBuf>,
+
+    /// tls 证书链文件（pem），设置后控制通道和代理连接都使用 tls
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// tls 私钥文件（pem）
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// 允许 client 注册的访问端口范围，例如 `7000-7999,8080`，不设置时不限制
+    #[arg(long)]
+    allowed_ports: Option<PortRanges>,
+
+    /// client 请求端口 0 时从该范围中分配访问端口，不设置时不分配
+    #[arg(long)]
+    assign_ports: Option<PortRanges>,
+
+    /// 按 Host 头路由的共享 http 端口，例如 80，不设置时不开启
+    #[arg(long)]
+    http_port: Option<u16>,
+
+    /// 共享 http 端口的域名，设置后 client 只能注册该域名下的主机名，例如 `tunnel.example.com`
+    #[arg(long)]
+    domain: Option<String>,
+
+    /// 可信代理的网段，例如 `10.0.0.0/8,127.0.0.1`，来自这些地址的请求保留已有的转发头，其他请求的转发头会被丢弃
+    #[arg(long)]
+    trusted_proxies: Option<TrustedProxies>,
+
+    /// http 请求体的最大字节数，超过时回复 413（chunked 请求体在转发途中超过时断开连接），不设置时不限制
+    #[arg(long)]
+    max_body_size: Option<u64>,
+
+    /// 添加到 http 响应中的响应头，例如 `Strict-Transport-Security: max-age=31536000`，可以重复设置
+    #[arg(long, value_parser = parse_header_field)]
+    response_header: Vec<(String, String)>,
+
+    /// 从 http 响应中删除的响应头，例如 `Server`，可以重复设置
+    #[arg(long)]
+    remove_response_header: Vec<String>,
+
+    /// 打印每个 http 请求的状态码和耗时
+    #[arg(long)]
+    access_log: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// 检查配置文件，有错误时输出出错的行号
+    Validate {
+        /// toml 配置文件
+        config: PathBuf,
+    },
+}
+
+/// 服务器对所有 http 隧道生效的设置
+#[derive(Debug, Clone, Default)]
+pub struct HttpSettings {
+    /// 请求体的最大长度，None 表示不限制
+    pub max_body_size: Option<u64>,
+    /// 响应头的处理规则
+    pub response_rules: Arc<ResponseRules>,
+    /// 是否打印每个请求的状态码和耗时
+    pub access_log: bool,
+}
+
+/// client 认证状态
+#[derive(Debug)]
+enum AuthState {
+    /// 服务器未开启认证
+    Disabled,
+    /// 等待 client 发送认证请求
+    Pending,
+    /// 已下发挑战，等待应答
+    Challenged {
+        client_id: String,
+        nonce: [u8; NONCE_LEN],
+    },
+    /// 认证通过
+    Authenticated(String),
+}
+
+impl AuthState {
+    /// 是否允许初始化
+    fn is_allowed(&self) -> bool {
+        matches!(self, AuthState::Disabled | AuthState::Authenticated(_))
+    }
+}
+
+/// 等待 client 建立的代理连接
+struct PendingConnect {
+    /// 所属会话，会话结束时清理
+    session_id: String,
+    /// 会话的来源 ip，代理连接也来自这个 ip
+    client_ip: IpAddr,
+    /// 代理连接建立后交给触发它的用户连接，同时交出读取 Attach 帧时多读到的数据
+    sender: oneshot::Sender<(RtcpStream, BytesMut)>,
+}
+
+/// 已下发给 client 但还没有建立的代理连接，connect_id -> 等待中的用户连接
+type PendingConnects = Arc<Mutex<HashMap<String, PendingConnect>>>;
+
+/// 等待代理连接的旧版文本协议会话
+#[derive(Clone)]
+struct LegacyWaiter {
+    /// 会话自己的连接池，代理连接到达后放入其中
+    pool: unmanaged::Pool<TcpStreamData>,
+    /// 轮到该会话时通过控制连接请求 client 新建连接
+    sender: Sender<RTCPMessage>,
+}
+
+/// 按来源 ip 排队等待代理连接的旧版文本协议会话
+type LegacyWaiters = Arc<Mutex<LegacyQueue<LegacyWaiter>>>;
+
+/// 创建隧道上游时需要的会话信息
+struct Session<'a> {
+    id: &'a str,
+    /// 控制连接的来源 ip
+    client_ip: IpAddr,
+    /// 旧版文本协议会话的代理连接池
+    legacy_pool: &'a unmanaged::Pool<TcpStreamData>,
+}
+
+/// 隧道的用户服务器，丢弃时停止接收新的用户连接
+struct UserServer {
+    /// 实际监听的访问端口
+    port: u16,
+    handle: JoinHandle<()>,
+}
+
+impl UserServer {
+    /// 停止接收新的用户连接，返回时访问端口已经关闭，可以立即重新绑定
+    async fn stop(mut self) {
+        self.handle.abort();
+        let _ = (&mut self.handle).await;
+    }
+}
+
+impl Drop for UserServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// 会话中的隧道，丢弃时不再接收新的用户连接
+enum SessionTunnel {
+    /// 独占的访问端口
+    Port(UserServer),
+    /// 共享 http 端口上的主机名
+    Host {
+        port: u16,
+        /// 丢弃时注销主机名
+        _route: HostRoute<Upstream>,
+    },
+}
+
+impl SessionTunnel {
+    /// 用户访问隧道的端口
+    fn port(&self) -> u16 {
+        match self {
+            SessionTunnel::Port(server) => server.port,
+            SessionTunnel::Host { port, .. } => *port,
+        }
+    }
+}
+
+/// client 为隧道设置的转发选项，在增加隧道之前发送
+#[derive(Debug, Clone, Default)]
+struct TunnelOptions {
+    /// 转发方式
+    mode: TunnelMode,
+    /// 转发时 Host 头的处理方式
+    host_rewrite: HostRewrite,
+}
+
+/// 用户连接的上游
+#[derive(Clone)]
+struct Upstream {
+    link: UpstreamLink,
+    /// 隧道的转发选项
+    options: TunnelOptions,
+    /// 可以保留转发头的代理地址
+    trusted_proxies: Arc<TrustedProxies>,
+    /// http 隧道的设置
+    http_settings: HttpSettings,
+    /// 是否在新连接和逻辑流中告知 client 用户地址
+    send_peer_addr: bool,
+}
+
+/// 获取上游连接的方式
+#[derive(Clone)]
+enum UpstreamLink {
+    /// 旧版文本协议 client：从会话的连接池中获取代理连接，不够用时通过控制连接请求 client 新建
+    LegacyPool {
+        pool: unmanaged::Pool<TcpStreamData>,
+        sender: Sender<RTCPMessage>,
+        session_id: String,
+        client_ip: IpAddr,
+        waiters: LegacyWaiters,
+    },
+    /// 每个用户连接都请求 client 新建一个代理连接，按 connect_id 配对
+    Paired {
+        tunnel: TunnelId,
+        session_id: String,
+        client_ip: IpAddr,
+        sender: Sender<RTCPMessage>,
+        pending: PendingConnects,
+    },
+    /// 在控制连接上打开逻辑流
+    Mux {
+        mux: Arc<Multiplexer>,
+        tunnel: TunnelId,
+    },
+}
+
+impl Upstream {
+    /// 设置是否告知 client 用户地址
+    fn with_peer_addr(mut self, send_peer_addr: bool) -> Self {
+        self.send_peer_addr = send_peer_addr;
+        self
+    }
+
+    /// 使用 client 为隧道设置的转发选项
+    fn with_options(
+        mut self,
+        tunnel_options: &HashMap<TunnelId, TunnelOptions>,
+        tunnel: TunnelId,
+    ) -> Self {
+        if let Some(options) = tunnel_options.get(&tunnel) {
+            self.options = options.clone();
+        }
+        self
+    }
+}
+
 pub struct RTcpServer {
-    pub tcp_pool: Arc<unmanaged::Pool<TcpStreamData>>,
+    /// 旧版文本协议 client 无法声明代理连接所属的会话，代理连接按来源 ip 交给正在等待的会话
+    legacy_waiters: LegacyWaiters,
+    pending: PendingConnects,
+    /// client 令牌，None 表示不认证
+    pub token_store: Option<TokenStore>,
+    /// 控制通道和代理连接的 tls 配置
+    pub acceptor: StreamAcceptor,
+    /// 允许注册的访问端口，None 表示不限制
+    pub allowed_ports: Option<PortRanges>,
+    /// 端口分配器，None 表示不分配端口
+    port_allocator: Option<Arc<PortAllocator>>,
+    /// 按主机名路由的共享 http 端口，None 表示不开启
+    pub http_port: Option<u16>,
+    /// 主机名 -> 隧道的上游
+    virtual_hosts: HostRouter<Upstream>,
+    /// 可信代理，来自这些地址的请求保留已有的转发头
+    trusted_proxies: Arc<TrustedProxies>,
+    /// http 隧道的设置
+    http_settings: HttpSettings,
+    /// 控制通道的监听地址
+    control_addr: SocketAddr,
+    /// 代理连接的监听地址
+    data_addr: SocketAddr,
 }
 
 impl RTcpServer {
-    pub async fn new() -> Self {
-        let tcp_pool = unmanaged::Pool::new(1000);
+    pub async fn new(
+        token_store: Option<TokenStore>,
+        acceptor: StreamAcceptor,
+        allowed_ports: Option<PortRanges>,
+        assign_ports: Option<PortRanges>,
+        http_port: Option<u16>,
+        domain: Option<String>,
+        trusted_proxies: Option<TrustedProxies>,
+    ) -> Self {
         Self {
-            tcp_pool: Arc::new(tcp_pool),
+            legacy_waiters: Arc::new(Mutex::new(LegacyQueue::new())),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            token_store,
+            acceptor,
+            allowed_ports,
+            port_allocator: assign_ports.map(PortAllocator::new),
+            http_port,
+            virtual_hosts: HostRouter::new(domain.as_deref()),
+            trusted_proxies: Arc::new(trusted_proxies.unwrap_or_default()),
+            http_settings: HttpSettings::default(),
+            control_addr: CONTROL_ADDR,
+            data_addr: DATA_ADDR,
         }
     }
 
+    /// 设置控制通道和代理连接的监听地址
+    pub fn with_listen_addrs(mut self, control_addr: SocketAddr, data_addr: SocketAddr) -> Self {
+        self.control_addr = control_addr;
+        self.data_addr = data_addr;
+        self
+    }
+
+    /// 设置 http 隧道的请求体限制、响应头规则等
+    pub fn with_http_settings(mut self, http_settings: HttpSettings) -> Self {
+        self.http_settings = http_settings;
+        self
+    }
+
     /// 创建通道服务器
     pub async fn create_connect_channel(self) -> io::Result<()> {
-        let tcp_listener = TcpListener::bind("0.0.0.0:5541").await?;
+        let tcp_listener = TcpListener::bind(self.control_addr).await.map_err(|e| {
+            println!("❌控制通道监听 {} 启动失败 {e}", self.control_addr);
+            e
+        })?;
+        println!("✅控制通道监听 {} 启动成功", self.control_addr);
+        let proxy_listener = TcpListener::bind(self.data_addr).await.map_err(|e| {
+            println!("❌代理服务器池监听 {} 启动失败 {e}", self.data_addr);
+            e
+        })?;
+        println!("✅代理服务器池监听 {} 启动成功", self.data_addr);
         let this = Arc::new(self);
+        // 代理服务器由所有会话共用，代理连接通过 Attach 帧归属到各自的会话
+        let _proxy_server_handle = this.create_proxy_server(proxy_listener);
+        let _virtual_host_handle = match this.http_port {
+            Some(port) => Some(this.create_virtual_host_server(port).await?),
+            None => None,
+        };
 
         loop {
             let this = this.clone();
@@ -41,7 +407,10 @@ impl RTcpServer {
                 Ok(stream) => {
                     println!("收到rtcp client新连接");
                     tokio::spawn(async move {
-                        this.client_handle(stream.0).await;
+                        match this.acceptor.accept(stream.0).await {
+                            Ok(stream) => this.client_handle(stream).await,
+                            Err(e) => println!("❌client tls 握手失败{:?}", e),
+                        }
                     });
                 }
                 Err(e) => {
@@ -52,185 +421,1055 @@ impl RTcpServer {
         }
     }
 
-    async fn client_handle(self: Arc<Self>, tcp: TcpStream) {
-        let (mut read_half, mut write_half) = tcp.into_split();
-        let mut new_poll_connect_handle: Option<JoinHandle<()>> = None;
-        let mut proxy_server_handle: Option<JoinHandle<()>> = None;
-        let mut user_server_handle: Option<JoinHandle<()>> = None;
+    async fn client_handle(self: Arc<Self>, stream: RtcpStream) {
+        let client_ip = match stream.tcp().peer_addr() {
+            Ok(addr) => addr.ip().to_canonical(),
+            Err(e) => {
+                println!("❌获取 client 地址失败{:?}", e);
+                return;
+            }
+        };
+        let (mut read_half, write_half) = io::split(stream);
+        let mut writer_handle: Option<JoinHandle<()>> = None;
+        // 本会话的隧道，隧道 id -> 用户服务器
+        let mut tunnels: HashMap<TunnelId, SessionTunnel> = HashMap::new();
+        // client 为隧道设置的转发选项，增加隧道时生效
+        let mut tunnel_options: HashMap<TunnelId, TunnelOptions> = HashMap::new();
+        let mut mux: Option<Arc<Multiplexer>> = None;
+        let mut send_peer_addr = false;
+        let session_id = uuid::Uuid::new_v4().to_string();
+        // 旧版文本协议会话的代理连接池
+        let legacy_pool = unmanaged::Pool::new(1000);
+        let session = Session {
+            id: &session_id,
+            client_ip,
+            legacy_pool: &legacy_pool,
+        };
 
-        // client 连接池不够用时候，发送创建新连接的消息
-        let (tx, mut rx) = mpsc::channel::<()>(1000);
+        // 发往 client 的消息统一经过该通道写出，包括连接池不够用时创建新连接的消息
+        let (tx, rx) = mpsc::channel::<RTCPMessage>(1000);
+        let mut pending_writer = Some((write_half, rx));
+        let mut reader = MessageReader::detect();
+        let mut auth_state = if self.token_store.is_some() {
+            AuthState::Pending
+        } else {
+            AuthState::Disabled
+        };
 
-        new_poll_connect_handle = Some(tokio::spawn(async move {
-            loop {
-                if rx.recv().await.is_some() {
-                    let msg = RTCPMessage::new(RTCPType::NewConnection);
-                    write_half.write_all(&msg.serialize()).await.unwrap();
-                    write_half.flush().await.unwrap();
+        let close_reason = loop {
+            let msg = match reader.read_msg(&mut read_half).await {
+                Ok(msg) => msg,
+                Err(e) => {
+                    println!("❌读取消息失败,关闭当前client 连接{:?}", e);
+                    break None;
                 }
-            }
-        }));
+            };
 
-        loop {
-            let msg = self.read_msg(&mut read_half).await;
+            // 收到第一条消息后才能确定 client 使用的协议格式
+            if let Some((write_half, rx)) = pending_writer.take() {
+                let codec = reader.codec().expect("已读取到消息");
+                writer_handle = Some(tokio::spawn(Self::write_loop(write_half, rx, codec)));
+            }
 
-            if msg.is_err() {
-                println!("❌读取消息失败,关闭当前client 连接{:?}", msg);
-                if let Some(handle) = new_poll_connect_handle.take() {
-                    handle.abort();
+            let res = match msg.message_type {
+                RTCPType::Auth(client_id) => self.start_auth(&mut auth_state, client_id, &tx).await,
+                RTCPType::AuthResponse(response) => {
+                    self.finish_auth(&mut auth_state, response, &tx).await
                 }
-                if let Some(handle) = user_server_handle.take() {
-                    handle.abort();
+                // 会话和隧道的选项与增加隧道一样需要先通过认证
+                RTCPType::Multiplex
+                | RTCPType::PeerAddr
+                | RTCPType::TunnelHostRewrite(..)
+                | RTCPType::TunnelMode(..)
+                    if !auth_state.is_allowed() =>
+                {
+                    Err("authentication required".to_string())
                 }
-                if let Some(handle) = proxy_server_handle.take() {
-                    handle.abort();
+                RTCPType::Multiplex => {
+                    // 替换多路复用器会丢下已经打开的逻辑流
+                    if mux.is_some() {
+                        Err("multiplex already enabled".to_string())
+                    } else if reader.codec() == Some(Codec::Binary) {
+                        mux = Some(Multiplexer::new(tx.clone(), true));
+                        Ok(())
+                    } else {
+                        Err("multiplex requires the binary protocol".to_string())
+                    }
+                }
+                RTCPType::PeerAddr => {
+                    if reader.codec() == Some(Codec::Binary) {
+                        send_peer_addr = true;
+                        Ok(())
+                    } else {
+                        Err("peer address requires the binary protocol".to_string())
+                    }
                 }
-                return;
-            }
-
-            let msg = msg.unwrap();
-
-            match msg.message_type {
                 RTCPType::Initialize(port) => {
-                    proxy_server_handle = Some(self.create_proxy_server().await);
-                    user_server_handle = Some(self.create_user_server(port, tx.clone()).await);
+                    let res = if auth_state.is_allowed() {
+                        // 重复初始化时替换原来的 0 号隧道，client 可能使用同一个端口重新注册
+                        if let Some(SessionTunnel::Port(server)) = tunnels.remove(&0) {
+                            server.stop().await;
+                        }
+                        let upstream = self.upstream(0, &mux, reader.codec(), &session, &tx);
+                        let upstream = upstream.with_peer_addr(send_peer_addr);
+                        let upstream = upstream.with_options(&tunnel_options, 0);
+                        self.open_user_server(port, upstream).await.map(|server| {
+                            let port = server.port;
+                            tunnels.insert(0, SessionTunnel::Port(server));
+                            port
+                        })
+                    } else {
+                        Err((
+                            RejectReason::AuthFailed,
+                            "authentication required".to_string(),
+                        ))
+                    };
+                    match res {
+                        // 旧版文本协议无法表达初始化结果，失败时只能关闭连接
+                        _ if reader.codec() != Some(Codec::Binary) => {
+                            res.map(|_| ()).map_err(|e| e.1)
+                        }
+                        Ok(port) => {
+                            let reply = RTCPType::InitializeOk(port);
+                            let _ = tx.send(RTCPMessage::new(reply)).await;
+                            Ok(())
+                        }
+                        Err((reason, message)) => {
+                            let reply = RTCPType::InitializeError(reason, message.clone());
+                            let _ = tx.send(RTCPMessage::new(reply)).await;
+                            // 未认证的会话直接关闭，其他错误交给 client 决定重试还是退出
+                            match reason {
+                                RejectReason::AuthFailed => Err(message),
+                                _ => Ok(()),
+                            }
+                        }
+                    }
+                }
+                RTCPType::TunnelAdd(tunnel, port) => {
+                    let upstream = self.upstream(tunnel, &mux, reader.codec(), &session, &tx);
+                    let upstream = upstream.with_peer_addr(send_peer_addr);
+                    let upstream = upstream.with_options(&tunnel_options, tunnel);
+                    let access = TunnelAccess::Port(port);
+                    self.add_tunnel(&mut tunnels, tunnel, access, upstream, &auth_state, &tx)
+                        .await
                 }
-                RTCPType::NewConnection => {
-                    println!("🔥不需要实现")
+                RTCPType::TunnelAddHost(tunnel, host) => {
+                    let upstream = self.upstream(tunnel, &mux, reader.codec(), &session, &tx);
+                    let upstream = upstream.with_peer_addr(send_peer_addr);
+                    let upstream = upstream.with_options(&tunnel_options, tunnel);
+                    let access = TunnelAccess::Host(host);
+                    self.add_tunnel(&mut tunnels, tunnel, access, upstream, &auth_state, &tx)
+                        .await
+                }
+                RTCPType::TunnelHostRewrite(tunnel, host) => {
+                    tunnel_options.entry(tunnel).or_default().host_rewrite = match host {
+                        Some(host) => HostRewrite::Rewrite(host),
+                        None => HostRewrite::Preserve,
+                    };
+                    Ok(())
+                }
+                RTCPType::TunnelMode(tunnel, mode) => {
+                    tunnel_options.entry(tunnel).or_default().mode = mode;
+                    Ok(())
+                }
+                RTCPType::TunnelRemove(tunnel) => {
+                    tunnel_options.remove(&tunnel);
+                    // 只停止接收新的用户连接，已经建立的连接继续传输
+                    let reply = match tunnels.remove(&tunnel) {
+                        Some(removed) => {
+                            let port = removed.port();
+                            // client 重新加载配置时可能紧接着用同一个端口注册新的隧道
+                            if let SessionTunnel::Port(server) = removed {
+                                server.stop().await;
+                            }
+                            println!("✅[{port}]隧道 {tunnel} 已移除");
+                            RTCPType::TunnelOk(tunnel, port)
+                        }
+                        None => RTCPType::TunnelError(
+                            tunnel,
+                            RejectReason::Other,
+                            "unknown tunnel".to_string(),
+                        ),
+                    };
+                    let _ = tx.send(RTCPMessage::new(reply)).await;
+                    Ok(())
                 }
-                RTCPType::CloseConnection => println!("🔥不需要实现"),
                 RTCPType::Heartbeat => {
                     println!("收到心跳");
+                    self.check_revoked(&auth_state)
                 }
+                other => {
+                    let handled = mux.as_ref().is_some_and(|mux| mux.handle_frame(&other));
+                    if !handled {
+                        println!("🔥不需要实现 {other}");
+                    }
+                    Ok(())
+                }
+            };
+
+            if let Err(reason) = res {
+                break Some(reason);
+            }
+        };
+
+        // 停止本会话的用户服务器，释放服务器分配的端口并注销主机名
+        drop(tunnels);
+        // 丢弃本会话还在等待的配对，等待中的用户连接随之关闭
+        self.pending
+            .lock()
+            .unwrap()
+            .retain(|_, pending| pending.session_id != session_id);
+        let next = self
+            .legacy_waiters
+            .lock()
+            .unwrap()
+            .remove_session(&session_id);
+        Self::request_legacy(next).await;
+        if let Some(mux) = mux.take() {
+            mux.close_all();
+        }
+        if let Some(handle) = writer_handle.take() {
+            match close_reason {
+                Some(reason) => {
+                    println!("❌{reason}，关闭当前client 连接");
+                    // 写出错误帧后写入任务会自行结束并关闭连接
+                    let _ = tx.send(RTCPMessage::new(RTCPType::Error(reason))).await;
+                    let _ = handle.await;
+                }
+                None => handle.abort(),
             }
         }
     }
 
-    async fn read_msg<T>(&self, tcp: &mut T) -> io::Result<RTCPMessage>
-    where
-        T: AsyncRead + Unpin,
-    {
-        let mut buf = BytesMut::with_capacity(4 * 1024);
-        loop {
-            tcp.read_buf(&mut buf).await?;
-            if buf.is_empty() {
-                return Err(io::Error::new(io::ErrorKind::Other, "tcp连接已关闭"));
+    /// 把通道中的消息写给 client，写出错误帧后结束
+    async fn write_loop(
+        mut write_half: WriteHalf<RtcpStream>,
+        mut rx: Receiver<RTCPMessage>,
+        codec: Codec,
+    ) {
+        while let Some(msg) = rx.recv().await {
+            if let Err(e) = write_msg(&mut write_half, &msg, codec).await {
+                println!("❌发送消息失败{e:?}");
+                break;
+            }
+            if matches!(msg.message_type, RTCPType::Error(_)) {
+                let _ = write_half.shutdown().await;
+                break;
             }
+        }
+    }
+
+    /// 收到认证请求，下发挑战
+    async fn start_auth(
+        &self,
+        auth_state: &mut AuthState,
+        client_id: String,
+        tx: &Sender<RTCPMessage>,
+    ) -> Result<(), String> {
+        let reply = match auth_state {
+            // 服务器未开启认证时直接放行，兼容配置了令牌的 client
+            AuthState::Disabled => RTCPType::AuthOk,
+            AuthState::Pending => {
+                let nonce = auth::new_nonce();
+                *auth_state = AuthState::Challenged { client_id, nonce };
+                RTCPType::AuthChallenge(Bytes::copy_from_slice(&nonce))
+            }
+            _ => return Err("unexpected auth request".to_string()),
+        };
+        tx.send(RTCPMessage::new(reply))
+            .await
+            .map_err(|e| e.to_string())
+    }
 
-            let res = RTCPMessage::deserialize(&buf);
+    /// 校验挑战应答
+    async fn finish_auth(
+        &self,
+        auth_state: &mut AuthState,
+        response: Bytes,
+        tx: &Sender<RTCPMessage>,
+    ) -> Result<(), String> {
+        let AuthState::Challenged { client_id, nonce } = auth_state else {
+            return Err("unexpected auth response".to_string());
+        };
+        let token_store = self.token_store.as_ref().expect("开启认证时才会下发挑战");
+        let token = token_store.token(client_id).map_err(|e| {
+            println!("❌读取令牌文件失败 {:?} {e:?}", token_store.path());
+            "authentication unavailable".to_string()
+        })?;
 
-            if res.is_err() {
-                println!("序列化失败,继续读取 {:?}", res);
-                continue;
+        match token {
+            Some(token) if auth::verify_response(&token, nonce, client_id, &response) => {
+                println!("✅client [{client_id}] 认证成功");
+                *auth_state = AuthState::Authenticated(client_id.clone());
+                tx.send(RTCPMessage::new(RTCPType::AuthOk))
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+            _ => {
+                println!("❌client [{client_id}] 认证失败");
+                Err("authentication failed".to_string())
             }
+        }
+    }
 
-            let (rtcp_message, _size) = res.unwrap();
+    /// 检查已认证的 client 令牌是否被吊销
+    fn check_revoked(&self, auth_state: &AuthState) -> Result<(), String> {
+        let (Some(token_store), AuthState::Authenticated(client_id)) =
+            (&self.token_store, auth_state)
+        else {
+            return Ok(());
+        };
+        match token_store.token(client_id) {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err("token revoked".to_string()),
+            Err(e) => {
+                // 令牌文件暂时不可读时不断开已认证的连接
+                println!("❌读取令牌文件失败 {:?} {e:?}", token_store.path());
+                Ok(())
+            }
+        }
+    }
 
-            return Ok(rtcp_message);
+    /// 根据会话的传输方式选择隧道的上游
+    fn upstream(
+        &self,
+        tunnel: TunnelId,
+        mux: &Option<Arc<Multiplexer>>,
+        codec: Option<Codec>,
+        session: &Session,
+        tx: &Sender<RTCPMessage>,
+    ) -> Upstream {
+        let link = match mux {
+            // 多路复用模式下用户连接都走控制连接，不需要代理服务器
+            Some(mux) => UpstreamLink::Mux {
+                mux: mux.clone(),
+                tunnel,
+            },
+            None if codec == Some(Codec::Binary) => UpstreamLink::Paired {
+                tunnel,
+                session_id: session.id.to_string(),
+                client_ip: session.client_ip,
+                sender: tx.clone(),
+                pending: self.pending.clone(),
+            },
+            None => UpstreamLink::LegacyPool {
+                pool: session.legacy_pool.clone(),
+                sender: tx.clone(),
+                session_id: session.id.to_string(),
+                client_ip: session.client_ip,
+                waiters: self.legacy_waiters.clone(),
+            },
+        };
+        Upstream {
+            link,
+            options: TunnelOptions::default(),
+            trusted_proxies: self.trusted_proxies.clone(),
+            http_settings: self.http_settings.clone(),
+            send_peer_addr: false,
         }
     }
 
-    /// 创建用户服务器
-    /// 用于接收用户请求，并把请求转发给代理服务器
-    async fn create_user_server(
+    /// 增加隧道并回复 client，未认证时返回关闭原因
+    async fn add_tunnel(
+        &self,
+        tunnels: &mut HashMap<TunnelId, SessionTunnel>,
+        tunnel: TunnelId,
+        access: TunnelAccess,
+        upstream: Upstream,
+        auth_state: &AuthState,
+        tx: &Sender<RTCPMessage>,
+    ) -> Result<(), String> {
+        let res = if !auth_state.is_allowed() {
+            Err((
+                RejectReason::AuthFailed,
+                "authentication required".to_string(),
+            ))
+        } else {
+            match tunnels.entry(tunnel) {
+                Entry::Occupied(_) => {
+                    Err((RejectReason::Other, "tunnel already exists".to_string()))
+                }
+                Entry::Vacant(entry) => {
+                    let opened = match access {
+                        TunnelAccess::Port(port) => self
+                            .open_user_server(port, upstream)
+                            .await
+                            .map(SessionTunnel::Port),
+                        TunnelAccess::Host(host) => self.register_host(&host, upstream),
+                    };
+                    opened.map(|opened| entry.insert(opened).port())
+                }
+            }
+        };
+
+        let (reply, res) = match res {
+            Ok(port) => (RTCPType::TunnelOk(tunnel, port), Ok(())),
+            Err((RejectReason::AuthFailed, message)) => (
+                RTCPType::TunnelError(tunnel, RejectReason::AuthFailed, message.clone()),
+                Err(message),
+            ),
+            Err((reason, message)) => (RTCPType::TunnelError(tunnel, reason, message), Ok(())),
+        };
+        let _ = tx.send(RTCPMessage::new(reply)).await;
+        res
+    }
+
+    /// 在共享 http 端口上注册主机名
+    fn register_host(
+        &self,
+        host: &str,
+        upstream: Upstream,
+    ) -> Result<SessionTunnel, (RejectReason, String)> {
+        let Some(port) = self.http_port else {
+            return Err((
+                RejectReason::HostNotAllowed,
+                "virtual hosts are disabled".to_string(),
+            ));
+        };
+        // 只有 http 请求带有 Host 头
+        if upstream.options.mode == TunnelMode::Tcp {
+            return Err((
+                RejectReason::HostNotAllowed,
+                "tcp tunnels cannot be routed by host".to_string(),
+            ));
+        }
+        match self.virtual_hosts.register(host, upstream) {
+            Ok(route) => {
+                println!("✅[{port}]主机名 {} 注册成功", route.host());
+                Ok(SessionTunnel::Host {
+                    port,
+                    _route: route,
+                })
+            }
+            Err(e) => {
+                println!("❌[{port}]主机名注册失败 {e}");
+                let reason = match e {
+                    RegisterError::InUse(_) => RejectReason::HostInUse,
+                    RegisterError::NotAllowed(_) => RejectReason::HostNotAllowed,
+                };
+                Err((reason, e.to_string()))
+            }
+        }
+    }
+
+    /// 绑定访问端口并启动用户服务器，端口为 0 时从分配范围中选择，失败时返回拒绝原因
+    async fn open_user_server(
         &self,
         port: u16,
-        sender: Sender<()>,
-    ) -> tokio::task::JoinHandle<()> {
-        let listener = TcpListener::bind(format!("0.0.0.0:{port}")).await.unwrap();
+        upstream: Upstream,
+    ) -> Result<UserServer, (RejectReason, String)> {
+        let (listener, lease) = match port {
+            0 => self.bind_assigned_port().await?,
+            _ => {
+                if let Some(allowed_ports) = &self.allowed_ports {
+                    if !allowed_ports.contains(port) {
+                        println!("❌[{port}]访问端口不在允许范围内");
+                        return Err((
+                            RejectReason::PortNotAllowed,
+                            format!("port {port} is not allowed"),
+                        ));
+                    }
+                }
+                let listener = TcpListener::bind(format!("0.0.0.0:{port}"))
+                    .await
+                    .map_err(|e| {
+                        println!("❌[{port}]用户服务器端口启动失败 {e}");
+                        let reason = match e.kind() {
+                            io::ErrorKind::AddrInUse => RejectReason::PortInUse,
+                            _ => RejectReason::Other,
+                        };
+                        (reason, e.to_string())
+                    })?;
+                (listener, None)
+            }
+        };
+        let port = listener.local_addr().map_or(port, |addr| addr.port());
         println!("✅[{port}]用户服务器端口启动成功");
-        let tcp_pool = self.tcp_pool.clone();
 
+        Ok(UserServer {
+            port,
+            handle: Self::create_user_server(listener, upstream, lease),
+        })
+    }
+
+    /// 从分配范围中选择空闲端口并绑定，跳过被其他程序占用的端口
+    async fn bind_assigned_port(
+        &self,
+    ) -> Result<(TcpListener, Option<PortLease>), (RejectReason, String)> {
+        let Some(allocator) = &self.port_allocator else {
+            return Err((
+                RejectReason::PortNotAllowed,
+                "server does not assign ports".to_string(),
+            ));
+        };
+        for _ in 0..allocator.capacity() {
+            let Some(lease) = allocator.reserve() else {
+                break;
+            };
+            match TcpListener::bind(format!("0.0.0.0:{}", lease.port())).await {
+                Ok(listener) => return Ok((listener, Some(lease))),
+                // 租约在这里丢弃，端口归还后继续尝试下一个
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+                Err(e) => return Err((RejectReason::Other, e.to_string())),
+            }
+        }
+        println!("❌分配范围内没有空闲端口");
+        Err((
+            RejectReason::PortInUse,
+            "no free port in the assigned range".to_string(),
+        ))
+    }
+
+    /// 创建用户服务器
+    /// 用于接收用户请求，并把请求转发给代理服务器，任务结束时释放分配的端口
+    fn create_user_server(
+        listener: TcpListener,
+        upstream: Upstream,
+        lease: Option<PortLease>,
+    ) -> JoinHandle<()> {
         tokio::spawn(async move {
+            let _lease = lease;
             loop {
-                if let Ok((mut user_tcp, user_addr)) = listener.accept().await {
-                    if tcp_pool.status().available == 0 {
-                        sender.send(()).await.unwrap();
-                    }
+                let Ok((user_tcp, user_addr)) = listener.accept().await else {
+                    continue;
+                };
 
-                    let mut client_tcp = tcp_pool.get().await.unwrap();
+                tokio::spawn(Self::serve_user(
+                    upstream.clone(),
+                    user_tcp,
+                    user_addr,
+                    BytesMut::new(),
+                ));
+            }
+        })
+    }
 
-                    tokio::spawn(async move {
-                        let (mut client_reader, mut client_writer) = client_tcp.stream.split();
-                        let (mut user_reader, mut user_writer) = user_tcp.split();
-
-                        let mut http_transformer = HttpTransformer::new(user_addr);
-
-                        let is_client_disconnect = loop {
-                            let (res, is_client_disconnect) = tokio::select! {
-                                res = http_transformer.copy(&mut user_reader, &mut client_writer) => {
-                                    // println!("🔐 用户发送到代理池 {res:?}");
-                                    (res.unwrap_or_default(),false)
-                                },
-                                res = io::copy(&mut client_reader, &mut user_writer) => {
-                                    // println!("🌈 代理池服务器响应到用户 {res:?} ");
-                                    (res.unwrap_or_default(),true)
-                                },
-                            };
-
-                            if res == 0 {
-                                // println!(
-                                //     "传输断开  是否为代理客户端断开{:?}",
-                                //     is_client_disconnect
-                                // );
-                                break is_client_disconnect;
-                            }
-                        };
+    /// 创建共享 http 端口的服务器，按请求的 Host 头把用户连接转发到对应的隧道
+    async fn create_virtual_host_server(self: &Arc<Self>, port: u16) -> io::Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(format!("0.0.0.0:{port}")).await?;
+        println!("✅[{port}]共享 http 端口启动成功");
 
-                        let mut client_tcp = Object::take(client_tcp);
-                        client_tcp.stream.shutdown().await;
-                        user_tcp.shutdown().await;
-                        // if is_client_disconnect {
-                        //     let mut client_tcp = Object::take(client_tcp);
-                        //     client_tcp.stream.shutdown().await;
-                        // } else {
-                        // client_tcp.latest_time = Some(std::time::Instant::now());
-                        // }
-                    });
+        let this = self.clone();
+        Ok(tokio::spawn(async move {
+            loop {
+                let Ok((user_tcp, user_addr)) = listener.accept().await else {
+                    continue;
                 };
+                tokio::spawn(this.clone().serve_virtual_host(user_tcp, user_addr));
             }
-        })
+        }))
     }
 
-    /// 创建代理服务器
-    /// 用于接收 client 端的 tcp 连接，并把该连接加入到连接池中
-    async fn create_proxy_server(&self) -> tokio::task::JoinHandle<()> {
-        let tcp_pool = self.tcp_pool.clone();
-        tokio::spawn(async move {
-            let listener = TcpListener::bind("0.0.0.0:5533").await;
-            if listener.is_err() {
-                return;
+    /// 读取请求头中的 Host，转发到注册了该主机名的隧道
+    async fn serve_virtual_host(self: Arc<Self>, mut user_tcp: TcpStream, user_addr: SocketAddr) {
+        let mut head = BytesMut::with_capacity(4 * 1024);
+        let host = match timeout(HEAD_TIMEOUT, Self::read_host(&mut user_tcp, &mut head)).await {
+            Ok(Ok(Some(host))) => host,
+            Ok(Ok(None)) => return Self::reject_user(user_tcp, 400, "Bad Request").await,
+            // 用户在发送完请求头之前断开或超时
+            _ => return,
+        };
+
+        match self.virtual_hosts.lookup(&host) {
+            Some(upstream) => Self::serve_user(upstream, user_tcp, user_addr, head).await,
+            None => {
+                println!("❌未注册的主机名 {host}");
+                Self::reject_user(user_tcp, 404, "Not Found").await;
+            }
+        }
+    }
+
+    /// 读取完整的请求头并返回 Host 头，请求头格式错误、过长、缺少 Host 或有多个 Host 时返回 None
+    async fn read_host(
+        user_tcp: &mut TcpStream,
+        head: &mut BytesMut,
+    ) -> io::Result<Option<String>> {
+        loop {
+            if user_tcp.read_buf(head).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            match parser_request_head_all(head) {
+                // 多个 Host 时无法确定路由到哪个隧道
+                Ok((_, (_, headers))) if headers.get_all("Host").count() > 1 => return Ok(None),
+                Ok((_, (_, headers))) => return Ok(headers.get("Host").map(str::to_string)),
+                Err(e) if e.is_incomplete() && head.len() < MAX_HEAD_LEN => continue,
+                Err(_) => return Ok(None),
+            }
+        }
+    }
+
+    /// 获取隧道的上游连接并转发用户请求，head 为已经从用户连接读取的数据
+    async fn serve_user(
+        upstream: Upstream,
+        user_tcp: TcpStream,
+        user_addr: SocketAddr,
+        head: BytesMut,
+    ) {
+        let Upstream {
+            link,
+            options,
+            trusted_proxies,
+            http_settings,
+            send_peer_addr,
+        } = upstream;
+        let local_addr = user_tcp.local_addr().ok();
+        let forwarding = Forwarding {
+            user_addr,
+            port: local_addr.map(|addr| addr.port()).unwrap_or_default(),
+            trusted: trusted_proxies.contains(user_addr.ip()),
+        };
+        // 客户端发送 PROXY 头时需要用户地址和用户连接的服务器地址
+        let (peer_addr, local_addr) = if send_peer_addr {
+            (Some(user_addr), local_addr)
+        } else {
+            (None, None)
+        };
+        let options = &options;
+        let http_settings = &http_settings;
+        match link {
+            UpstreamLink::LegacyPool {
+                pool,
+                sender,
+                session_id,
+                client_ip,
+                waiters,
+            } => {
+                let mut waiter_id = None;
+                if pool.status().available == 0 {
+                    // 先登记再请求，避免代理连接比登记先到达
+                    let waiter = LegacyWaiter {
+                        pool: pool.clone(),
+                        sender: sender.clone(),
+                    };
+                    let (id, request) =
+                        waiters.lock().unwrap().push(&session_id, client_ip, waiter);
+                    waiter_id = Some(id);
+                    let msg = RTCPMessage::new(RTCPType::NewConnection(0));
+                    if request && sender.send(msg).await.is_err() {
+                        println!("❌client 已断开，无法创建新连接");
+                        return Self::reject_user(user_tcp, 502, "Bad Gateway").await;
+                    }
+                }
+
+                let res = timeout(CONNECT_TIMEOUT, pool.get()).await;
+                let mut client_tcp = match res {
+                    Ok(Ok(client_tcp)) => client_tcp,
+                    _ => {
+                        match res {
+                            Ok(Err(e)) => println!("❌获取代理连接失败 {e:?}"),
+                            _ => println!("❌等待代理连接超时，关闭用户连接"),
+                        }
+                        // 不再等待，让同一个 ip 上排队的其他会话继续
+                        if let Some(id) = waiter_id {
+                            let next = waiters.lock().unwrap().remove(id);
+                            Self::request_legacy(next).await;
+                        }
+                        return Self::reject_user(user_tcp, 502, "Bad Gateway").await;
+                    }
+                };
+                Self::proxy(
+                    user_tcp,
+                    forwarding,
+                    &head,
+                    options,
+                    http_settings,
+                    &mut client_tcp.stream,
+                    &[],
+                )
+                .await;
+                let mut client_tcp = Object::take(client_tcp);
+                let _ = client_tcp.stream.shutdown().await;
+            }
+            UpstreamLink::Paired {
+                tunnel,
+                session_id,
+                client_ip,
+                sender,
+                pending,
+            } => {
+                let msg = RTCPMessage::new(RTCPType::NewConnection(tunnel))
+                    .with_peer_addr(peer_addr)
+                    .with_local_addr(local_addr);
+                let connect_id = msg.connect_id.clone().expect("新连接消息带有 connect_id");
+                let (stream_tx, stream_rx) = oneshot::channel();
+                pending.lock().unwrap().insert(
+                    connect_id.clone(),
+                    PendingConnect {
+                        session_id,
+                        client_ip,
+                        sender: stream_tx,
+                    },
+                );
+                if sender.send(msg).await.is_err() {
+                    println!("❌client 已断开，无法创建新连接");
+                    pending.lock().unwrap().remove(&connect_id);
+                    return Self::reject_user(user_tcp, 502, "Bad Gateway").await;
+                }
+
+                match timeout(CONNECT_TIMEOUT, stream_rx).await {
+                    Ok(Ok((mut client_stream, remaining))) => {
+                        Self::proxy(
+                            user_tcp,
+                            forwarding,
+                            &head,
+                            options,
+                            http_settings,
+                            &mut client_stream,
+                            &remaining,
+                        )
+                        .await;
+                        let _ = client_stream.shutdown().await;
+                    }
+                    // 会话结束，等待被取消
+                    Ok(Err(_)) => Self::reject_user(user_tcp, 502, "Bad Gateway").await,
+                    Err(_) => {
+                        println!("❌等待代理连接 {connect_id} 超时，关闭用户连接");
+                        pending.lock().unwrap().remove(&connect_id);
+                        Self::reject_user(user_tcp, 502, "Bad Gateway").await;
+                    }
+                }
+            }
+            UpstreamLink::Mux { mux, tunnel } => {
+                match mux.open_from(tunnel, peer_addr, local_addr).await {
+                    Ok(mut stream) => {
+                        Self::proxy(
+                            user_tcp,
+                            forwarding,
+                            &head,
+                            options,
+                            http_settings,
+                            &mut stream,
+                            &[],
+                        )
+                        .await;
+                        let _ = stream.shutdown().await;
+                    }
+                    Err(e) => {
+                        println!("❌打开逻辑流失败{e:?}");
+                        Self::reject_user(user_tcp, 502, "Bad Gateway").await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// 无法转发时直接回复错误响应并关闭用户连接
+    async fn reject_user(mut user_tcp: TcpStream, status: u16, reason: &str) {
+        let _ = user_tcp.write_all(&error_response(status, reason)).await;
+        let _ = user_tcp.shutdown().await;
+    }
+
+    /// 按隧道的转发方式在用户连接和 client 连接之间转发数据
+    /// remaining 是 client 连接上已经读取、需要先转发给用户的数据
+    async fn proxy<S>(
+        user_tcp: TcpStream,
+        forwarding: Forwarding,
+        head: &[u8],
+        options: &TunnelOptions,
+        http_settings: &HttpSettings,
+        client_stream: &mut S,
+        remaining: &[u8],
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match options.mode {
+            TunnelMode::Http => {
+                Self::proxy_http(
+                    user_tcp,
+                    forwarding,
+                    head,
+                    &options.host_rewrite,
+                    http_settings,
+                    client_stream,
+                    remaining,
+                )
+                .await
             }
-            let listener = listener.unwrap();
-            println!("✅代理服务器池监听启动成功");
+            // tcp 隧道不能按主机名路由，不会预先读取用户数据
+            TunnelMode::Tcp => Self::proxy_tcp(user_tcp, client_stream, remaining).await,
+        }
+    }
+
+    /// 在用户连接和 client 连接之间原样转发字节，两个方向都结束后返回
+    async fn proxy_tcp<S>(mut user_tcp: TcpStream, client_stream: &mut S, remaining: &[u8])
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // 后端先发送数据的协议（SSH、MySQL 等）的开头可能已经被读取
+        if let Err(e) = user_tcp.write_all(remaining).await {
+            println!("❌tcp 转发中断 {e:?}");
+            return;
+        }
+        if let Err(e) = io::copy_bidirectional(&mut user_tcp, client_stream).await {
+            println!("❌tcp 转发中断 {e:?}");
+        }
+    }
 
+    /// 在用户连接和 client 连接之间转发 http 数据，任意一端断开后结束
+    async fn proxy_http<S>(
+        mut user_tcp: TcpStream,
+        forwarding: Forwarding,
+        head: &[u8],
+        host_rewrite: &HostRewrite,
+        http_settings: &HttpSettings,
+        client_stream: &mut S,
+        remaining: &[u8],
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (client_reader, mut client_writer) = io::split(client_stream);
+        // 已经读取的响应数据需要先发给用户
+        let mut client_reader = remaining.chain(client_reader);
+        let (user_reader, mut user_writer) = user_tcp.split();
+        // 已经读取的请求头需要先发给 client
+        let mut user_reader = head.chain(user_reader);
+
+        let mut http_transformer = HttpTransformer::new(forwarding, host_rewrite.clone())
+            .with_max_body_size(http_settings.max_body_size);
+        let mut response_transformer =
+            http_transformer.response_transformer(http_settings.response_rules.clone());
+
+        // 已转发的请求数和已返回的响应数，用户关闭写入后还要等待没有返回的响应
+        let requests = AtomicUsize::new(0);
+        let responses = AtomicUsize::new(0);
+        let user_closed = AtomicBool::new(false);
+        let pending = || responses.load(Ordering::Relaxed) < requests.load(Ordering::Relaxed);
+        let rejected = {
+            // 响应在多个请求之间持续转发，不能中途取消，否则会丢掉已经读取的数据
+            let response = async {
+                loop {
+                    match response_transformer
+                        .copy(&mut client_reader, &mut user_writer)
+                        .await
+                    {
+                        Ok(Some(log)) => {
+                            if http_settings.access_log {
+                                println!(
+                                    "📊[{}] {} {} {} {} {}ms {}B",
+                                    forwarding.port,
+                                    forwarding.user_addr.ip().to_canonical(),
+                                    log.method,
+                                    log.path,
+                                    log.status,
+                                    log.latency.as_millis(),
+                                    log.body_len
+                                );
+                            }
+                            responses.fetch_add(1, Ordering::Relaxed);
+                            if user_closed.load(Ordering::Relaxed) && !pending() {
+                                break None;
+                            }
+                        }
+                        Ok(None) | Err(HttpError::Io(_)) => break None,
+                        Err(HttpError::Reject(status, reason)) => break Some((status, reason)),
+                    }
+                }
+            };
+            tokio::pin!(response);
+            loop {
+                tokio::select! {
+                    res = http_transformer.copy(&mut user_reader, &mut client_writer) => match res {
+                        Ok(0) if !http_transformer.is_upgraded() && pending() => {
+                            user_closed.store(true, Ordering::Relaxed);
+                            break (&mut response).await;
+                        }
+                        Ok(0) | Err(HttpError::Io(_)) => break None,
+                        Ok(_) => {
+                            requests.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(HttpError::Reject(status, reason)) => break Some((status, reason)),
+                    },
+                    // 后端响应结束或无法解析
+                    rejected = &mut response => break rejected,
+                }
+            }
+        };
+
+        if let Some((status, reason)) = rejected {
+            println!("❌拒绝用户请求 {status} {reason}");
+            let _ = user_writer.write_all(&error_response(status, reason)).await;
+        }
+        let _ = user_tcp.shutdown().await;
+    }
+
+    /// 创建代理服务器
+    /// 用于接收 client 端的 tcp 连接，并把该连接加入到所属会话的连接池中
+    fn create_proxy_server(self: &Arc<Self>, listener: TcpListener) -> JoinHandle<()> {
+        let this = self.clone();
+        tokio::spawn(async move {
             loop {
                 let res = listener.accept().await;
                 if res.is_err() {
                     println!("❌获取代理连接失败{:?}", res);
-                    break;
+                    continue;
                 }
                 let (proxy_client, _) = res.unwrap();
 
-                match tcp_pool.add(TcpStreamData::new(proxy_client)).await {
-                    Ok(_) => {
-                        // println!("✅ 收到1个代理客户端连接成功");
-                    }
-                    Err(e) => {
-                        println!("❌代理连接添加失败{:?}", e.1);
-                        break;
-                    }
-                };
+                // tls 握手和 Attach 帧放到单独的任务中，避免阻塞接收新的代理连接
+                let this = this.clone();
+                tokio::spawn(async move {
+                    let proxy_client = match this.acceptor.accept(proxy_client).await {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            println!("❌代理连接 tls 握手失败{:?}", e);
+                            return;
+                        }
+                    };
+                    this.attach_proxy_connection(proxy_client).await;
+                });
             }
         })
     }
+
+    /// 轮到排队的旧版文本协议会话时，请求 client 新建代理连接
+    async fn request_legacy(waiters: Vec<LegacyWaiter>) {
+        for waiter in waiters {
+            let msg = RTCPMessage::new(RTCPType::NewConnection(0));
+            let _ = waiter.sender.send(msg).await;
+        }
+    }
+
+    /// 根据代理连接的第一帧找到等待它的用户连接，旧版 client 的连接加入共用连接池
+    async fn attach_proxy_connection(&self, proxy_client: RtcpStream) {
+        let client_ip = match proxy_client.tcp().peer_addr() {
+            Ok(addr) => addr.ip().to_canonical(),
+            Err(e) => {
+                println!("❌获取代理连接地址失败{:?}", e);
+                return;
+            }
+        };
+        // 旧版 client 不会发送 Attach 帧，来源 ip 上只有旧版会话在等待时直接加入它的连接池
+        let legacy = self.legacy_waiters.lock().unwrap().is_waiting(client_ip);
+        let paired = self
+            .pending
+            .lock()
+            .unwrap()
+            .values()
+            .any(|pending| pending.client_ip == client_ip);
+        if !legacy || paired {
+            self.read_attach(proxy_client, client_ip, legacy).await;
+        } else {
+            self.attach_legacy(proxy_client, client_ip).await;
+        }
+    }
+
+    /// 读取 Attach 帧并把代理连接交给等待它的用户连接，
+    /// legacy 表示同一个 ip 上还有旧版会话在等待，超时时交给旧版会话
+    async fn read_attach(&self, mut proxy_client: RtcpStream, client_ip: IpAddr, legacy: bool) {
+        let mut reader = MessageReader::new(Codec::Binary);
+        match timeout(ATTACH_TIMEOUT, reader.read_msg(&mut proxy_client)).await {
+            Ok(Ok(RTCPMessage {
+                message_type: RTCPType::Attach(connect_id),
+                ..
+            })) => {
+                let pending = self.pending.lock().unwrap().remove(&connect_id);
+                match pending {
+                    Some(pending) => {
+                        let remaining = reader.into_remaining();
+                        if pending.sender.send((proxy_client, remaining)).is_err() {
+                            println!("❌用户连接已关闭 {connect_id}，丢弃代理连接");
+                        }
+                    }
+                    None => println!("❌未知或已超时的 connect_id {connect_id}，关闭代理连接"),
+                }
+            }
+            Ok(res) => println!("❌代理连接首帧错误，关闭代理连接 {res:?}"),
+            // 旧版 client 不会主动发送数据
+            Err(_) if legacy => self.attach_legacy(proxy_client, client_ip).await,
+            Err(_) => println!("❌等待 Attach 帧超时，关闭代理连接"),
+        }
+    }
+
+    /// 把旧版 client 的代理连接加入排在最前面的旧版会话的连接池
+    async fn attach_legacy(&self, proxy_client: RtcpStream, client_ip: IpAddr) {
+        let (waiter, next) = self.legacy_waiters.lock().unwrap().arrive(client_ip);
+        Self::request_legacy(next).await;
+        let Some(waiter) = waiter else {
+            println!("❌没有会话在等待来自 {client_ip} 的代理连接，关闭代理连接");
+            return;
+        };
+        match waiter.pool.add(TcpStreamData::new(proxy_client)).await {
+            Ok(_) => {
+                // println!("✅ 收到1个代理客户端连接成功");
+            }
+            Err(e) => {
+                println!("❌代理连接添加失败{:?}", e.1);
+            }
+        };
+    }
+}
+
+/// 读取配置文件，出错时退出
+fn load_config(path: &std::path::Path) -> ServerConfig {
+    match config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("❌配置文件错误 {e}");
+            std::process::exit(1);
+        }
+    }
 }
 
 // async fn create_proxy_server()
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let r_tcp_server = RTcpServer::new().await;
-    let _ = r_tcp_server.create_connect_channel().await;
+    let args = Args::parse();
+    if let Some(Command::Validate { config }) = &args.command {
+        load_config(config);
+        println!("✅配置文件 {} 检查通过", config.display());
+        return Ok(());
+    }
+    let config = args.config.as_deref().map(load_config).unwrap_or_default();
 
-    Ok(())
+    let token_store = args.tokens.or(config.tokens).map(TokenStore::new);
+    if token_store.is_none() {
+        println!("⚠️未配置令牌文件，任何人都可以注册端口");
+    }
+    let tls = match (args.tls_cert, args.tls_key) {
+        (Some(cert), Some(key)) => Some((cert, key)),
+        _ => config.tls.map(|tls| (tls.cert, tls.key)),
+    };
+    let acceptor = match tls {
+        Some((cert, key)) => StreamAcceptor::from_pem_files(&cert, &key)?,
+        None => StreamAcceptor::Plain,
+    };
+    let http_port = args.http_port.or(config.http_port);
+    let domain = args.domain.or(config.domain);
+    if domain.is_some() && http_port.is_none() {
+        println!("❌设置域名时必须同时设置共享 http 端口");
+        std::process::exit(1);
+    }
+    let response_header = match args.response_header.is_empty() {
+        true => config.http.response_headers,
+        false => args.response_header,
+    };
+    let remove_response_header = match args.remove_response_header.is_empty() {
+        true => config.http.remove_response_headers,
+        false => args.remove_response_header,
+    };
+    let r_tcp_server = RTcpServer::new(
+        token_store,
+        acceptor,
+        args.allowed_ports.or(config.allowed_ports),
+        args.assign_ports.or(config.assign_ports),
+        http_port,
+        domain,
+        args.trusted_proxies.or(config.trusted_proxies),
+    )
+    .await
+    .with_listen_addrs(
+        args.control_addr
+            .or(config.control_addr)
+            .unwrap_or(CONTROL_ADDR),
+        args.data_addr.or(config.data_addr).unwrap_or(DATA_ADDR),
+    )
+    .with_http_settings(HttpSettings {
+        max_body_size: args.max_body_size.or(config.http.max_body_size),
+        response_rules: Arc::new(ResponseRules {
+            set: response_header,
+            remove: remove_response_header,
+        }),
+        access_log: args.access_log || config.http.access_log,
+    });
+    r_tcp_server.create_connect_channel().await
 }
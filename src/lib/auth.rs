@@ -0,0 +1,121 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// 挑战随机数长度
+pub const NONCE_LEN: usize = 32;
+
+/// 生成一次性挑战随机数
+pub fn new_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+/// 计算挑战应答：HMAC-SHA256(token, nonce || client_id)
+pub fn compute_response(token: &str, nonce: &[u8], client_id: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(token.as_bytes()).expect("hmac 支持任意长度的 key");
+    mac.update(nonce);
+    mac.update(client_id.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// 常量时间校验挑战应答
+pub fn verify_response(token: &str, nonce: &[u8], client_id: &str, response: &[u8]) -> bool {
+    let mut mac = HmacSha256::new_from_slice(token.as_bytes()).expect("hmac 支持任意长度的 key");
+    mac.update(nonce);
+    mac.update(client_id.as_bytes());
+    mac.verify_slice(response).is_ok()
+}
+
+/// client 令牌存储
+///
+/// 令牌文件每行一条 `client_id token`，`#` 开头的行为注释。
+/// 每次握手和心跳时都会重新读取文件，删除某一行即可吊销对应 client。
+#[derive(Debug, Clone)]
+pub struct TokenStore {
+    path: PathBuf,
+}
+
+impl TokenStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// 令牌文件路径
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 读取全部令牌
+    pub fn load(&self) -> io::Result<HashMap<String, String>> {
+        let content = std::fs::read_to_string(&self.path)?;
+        parse_tokens(&content)
+    }
+
+    /// 查询 client 当前的令牌，已吊销或不存在返回 None
+    pub fn token(&self, client_id: &str) -> io::Result<Option<String>> {
+        Ok(self.load()?.remove(client_id))
+    }
+}
+
+/// 解析令牌文件内容
+pub fn parse_tokens(content: &str) -> io::Result<HashMap<String, String>> {
+    let mut tokens = HashMap::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(client_id), Some(token), None) => {
+                tokens.insert(client_id.to_string(), token.to_string());
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "令牌文件第 {} 行格式错误，应为 `client_id token`",
+                        index + 1
+                    ),
+                ))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod auth_test {
+    use super::*;
+
+    #[test]
+    fn test_response_round_trip() {
+        let nonce = new_nonce();
+        let response = compute_response("secret", &nonce, "alice");
+        assert!(verify_response("secret", &nonce, "alice", &response));
+        assert!(!verify_response("other", &nonce, "alice", &response));
+        assert!(!verify_response("secret", &nonce, "bob", &response));
+        assert!(!verify_response("secret", &new_nonce(), "alice", &response));
+    }
+
+    #[test]
+    fn test_parse_tokens() {
+        let tokens = parse_tokens("# comment\nalice s1\n\n  bob   s2  \n").unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens["alice"], "s1");
+        assert_eq!(tokens["bob"], "s2");
+
+        assert!(parse_tokens("alice\n").is_err());
+        assert!(parse_tokens("alice a b\n").is_err());
+    }
+}
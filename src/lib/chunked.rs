@@ -0,0 +1,295 @@
+use std::fmt::Display;
+
+use bytes::BytesMut;
+
+use crate::parser::{is_chunk_ext, parser_request_header, MAX_HEADER_COUNT};
+
+/// 块大小行和 trailer 行的最大长度
+pub const MAX_LINE_LEN: usize = 4 * 1024;
+
+/// chunked 编码格式错误
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChunkedError {
+    /// 块大小不是合法的十六进制数
+    InvalidSize,
+    /// 块数据后面缺少 CRLF
+    MissingCrlf,
+    /// 块扩展不是 `;name=value` 格式
+    InvalidExtension,
+    /// trailer 不是 `name: value` 格式
+    InvalidTrailer,
+    /// 一行超过了 MAX_LINE_LEN
+    LineTooLong,
+    /// trailer 超过了 MAX_HEADER_COUNT 个
+    TooManyTrailers,
+}
+
+impl Display for ChunkedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChunkedError::InvalidSize => write!(f, "invalid chunk size"),
+            ChunkedError::MissingCrlf => write!(f, "missing CRLF after chunk data"),
+            ChunkedError::InvalidExtension => write!(f, "invalid chunk extension"),
+            ChunkedError::InvalidTrailer => write!(f, "invalid trailer field"),
+            ChunkedError::LineTooLong => write!(f, "chunk line too long"),
+            ChunkedError::TooManyTrailers => write!(f, "too many trailer fields"),
+        }
+    }
+}
+
+impl std::error::Error for ChunkedError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// 等待块大小行
+    Size,
+    /// 块数据还剩多少字节
+    Data(u64),
+    /// 块数据后面的 CRLF
+    DataEnd,
+    /// 最后一个块之后的 trailer，空行结束
+    Trailer,
+    Done,
+}
+
+/// 流式的 chunked 解码器，每次喂入已经读到的数据，返回其中属于消息体的字节数。
+/// 不完整的行不会被消费，调用方读到更多数据后再次传入。
+#[derive(Debug)]
+pub struct ChunkedDecoder {
+    state: State,
+    /// 已经解码的数据长度
+    body_len: u64,
+    /// 收到的 trailer
+    trailers: Vec<(String, String)>,
+}
+
+impl Default for ChunkedDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> Self {
+        Self {
+            state: State::Size,
+            body_len: 0,
+            trailers: vec![],
+        }
+    }
+
+    /// 消息体是否已经结束
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// 已经解码的数据长度，不含块大小行和 trailer
+    pub fn body_len(&self) -> u64 {
+        self.body_len
+    }
+
+    /// 收到的 trailer
+    pub fn trailers(&self) -> &[(String, String)] {
+        &self.trailers
+    }
+
+    /// 解码 input，返回消费的字节数，需要数据内容时传入 body 接收解码后的数据
+    pub fn decode(
+        &mut self,
+        input: &[u8],
+        mut body: Option<&mut BytesMut>,
+    ) -> Result<usize, ChunkedError> {
+        let mut pos = 0;
+        loop {
+            let rest = &input[pos..];
+            match self.state {
+                State::Done => return Ok(pos),
+                State::Data(remaining) => {
+                    if rest.is_empty() {
+                        return Ok(pos);
+                    }
+                    let len = rest.len().min(remaining.try_into().unwrap_or(usize::MAX));
+                    if let Some(body) = body.as_deref_mut() {
+                        body.extend_from_slice(&rest[..len]);
+                    }
+                    pos += len;
+                    self.body_len += len as u64;
+                    self.state = match remaining - len as u64 {
+                        0 => State::DataEnd,
+                        remaining => State::Data(remaining),
+                    };
+                }
+                State::DataEnd => {
+                    if rest.len() < 2 {
+                        return Ok(pos);
+                    }
+                    if &rest[..2] != b"\r\n" {
+                        return Err(ChunkedError::MissingCrlf);
+                    }
+                    pos += 2;
+                    self.state = State::Size;
+                }
+                State::Size => {
+                    let Some(line) = take_line(rest)? else {
+                        return Ok(pos);
+                    };
+                    pos += line.len() + 2;
+                    self.state = match parse_size(line)? {
+                        0 => State::Trailer,
+                        size => State::Data(size),
+                    };
+                }
+                State::Trailer => {
+                    let Some(line) = take_line(rest)? else {
+                        return Ok(pos);
+                    };
+                    pos += line.len() + 2;
+                    if line.is_empty() {
+                        self.state = State::Done;
+                        continue;
+                    }
+                    // 和请求头使用同样的规则：字段名是 token，值里不能有控制字符
+                    let (_, (name, value)) = parser_request_header(&rest[..line.len() + 2])
+                        .map_err(|_| ChunkedError::InvalidTrailer)?;
+                    // 和首部一样限制数量，避免不断发送 trailer 占用内存
+                    if self.trailers.len() >= MAX_HEADER_COUNT {
+                        return Err(ChunkedError::TooManyTrailers);
+                    }
+                    self.trailers.push((name, value));
+                }
+            }
+        }
+    }
+}
+
+/// 取出一行（不含 CRLF），行不完整时返回 None
+fn take_line(input: &[u8]) -> Result<Option<&[u8]>, ChunkedError> {
+    match input.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end > MAX_LINE_LEN => Err(ChunkedError::LineTooLong),
+        Some(end) => Ok(Some(&input[..end])),
+        None if input.len() > MAX_LINE_LEN => Err(ChunkedError::LineTooLong),
+        None => Ok(None),
+    }
+}
+
+/// 解析块大小行，`;` 之后的扩展只校验格式
+fn parse_size(line: &[u8]) -> Result<u64, ChunkedError> {
+    let size = line.split(|b| *b == b';').next().unwrap_or_default();
+    if !is_chunk_ext(&line[size.len()..]) {
+        return Err(ChunkedError::InvalidExtension);
+    }
+    let size = std::str::from_utf8(size)
+        .map_err(|_| ChunkedError::InvalidSize)?
+        .trim_end_matches([' ', '\t']);
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ChunkedError::InvalidSize);
+    }
+    u64::from_str_radix(size, 16).map_err(|_| ChunkedError::InvalidSize)
+}
+
+#[cfg(test)]
+mod chunked_test {
+    use super::*;
+
+    const BODY: &[u8] =
+        b"4\r\nWiki\r\n6;ext=1\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\nExpires: never\r\nX-Sum: 1\r\n\r\nGET / HTTP/1.1\r\n";
+
+    #[test]
+    fn test_decode() {
+        let mut decoder = ChunkedDecoder::new();
+        let mut body = BytesMut::new();
+        let used = decoder.decode(BODY, Some(&mut body)).unwrap();
+        assert!(decoder.is_done());
+        assert_eq!(&body[..], b"Wikipedia in \r\n\r\nchunks.");
+        assert_eq!(decoder.body_len(), body.len() as u64);
+        assert_eq!(&BODY[used..], b"GET / HTTP/1.1\r\n");
+        assert_eq!(
+            decoder.trailers(),
+            &[
+                ("Expires".to_string(), "never".to_string()),
+                ("X-Sum".to_string(), "1".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_byte_by_byte() {
+        let mut decoder = ChunkedDecoder::new();
+        let mut body = BytesMut::new();
+        let mut buf = BytesMut::new();
+        let mut total = 0;
+        for b in BODY {
+            if decoder.is_done() {
+                break;
+            }
+            buf.extend_from_slice(&[*b]);
+            let used = decoder.decode(&buf, Some(&mut body)).unwrap();
+            let _ = buf.split_to(used);
+            total += used;
+        }
+        assert!(decoder.is_done());
+        assert_eq!(&body[..], b"Wikipedia in \r\n\r\nchunks.");
+        assert_eq!(&BODY[total..], b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let decode = |input: &[u8]| ChunkedDecoder::new().decode(input, None);
+        assert_eq!(decode(b"x\r\n"), Err(ChunkedError::InvalidSize));
+        assert_eq!(decode(b"\r\n"), Err(ChunkedError::InvalidSize));
+        assert_eq!(decode(b"-1\r\n"), Err(ChunkedError::InvalidSize));
+        assert_eq!(
+            decode(b"fffffffffffffffff\r\n"),
+            Err(ChunkedError::InvalidSize)
+        );
+        assert_eq!(decode(b"1\r\nabc"), Err(ChunkedError::MissingCrlf));
+        assert_eq!(decode(b"0\r\nbad\r\n"), Err(ChunkedError::InvalidTrailer));
+        for trailer in [
+            &b"Bad Name: 1"[..],
+            b" X-Sum: 1",
+            b"(x): 1",
+            b"X-Sum: a\nb",
+            b"X-Sum: a\rb",
+            b"X-Sum: \x7f",
+        ] {
+            let input = [&b"0\r\n"[..], trailer, b"\r\n\r\n"].concat();
+            assert_eq!(
+                decode(&input),
+                Err(ChunkedError::InvalidTrailer),
+                "{trailer:?}"
+            );
+        }
+        for size in [
+            &b"1;"[..],
+            b"1;a b",
+            b"1;a=",
+            b"1;a=b c",
+            b"1;a=\"b",
+            b"1;a=\"b\x01\"",
+            b"1;a=\"\\\n\"",
+            b"1;a=\x01",
+            b"1;a\nb",
+            b"1;(a)",
+        ] {
+            let input = [size, b"\r\nx\r\n0\r\n\r\n"].concat();
+            assert_eq!(
+                decode(&input),
+                Err(ChunkedError::InvalidExtension),
+                "{size:?}"
+            );
+        }
+        assert!(decode(b"1 ; a = \"b;\\\"c\" ;d\t;e=f\r\nx\r\n0\r\n\r\n").is_ok());
+        assert_eq!(
+            decode(&vec![b'1'; MAX_LINE_LEN + 1]),
+            Err(ChunkedError::LineTooLong)
+        );
+        let trailers = "X-Sum: 1\r\n".repeat(MAX_HEADER_COUNT);
+        assert!(decode(format!("0\r\n{trailers}\r\n").as_bytes()).is_ok());
+        assert_eq!(
+            decode(format!("0\r\n{trailers}X-Sum: 1\r\n").as_bytes()),
+            Err(ChunkedError::TooManyTrailers)
+        );
+        // 不完整的数据不算错误
+        assert_eq!(decode(b"1"), Ok(0));
+    }
+}
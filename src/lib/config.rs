@@ -0,0 +1,546 @@
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use clap::ValueEnum;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+
+use crate::{
+    forwarded::TrustedProxies,
+    ports::PortRanges,
+    protocol::{RejectReason, TunnelMode},
+    proxy_protocol::ProxyProtocol,
+    tls::{parse_fingerprint, TlsVerify},
+    transformer::parse_header_field,
+    tunnel::{HostHeader, TunnelAccess, TunnelSpec},
+};
+
+/// 配置文件错误，能定位时带有出错的行号和列号
+#[derive(Debug)]
+pub struct ConfigError {
+    pub path: PathBuf,
+    /// 出错位置的行号和列号，从 1 开始
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.position {
+            Some((line, column)) => write!(
+                f,
+                "{}:{line}:{column}: {}",
+                self.path.display(),
+                self.message
+            ),
+            None => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// 读取并解析 toml 配置文件，配置中的相对路径相对于配置文件所在的目录
+pub fn load<T: ConfigFile>(path: &Path) -> Result<T, ConfigError> {
+    let content = std::fs::read_to_string(path).map_err(|e| ConfigError {
+        path: path.to_path_buf(),
+        position: None,
+        message: e.to_string(),
+    })?;
+    let mut config = parse::<T>(&content).map_err(|(position, message)| ConfigError {
+        path: path.to_path_buf(),
+        position,
+        message,
+    })?;
+    config.resolve_paths(path.parent().unwrap_or(Path::new("")));
+    Ok(config)
+}
+
+/// 解析并检查配置内容，错误时返回出错的位置和原因
+fn parse<T: ConfigFile>(content: &str) -> Result<T, (Option<(usize, usize)>, String)> {
+    let config: T = toml::from_str(content).map_err(|e: toml::de::Error| {
+        let position = e.span().map(|span| line_column(content, span.start));
+        (position, e.message().to_string())
+    })?;
+    // 跨越整个文件的约束没有对应的位置
+    config.check().map_err(|message| (None, message))?;
+    Ok(config)
+}
+
+/// 字节偏移对应的行号和列号
+fn line_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+    (line, column)
+}
+
+/// 可以从 toml 文件加载的配置
+pub trait ConfigFile: DeserializeOwned {
+    /// 把相对路径转换为相对于 dir 的路径
+    fn resolve_paths(&mut self, dir: &Path);
+
+    /// 检查顶层字段之间的约束
+    fn check(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+fn resolve_path(dir: &Path, path: &mut Option<PathBuf>) {
+    if let Some(path) = path {
+        if path.is_relative() {
+            *path = dir.join(&*path);
+        }
+    }
+}
+
+/// 用 FromStr 解析字符串配置项，错误会定位到该值
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+/// 可选的 FromStr 配置项
+fn from_str_opt<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    from_str(deserializer).map(Some)
+}
+
+/// 服务器配置文件
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// 控制通道的监听地址
+    pub control_addr: Option<SocketAddr>,
+    /// 代理连接的监听地址
+    pub data_addr: Option<SocketAddr>,
+    /// client 令牌文件
+    pub tokens: Option<PathBuf>,
+    /// tls 证书和私钥
+    pub tls: Option<TlsFiles>,
+    /// 允许 client 注册的访问端口范围
+    #[serde(deserialize_with = "from_str_opt")]
+    pub allowed_ports: Option<PortRanges>,
+    /// client 请求端口 0 时分配访问端口的范围
+    #[serde(deserialize_with = "from_str_opt")]
+    pub assign_ports: Option<PortRanges>,
+    /// 按 Host 头路由的共享 http 端口
+    pub http_port: Option<u16>,
+    /// 共享 http 端口的域名
+    pub domain: Option<String>,
+    /// 可信代理的网段
+    #[serde(deserialize_with = "from_str_opt")]
+    pub trusted_proxies: Option<TrustedProxies>,
+    /// http 隧道的设置
+    pub http: HttpConfig,
+}
+
+impl ConfigFile for ServerConfig {
+    fn resolve_paths(&mut self, dir: &Path) {
+        resolve_path(dir, &mut self.tokens);
+        if let Some(tls) = &mut self.tls {
+            tls.cert = dir.join(&tls.cert);
+            tls.key = dir.join(&tls.key);
+        }
+    }
+}
+
+/// 服务器的 tls 证书链和私钥文件（pem）
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// 服务器对所有 http 隧道生效的设置
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// 请求体的最大字节数
+    pub max_body_size: Option<u64>,
+    /// 添加到响应中的响应头，格式同 `--response-header`
+    #[serde(deserialize_with = "header_fields")]
+    pub response_headers: Vec<(String, String)>,
+    /// 从响应中删除的响应头
+    pub remove_response_headers: Vec<String>,
+    /// 是否打印访问日志
+    pub access_log: bool,
+}
+
+/// 解析 `Name: value` 形式的头部字段列表
+fn header_fields<'de, D>(deserializer: D) -> Result<Vec<(String, String)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|field| parse_header_field(field).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// 服务器拒绝初始化时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InitErrorPolicy {
+    /// 端口被占用等临时错误时重试，端口不允许、认证失败时退出
+    Auto,
+    /// 总是退出
+    Exit,
+    /// 总是重试
+    Retry,
+}
+
+impl InitErrorPolicy {
+    pub fn should_retry(self, reason: RejectReason) -> bool {
+        match self {
+            InitErrorPolicy::Auto => reason.is_transient(),
+            InitErrorPolicy::Exit => false,
+            InitErrorPolicy::Retry => true,
+        }
+    }
+}
+
+/// client 配置文件
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// rtcp 服务器的主机名或 ip
+    pub server: Option<String>,
+    /// 服务器控制通道的端口
+    pub server_port: Option<u16>,
+    /// 服务器代理连接的端口
+    pub data_port: Option<u16>,
+    /// 认证凭据
+    pub auth: Option<AuthConfig>,
+    /// tls 校验方式
+    pub tls: Option<ClientTls>,
+    /// 多路复用模式
+    pub mux: bool,
+    /// 使用旧版文本协议
+    pub legacy_text: bool,
+    /// 服务器拒绝初始化时的处理方式
+    pub on_init_error: Option<InitErrorPolicy>,
+    /// 连接服务器和后端的超时时间（秒）
+    #[serde(deserialize_with = "positive_secs")]
+    pub connect_timeout: Option<u64>,
+    /// 全部隧道，第一个通过 Initialize 注册
+    pub tunnels: Vec<TunnelConfig>,
+}
+
+impl ConfigFile for ClientConfig {
+    fn resolve_paths(&mut self, dir: &Path) {
+        if let Some(ClientTls {
+            verify: TlsVerify::CaFile(ca),
+            ..
+        }) = &mut self.tls
+        {
+            *ca = dir.join(&*ca);
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if !self.legacy_text {
+            return Ok(());
+        }
+        let tunnels: Vec<_> = self.tunnels.iter().map(|tunnel| tunnel.0.clone()).collect();
+        check_legacy_text(self.mux, &tunnels)
+    }
+}
+
+/// 旧版文本协议只能注册一个使用默认选项的端口隧道
+pub fn check_legacy_text(mux: bool, tunnels: &[TunnelSpec]) -> Result<(), String> {
+    let supported = !mux
+        && tunnels.len() <= 1
+        && tunnels.iter().all(|spec| {
+            matches!(spec.access, TunnelAccess::Port(_))
+                && spec.host_header == HostHeader::default()
+                && spec.mode == TunnelMode::default()
+                && spec.proxy_protocol.is_none()
+        });
+    match supported {
+        true => Ok(()),
+        false => Err("旧版文本协议不支持多路复用、多隧道和隧道选项".to_string()),
+    }
+}
+
+/// 大于 0 的秒数
+fn positive_secs<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match u64::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom("超时时间必须大于 0")),
+        secs => Ok(Some(secs)),
+    }
+}
+
+/// client 认证凭据
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    pub client_id: String,
+    pub token: String,
+}
+
+/// client 校验服务器证书的方式
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RawClientTls")]
+pub struct ClientTls {
+    pub verify: TlsVerify,
+    /// 校验使用的服务器名称，默认为服务器ip
+    pub server_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawClientTls {
+    ca: Option<PathBuf>,
+    fingerprint: Option<String>,
+    server_name: Option<String>,
+}
+
+impl TryFrom<RawClientTls> for ClientTls {
+    type Error = String;
+
+    fn try_from(raw: RawClientTls) -> Result<Self, Self::Error> {
+        let verify = match (raw.ca, raw.fingerprint) {
+            (Some(ca), None) => TlsVerify::CaFile(ca),
+            (None, Some(fingerprint)) => {
+                parse_fingerprint(&fingerprint)
+                    .map_err(|e| format!("tls 指纹 `{fingerprint}` 无效：{e}"))?;
+                TlsVerify::Fingerprint(fingerprint)
+            }
+            _ => return Err("tls 需要设置 `ca` 或 `fingerprint` 中的一个".to_string()),
+        };
+        Ok(Self {
+            verify,
+            server_name: raw.server_name,
+        })
+    }
+}
+
+/// 配置文件中的隧道
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RawTunnel")]
+pub struct TunnelConfig(pub TunnelSpec);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTunnel {
+    #[serde(deserialize_with = "from_str")]
+    backend: BackendAddr,
+    /// 访问端口，0 表示由服务器分配
+    port: Option<u16>,
+    /// 共享 http 端口上的主机名
+    host: Option<String>,
+    #[serde(default, deserialize_with = "from_str")]
+    mode: TunnelMode,
+    #[serde(default, deserialize_with = "from_str")]
+    host_header: HostHeader,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    proxy_protocol: Option<ProxyProtocol>,
+}
+
+/// 后端地址 `host:port`，host 可以是主机名，IPv6 地址可以写成 `[::1]:3000`
+struct BackendAddr {
+    ip: String,
+    port: u16,
+}
+
+impl FromStr for BackendAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, port) = s
+            .rsplit_once(':')
+            .and_then(|(ip, port)| Some((ip, port.parse::<u16>().ok()?)))
+            .filter(|(ip, _)| !ip.is_empty())
+            .ok_or_else(|| format!("后端地址 `{s}` 无效，应为 `host:port`"))?;
+        Ok(Self {
+            ip: ip.trim_start_matches('[').trim_end_matches(']').to_string(),
+            port,
+        })
+    }
+}
+
+impl TryFrom<RawTunnel> for TunnelConfig {
+    type Error = String;
+
+    fn try_from(raw: RawTunnel) -> Result<Self, Self::Error> {
+        let access = match (raw.port, raw.host) {
+            (Some(port), None) => TunnelAccess::Port(port),
+            (None, Some(host)) if !host.is_empty() => TunnelAccess::Host(host),
+            _ => {
+                return Err(format!(
+                    "隧道 `{}:{}` 需要设置 `port` 或 `host` 中的一个",
+                    raw.backend.ip, raw.backend.port
+                ))
+            }
+        };
+        Ok(Self(TunnelSpec {
+            backend_ip: raw.backend.ip,
+            backend_port: raw.backend.port,
+            access,
+            host_header: raw.host_header,
+            proxy_protocol: raw.proxy_protocol,
+            mode: raw.mode,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod config_test {
+    use super::*;
+
+    #[test]
+    fn test_server_config() {
+        let config: ServerConfig = parse(
+            r#"
+control_addr = "[::]:6000"
+tokens = "tokens.txt"
+allowed_ports = "7000-7999,8080"
+trusted_proxies = "10.0.0.0/8"
+
+[tls]
+cert = "cert.pem"
+key = "/etc/rtcp/key.pem"
+
+[http]
+max_body_size = 1024
+response_headers = ["Strict-Transport-Security: max-age=63072000"]
+remove_response_headers = ["Server"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.control_addr, Some("[::]:6000".parse().unwrap()));
+        assert!(config.data_addr.is_none());
+        assert!(config.allowed_ports.unwrap().contains(8080));
+        assert_eq!(config.http.max_body_size, Some(1024));
+        assert_eq!(
+            config.http.response_headers,
+            [(
+                "Strict-Transport-Security".to_string(),
+                "max-age=63072000".to_string()
+            )]
+        );
+        assert!(!config.http.access_log);
+
+        let mut config: ServerConfig =
+            parse("tokens = \"tokens.txt\"\ntls = { cert = \"a.pem\", key = \"/b.pem\" }").unwrap();
+        config.resolve_paths(Path::new("/etc/rtcp"));
+        assert_eq!(config.tokens.unwrap(), Path::new("/etc/rtcp/tokens.txt"));
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.cert, Path::new("/etc/rtcp/a.pem"));
+        assert_eq!(tls.key, Path::new("/b.pem"));
+    }
+
+    #[test]
+    fn test_client_config() {
+        let config: ClientConfig = parse(
+            r#"
+server = "::1"
+mux = true
+on_init_error = "retry"
+connect_timeout = 3
+
+[auth]
+client_id = "alice"
+token = "s3cret"
+
+[tls]
+fingerprint = "AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89"
+
+[[tunnels]]
+backend = "127.0.0.1:3000"
+port = 7002
+host_header = "backend"
+
+[[tunnels]]
+backend = "[::1]:22"
+host = "ssh"
+mode = "tcp"
+proxy_protocol = "v2"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.server.as_deref(), Some("::1"));
+        assert!(config.mux);
+        assert_eq!(config.on_init_error, Some(InitErrorPolicy::Retry));
+        assert_eq!(config.connect_timeout, Some(3));
+        assert_eq!(config.auth.unwrap().client_id, "alice");
+        assert!(matches!(
+            config.tls.unwrap().verify,
+            TlsVerify::Fingerprint(_)
+        ));
+        let tunnels: Vec<_> = config.tunnels.into_iter().map(|t| t.0).collect();
+        assert_eq!(
+            tunnels[0],
+            "127.0.0.1:3000:7002,host=backend".parse().unwrap()
+        );
+        assert_eq!(tunnels[1], "::1:22:ssh,mode=tcp,proxy=v2".parse().unwrap());
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let error = |content: &str| parse::<ClientConfig>(content).unwrap_err();
+
+        // 语法错误
+        let (position, _) = error("server = \"a\"\nmux = \n");
+        assert_eq!(position.unwrap().0, 2);
+        // 类型错误和未知字段
+        let (position, message) = error("server = \"a\"\nserver_port = 70000\n");
+        assert_eq!(position.unwrap().0, 2, "{message}");
+        let (position, message) = error("\nsever = \"a\"\n");
+        assert_eq!(position.unwrap(), (2, 1));
+        assert!(message.contains("sever"), "{message}");
+        // 值的格式错误定位到该值
+        let (position, message) = error(
+            "[[tunnels]]\nbackend = \"127.0.0.1:3000\"\nport = 1\n\n[[tunnels]]\nbackend = \"127.0.0.1:3001\"\nport = 2\nmode = \"udp\"\n",
+        );
+        assert_eq!(position.unwrap().0, 8, "{message}");
+        let (position, message) = error(
+            "[[tunnels]]\nbackend = \"127.0.0.1:3000\"\nport = 1\n\n[[tunnels]]\nbackend = \"127.0.0.1\"\nport = 2\n",
+        );
+        assert_eq!(position.unwrap().0, 6, "{message}");
+        // 字段之间的约束定位到所在的表
+        let (position, message) =
+            error("mux = true\n\n[[tunnels]]\nbackend = \"127.0.0.1:3000\"\n");
+        assert_eq!(position.unwrap().0, 3, "{message}");
+        assert!(message.contains("127.0.0.1:3000"), "{message}");
+        let (position, message) = error("[auth]\nclient_id = \"alice\"\n");
+        assert_eq!(position.unwrap().0, 1, "{message}");
+        assert!(message.contains("token"), "{message}");
+
+        // 值的约束定位到该值
+        let (position, message) = error("server = \"a\"\nconnect_timeout = 0\n");
+        assert_eq!(position.unwrap().0, 2, "{message}");
+        let (position, message) = error("\n[tls]\nfingerprint = \"ab:cd\"\n");
+        assert_eq!(position.unwrap().0, 2, "{message}");
+        assert!(message.contains("ab:cd"), "{message}");
+        // 旧版文本协议的限制
+        let tunnel = "[[tunnels]]\nbackend = \"127.0.0.1:3000\"\nport = 7002\n";
+        assert!(parse::<ClientConfig>(&format!("legacy_text = true\n{tunnel}")).is_ok());
+        let (_, message) = error(&format!("legacy_text = true\n{tunnel}{tunnel}"));
+        assert!(message.contains("旧版文本协议"), "{message}");
+        error(&format!("legacy_text = true\nmux = true\n{tunnel}"));
+        error(&format!("legacy_text = true\n{tunnel}mode = \"tcp\"\n"));
+
+        let (position, message) =
+            parse::<ServerConfig>("[http]\nresponse_headers = [\"bad\"]\n").unwrap_err();
+        assert_eq!(position.unwrap().0, 2, "{message}");
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+/// 可信代理的网段列表，例如 `10.0.0.0/8,127.0.0.1,::1`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrustedProxies(Vec<(IpAddr, u8)>);
+
+impl TrustedProxies {
+    /// 地址是否在任一网段内
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|(net, prefix)| match (net, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                u32::from(*net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                u128::from(*net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        })
+    }
+}
+
+impl FromStr for TrustedProxies {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut nets = vec![];
+        for part in s.split(',').map(str::trim) {
+            let (ip, prefix) = part.split_once('/').unwrap_or((part, ""));
+            let ip = ip
+                .parse::<IpAddr>()
+                .map_err(|_| format!("地址 `{part}` 无效"))?
+                .to_canonical();
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                "" => max,
+                prefix => prefix
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|prefix| *prefix <= max)
+                    .ok_or_else(|| format!("网段 `{part}` 的前缀长度无效"))?,
+            };
+            nets.push((ip, prefix));
+        }
+        Ok(Self(nets))
+    }
+}
+
+/// `Forwarded` 头中的节点，IPv6 需要加方括号和引号
+pub fn forwarded_node(addr: SocketAddr) -> String {
+    match addr.ip().to_canonical() {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{ip}]\""),
+    }
+}
+
+/// `Forwarded` 头中的值，不是 token 时加引号
+pub fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod forwarded_test {
+    use super::*;
+
+    #[test]
+    fn test_trusted_proxies() {
+        let trusted: TrustedProxies = "10.0.0.0/8, 127.0.0.1,fd00::/8".parse().unwrap();
+        assert!(trusted.contains("10.1.2.3".parse().unwrap()));
+        assert!(trusted.contains("127.0.0.1".parse().unwrap()));
+        assert!(!trusted.contains("127.0.0.2".parse().unwrap()));
+        assert!(!trusted.contains("11.0.0.1".parse().unwrap()));
+        assert!(trusted.contains("fd12::1".parse().unwrap()));
+        assert!(!trusted.contains("fe80::1".parse().unwrap()));
+        // 双栈监听时 IPv4 地址以映射形式出现
+        assert!(trusted.contains("::ffff:10.0.0.1".parse().unwrap()));
+
+        let all: TrustedProxies = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("8.8.8.8".parse().unwrap()));
+        assert!(!TrustedProxies::default().contains("127.0.0.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<TrustedProxies>().is_err());
+        assert!("10.0.0/8".parse::<TrustedProxies>().is_err());
+        assert!("".parse::<TrustedProxies>().is_err());
+    }
+
+    #[test]
+    fn test_forwarded_format() {
+        assert_eq!(forwarded_node("1.2.3.4:5".parse().unwrap()), "1.2.3.4");
+        assert_eq!(
+            forwarded_node("[2001:db8::1]:5".parse().unwrap()),
+            "\"[2001:db8::1]\""
+        );
+        assert_eq!(forwarded_value("example.com"), "example.com");
+        assert_eq!(forwarded_value("example.com:8080"), "\"example.com:8080\"");
+    }
+}
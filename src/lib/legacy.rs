@@ -0,0 +1,181 @@
+use std::{collections::VecDeque, net::IpAddr};
+
+/// 旧版文本协议的代理连接不带任何标识，只能按来源 ip 和请求顺序交给会话。
+/// 同一个 ip 上同时只有一个会话在等待代理连接，其他会话的请求排队，
+/// 避免两个会话的代理连接到达顺序和请求顺序不一致时互相串用。
+#[derive(Debug)]
+pub struct LegacyQueue<T> {
+    entries: VecDeque<Entry<T>>,
+    next_id: u64,
+}
+
+#[derive(Debug)]
+struct Entry<T> {
+    id: u64,
+    session_id: String,
+    client_ip: IpAddr,
+    value: T,
+    /// 是否已经请求 client 新建代理连接
+    requested: bool,
+}
+
+impl<T> Default for LegacyQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> LegacyQueue<T> {
+    pub fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            next_id: 0,
+        }
+    }
+}
+
+impl<T: Clone> LegacyQueue<T> {
+    /// 登记一个等待代理连接的用户连接，返回登记的 id 和是否可以立即请求 client 新建连接
+    pub fn push(&mut self, session_id: &str, client_ip: IpAddr, value: T) -> (u64, bool) {
+        let id = self.next_id;
+        self.next_id += 1;
+        // 同一个 ip 上只有本会话在等待时才能立即请求
+        let requested = self
+            .entries
+            .iter()
+            .filter(|entry| entry.client_ip == client_ip)
+            .all(|entry| entry.session_id == session_id);
+        self.entries.push_back(Entry {
+            id,
+            session_id: session_id.to_string(),
+            client_ip,
+            value,
+            requested,
+        });
+        (id, requested)
+    }
+
+    /// 是否有来自 client_ip 的会话在等待已经请求的代理连接
+    pub fn is_waiting(&self, client_ip: IpAddr) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| entry.client_ip == client_ip && entry.requested)
+    }
+
+    /// 来自 client_ip 的代理连接到达，返回等待它的值，以及之后可以请求新建连接的值
+    pub fn arrive(&mut self, client_ip: IpAddr) -> (Option<T>, Vec<T>) {
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.client_ip == client_ip && entry.requested);
+        let value = index
+            .and_then(|index| self.entries.remove(index))
+            .map(|entry| entry.value);
+        (value, self.activate(client_ip))
+    }
+
+    /// 用户连接不再等待，返回之后可以请求新建连接的值
+    pub fn remove(&mut self, id: u64) -> Vec<T> {
+        let Some(index) = self.entries.iter().position(|entry| entry.id == id) else {
+            return vec![];
+        };
+        let entry = self.entries.remove(index).expect("index 有效");
+        self.activate(entry.client_ip)
+    }
+
+    /// 会话结束，返回之后可以请求新建连接的值
+    pub fn remove_session(&mut self, session_id: &str) -> Vec<T> {
+        let mut ips = vec![];
+        self.entries.retain(|entry| {
+            if entry.session_id == session_id && !ips.contains(&entry.client_ip) {
+                ips.push(entry.client_ip);
+            }
+            entry.session_id != session_id
+        });
+        ips.into_iter().flat_map(|ip| self.activate(ip)).collect()
+    }
+
+    /// client_ip 上没有已请求的连接时，轮到最早排队的会话，返回它排队的值
+    fn activate(&mut self, client_ip: IpAddr) -> Vec<T> {
+        let mut same_ip = self
+            .entries
+            .iter_mut()
+            .filter(|entry| entry.client_ip == client_ip)
+            .peekable();
+        let Some(first) = same_ip.peek() else {
+            return vec![];
+        };
+        let session_id = first.session_id.clone();
+        let same_ip: Vec<_> = same_ip.collect();
+        if same_ip.iter().any(|entry| entry.requested) {
+            return vec![];
+        }
+        same_ip
+            .into_iter()
+            .filter(|entry| entry.session_id == session_id)
+            .map(|entry| {
+                entry.requested = true;
+                entry.value.clone()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod legacy_test {
+    use super::*;
+
+    const IP_A: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const IP_B: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+
+    #[test]
+    fn test_same_ip_sessions_take_turns() {
+        let mut queue = LegacyQueue::new();
+        assert!(!queue.is_waiting(IP_A));
+        assert!(queue.push("s1", IP_A, "a1").1);
+        assert!(queue.push("s1", IP_A, "a2").1);
+        assert!(queue.is_waiting(IP_A));
+        // 同一个 ip 的其他会话排队，不同 ip 互不影响
+        assert!(!queue.push("s2", IP_A, "b1").1);
+        assert!(queue.push("s3", IP_B, "c1").1);
+        // 排队之后本会话的新请求也要等待
+        assert!(!queue.push("s1", IP_A, "a3").1);
+
+        assert_eq!(queue.arrive(IP_A), (Some("a1"), vec![]));
+        assert_eq!(queue.arrive(IP_B), (Some("c1"), vec![]));
+        // s1 已请求的连接都到达后轮到 s2
+        assert_eq!(queue.arrive(IP_A), (Some("a2"), vec!["b1"]));
+        assert_eq!(queue.arrive(IP_A), (Some("b1"), vec!["a3"]));
+        assert_eq!(queue.arrive(IP_A), (Some("a3"), vec![]));
+        assert!(!queue.is_waiting(IP_A));
+        assert_eq!(queue.arrive(IP_A), (None, vec![]));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut queue = LegacyQueue::new();
+        let (id, _) = queue.push("s1", IP_A, "a1");
+        queue.push("s2", IP_A, "b1");
+        queue.push("s2", IP_A, "b2");
+        queue.push("s3", IP_A, "c1");
+        // 等待超时的连接不再阻塞其他会话
+        assert_eq!(queue.remove(id), vec!["b1", "b2"]);
+        assert_eq!(queue.remove_session("s2"), vec!["c1"]);
+        assert_eq!(queue.arrive(IP_A), (Some("c1"), vec![]));
+        assert!(queue.remove(id).is_empty());
+    }
+
+    #[test]
+    fn test_queued_session_waits_for_requested() {
+        let mut queue = LegacyQueue::new();
+        queue.push("s1", IP_A, "a1");
+        queue.push("s2", IP_A, "b1");
+        queue.push("s1", IP_A, "a2");
+        queue.push("s2", IP_A, "b2");
+        assert_eq!(queue.arrive(IP_A), (Some("a1"), vec!["b1", "b2"]));
+        // a2 排在最前面，但 s2 已请求的连接全部到达之前不能轮到 s1
+        assert_eq!(queue.arrive(IP_A), (Some("b1"), vec![]));
+        assert_eq!(queue.arrive(IP_A), (Some("b2"), vec!["a2"]));
+        assert_eq!(queue.arrive(IP_A), (Some("a2"), vec![]));
+    }
+}
@@ -1,5 +1,16 @@
+pub mod auth;
+pub mod chunked;
+pub mod config;
+pub mod forwarded;
+pub mod legacy;
+pub mod manage;
+pub mod mux;
 pub mod parser;
+pub mod ports;
 pub mod protocol;
-pub mod manage;
+pub mod proxy_protocol;
 pub mod tcp_pool;
-pub mod transformer;
\ No newline at end of file
+pub mod tls;
+pub mod transformer;
+pub mod tunnel;
+pub mod vhost;
@@ -19,6 +19,12 @@ pub struct RTCPManager {
     inner: RTCPConnectionMap,
 }
 
+impl Default for RTCPManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RTCPManager {
     pub fn new() -> Self {
         RTCPManager {
@@ -29,7 +35,7 @@ impl RTCPManager {
     /// 检查connect_id 是否有效
     fn check_connect_id(&self, connect_id: &ConnectId) -> io::Result<bool> {
         if connect_id.is_none() {
-            return Err(io::Error::new(io::ErrorKind::Other, "connect_id is none"));
+            return Err(io::Error::other("connect_id is none"));
         }
         Ok(true)
     }
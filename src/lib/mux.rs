@@ -0,0 +1,431 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
+    sync::{
+        mpsc::{self, Sender, UnboundedReceiver, UnboundedSender},
+        Semaphore,
+    },
+};
+
+use crate::protocol::{RTCPMessage, RTCPType, TunnelId};
+
+/// 逻辑流 id
+pub type StreamId = u32;
+
+/// 每个逻辑流的初始流控窗口
+pub const STREAM_WINDOW: u32 = 256 * 1024;
+
+/// 单个数据帧的最大负载
+pub const MAX_DATA_FRAME: usize = 16 * 1024;
+
+/// 逻辑流在多路复用器中的状态
+struct StreamHandle {
+    /// 对端发来的数据，交给写入任务写到本地，对端不再发送后为 None
+    data_tx: Option<UnboundedSender<Bytes>>,
+    /// 向对端发送数据的额度，对端确认后归还
+    send_credit: Arc<Semaphore>,
+    /// 对端还可以发送而未被确认的字节数
+    recv_window: Arc<AtomicU32>,
+    /// 本地已经写完，发送过 StreamShutdown
+    local_done: bool,
+}
+
+/// 控制连接上的多路复用器
+///
+/// 每个逻辑流在本地表现为一个 `DuplexStream`，由两个任务负责搬运数据：
+/// 一个把本地写入的数据按额度切成数据帧发给对端，另一个把对端的数据帧写入本地，
+/// 本地读走数据后再向对端归还窗口。
+/// 一端写完后发送 StreamShutdown 半关闭，两个方向都结束后流才会移除。
+pub struct Multiplexer {
+    streams: Mutex<HashMap<StreamId, StreamHandle>>,
+    next_id: AtomicU32,
+    /// 发往对端的帧
+    frames: Sender<RTCPMessage>,
+}
+
+impl Multiplexer {
+    /// 创建多路复用器，server 端打开奇数 id 的流，client 端打开偶数 id 的流
+    pub fn new(frames: Sender<RTCPMessage>, is_server: bool) -> Arc<Self> {
+        Arc::new(Self {
+            streams: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(if is_server { 1 } else { 2 }),
+            frames,
+        })
+    }
+
+    /// 当前活跃的逻辑流数量
+    pub fn stream_count(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    /// 主动打开一个逻辑流，对端根据隧道 id 决定转发到哪个后端
+    pub async fn open(self: &Arc<Self>, tunnel: TunnelId) -> io::Result<DuplexStream> {
+        self.open_from(tunnel, None, None).await
+    }
+
+    /// 打开逻辑流并告知对端用户地址和用户连接的服务器地址
+    pub async fn open_from(
+        self: &Arc<Self>,
+        tunnel: TunnelId,
+        peer_addr: Option<SocketAddr>,
+        local_addr: Option<SocketAddr>,
+    ) -> io::Result<DuplexStream> {
+        let id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let stream = self.register(id);
+        let msg = RTCPMessage::new(RTCPType::StreamOpen(id, tunnel))
+            .with_peer_addr(peer_addr)
+            .with_local_addr(local_addr);
+        self.send_msg(msg).await?;
+        Ok(stream)
+    }
+
+    /// 接受对端打开的逻辑流
+    pub fn accept(self: &Arc<Self>, id: StreamId) -> io::Result<DuplexStream> {
+        if self.streams.lock().unwrap().contains_key(&id) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("stream {id} already open"),
+            ));
+        }
+        Ok(self.register(id))
+    }
+
+    /// 处理对端发来的多路复用帧，不是多路复用帧时返回 false
+    pub fn handle_frame(self: &Arc<Self>, message_type: &RTCPType) -> bool {
+        match message_type {
+            RTCPType::StreamData(id, data) => self.on_data(*id, data.clone()),
+            RTCPType::StreamWindowUpdate(id, delta) => self.on_window_update(*id, *delta),
+            RTCPType::StreamShutdown(id) => self.on_shutdown(*id),
+            RTCPType::StreamClose(id) => self.on_close(*id),
+            _ => return false,
+        }
+        true
+    }
+
+    /// 关闭所有逻辑流，控制连接断开时调用
+    pub fn close_all(&self) {
+        let streams = std::mem::take(&mut *self.streams.lock().unwrap());
+        for handle in streams.into_values() {
+            handle.send_credit.close();
+        }
+    }
+
+    fn register(self: &Arc<Self>, id: StreamId) -> DuplexStream {
+        let (local, remote) = tokio::io::duplex(STREAM_WINDOW as usize);
+        let (remote_reader, remote_writer) = tokio::io::split(remote);
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        let send_credit = Arc::new(Semaphore::new(STREAM_WINDOW as usize));
+        let recv_window = Arc::new(AtomicU32::new(STREAM_WINDOW));
+
+        self.streams.lock().unwrap().insert(
+            id,
+            StreamHandle {
+                data_tx: Some(data_tx),
+                send_credit: send_credit.clone(),
+                recv_window: recv_window.clone(),
+                local_done: false,
+            },
+        );
+
+        tokio::spawn(self.clone().outbound(id, remote_reader, send_credit));
+        tokio::spawn(
+            self.clone()
+                .inbound(id, remote_writer, data_rx, recv_window),
+        );
+        local
+    }
+
+    /// 把本地写入的数据发给对端
+    async fn outbound(
+        self: Arc<Self>,
+        id: StreamId,
+        mut reader: ReadHalf<DuplexStream>,
+        send_credit: Arc<Semaphore>,
+    ) {
+        let mut buf = vec![0u8; MAX_DATA_FRAME];
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(0) => return self.shutdown(id).await,
+                Err(_) => break,
+                Ok(size) => {
+                    // 额度不足时等待对端归还窗口，流被关闭时 acquire 会失败
+                    let Ok(permit) = send_credit.acquire_many(size as u32).await else {
+                        return;
+                    };
+                    permit.forget();
+                    let data = Bytes::copy_from_slice(&buf[..size]);
+                    if self.send(RTCPType::StreamData(id, data)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        self.close(id).await;
+    }
+
+    /// 把对端的数据写入本地，写入成功后归还窗口
+    async fn inbound(
+        self: Arc<Self>,
+        id: StreamId,
+        mut writer: WriteHalf<DuplexStream>,
+        mut data_rx: UnboundedReceiver<Bytes>,
+        recv_window: Arc<AtomicU32>,
+    ) {
+        while let Some(data) = data_rx.recv().await {
+            if writer.write_all(&data).await.is_err() {
+                // 本地已经不再读取，整个流关闭
+                self.close(id).await;
+                return;
+            }
+            let size = data.len() as u32;
+            recv_window.fetch_add(size, Ordering::Relaxed);
+            let _ = self.send(RTCPType::StreamWindowUpdate(id, size)).await;
+        }
+        let _ = writer.shutdown().await;
+    }
+
+    fn on_data(&self, id: StreamId, data: Bytes) {
+        let mut streams = self.streams.lock().unwrap();
+        let Some(handle) = streams.get(&id) else {
+            return;
+        };
+        let size = data.len() as u32;
+        // 对端超出窗口发送数据，说明流控出错，直接关闭这个流
+        let within_window = handle
+            .recv_window
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |w| {
+                w.checked_sub(size)
+            })
+            .is_ok();
+        // 超出窗口的数据不能交给本地
+        let delivered = within_window
+            && handle
+                .data_tx
+                .as_ref()
+                .is_some_and(|data_tx| data_tx.send(data).is_ok());
+        if !within_window || !delivered {
+            println!("❌逻辑流 {id} 超出流控窗口或已关闭，关闭该流");
+            if let Some(handle) = streams.remove(&id) {
+                handle.send_credit.close();
+            }
+            let frames = self.frames.clone();
+            tokio::spawn(async move {
+                let _ = frames
+                    .send(RTCPMessage::new(RTCPType::StreamClose(id)))
+                    .await;
+            });
+        }
+    }
+
+    fn on_window_update(&self, id: StreamId, delta: u32) {
+        if let Some(handle) = self.streams.lock().unwrap().get(&id) {
+            handle.send_credit.add_permits(delta as usize);
+        }
+    }
+
+    /// 对端不再发送数据：已收到的数据写完后关闭本地读取端，本地仍然可以继续发送
+    fn on_shutdown(&self, id: StreamId) {
+        let mut streams = self.streams.lock().unwrap();
+        let Some(handle) = streams.get_mut(&id) else {
+            return;
+        };
+        handle.data_tx = None;
+        if handle.local_done {
+            streams.remove(&id);
+        }
+    }
+
+    /// 本地写完了数据，通知对端半关闭
+    async fn shutdown(&self, id: StreamId) {
+        let exists = {
+            let mut streams = self.streams.lock().unwrap();
+            match streams.get_mut(&id) {
+                Some(handle) if handle.data_tx.is_none() => {
+                    streams.remove(&id);
+                    true
+                }
+                Some(handle) => {
+                    handle.local_done = true;
+                    true
+                }
+                None => false,
+            }
+        };
+        if exists {
+            let _ = self.send(RTCPType::StreamShutdown(id)).await;
+        }
+    }
+
+    /// 对端关闭了流：停止发送，已收到的数据写完后关闭本地读取端
+    fn on_close(&self, id: StreamId) {
+        if let Some(handle) = self.streams.lock().unwrap().remove(&id) {
+            handle.send_credit.close();
+        }
+    }
+
+    /// 本地关闭了流，通知对端
+    async fn close(&self, id: StreamId) {
+        let removed = self.streams.lock().unwrap().remove(&id);
+        if let Some(handle) = removed {
+            handle.send_credit.close();
+            let _ = self.send(RTCPType::StreamClose(id)).await;
+        }
+    }
+
+    async fn send(&self, message_type: RTCPType) -> io::Result<()> {
+        self.send_msg(RTCPMessage::new(message_type)).await
+    }
+
+    async fn send_msg(&self, msg: RTCPMessage) -> io::Result<()> {
+        self.frames
+            .send(msg)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "control connection closed"))
+    }
+}
+
+#[cfg(test)]
+mod mux_test {
+    use std::time::Duration;
+
+    use tokio::{sync::mpsc::Receiver, time::timeout};
+
+    use super::*;
+
+    /// 把一端发出的帧转交给另一端处理，模拟控制连接
+    fn connect(mut rx: Receiver<RTCPMessage>, peer: Arc<Multiplexer>, accept: bool) {
+        tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                match msg.message_type {
+                    RTCPType::StreamOpen(id, _) if accept => {
+                        let stream = peer.accept(id).unwrap();
+                        // 对端作为 echo 服务
+                        tokio::spawn(async move {
+                            let (mut r, mut w) = tokio::io::split(stream);
+                            let _ = tokio::io::copy(&mut r, &mut w).await;
+                            let _ = w.shutdown().await;
+                        });
+                    }
+                    other => {
+                        peer.handle_frame(&other);
+                    }
+                }
+            }
+        });
+    }
+
+    fn pair() -> (Arc<Multiplexer>, Arc<Multiplexer>) {
+        let (server_tx, server_rx) = mpsc::channel(1000);
+        let (client_tx, client_rx) = mpsc::channel(1000);
+        let server = Multiplexer::new(server_tx, true);
+        let client = Multiplexer::new(client_tx, false);
+        connect(server_rx, client.clone(), true);
+        connect(client_rx, server.clone(), false);
+        (server, client)
+    }
+
+    #[tokio::test]
+    async fn test_echo_many_streams() {
+        let (server, client) = pair();
+        let mut tasks = vec![];
+        for i in 0..20u8 {
+            let server = server.clone();
+            tasks.push(tokio::spawn(async move {
+                let stream = server.open(0).await.unwrap();
+                let (mut r, mut w) = tokio::io::split(stream);
+                // 超过窗口大小的数据，需要依赖窗口归还才能传完
+                let data = vec![i; STREAM_WINDOW as usize * 3];
+                let writer = tokio::spawn(async move {
+                    w.write_all(&data).await.unwrap();
+                    w
+                });
+                let mut received = vec![0u8; STREAM_WINDOW as usize * 3];
+                r.read_exact(&mut received).await.unwrap();
+                assert!(received.iter().all(|b| *b == i));
+                drop(writer.await.unwrap());
+            }));
+        }
+        for task in tasks {
+            timeout(Duration::from_secs(10), task)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(server.stream_count(), 0);
+        assert_eq!(client.stream_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_close_propagates() {
+        let (server, _client) = pair();
+        let stream = server.open(0).await.unwrap();
+        let (mut r, mut w) = tokio::io::split(stream);
+        w.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        r.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        w.shutdown().await.unwrap();
+        let mut rest = vec![];
+        let size = timeout(Duration::from_secs(5), r.read_to_end(&mut rest))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(size, 0);
+    }
+
+    #[tokio::test]
+    async fn test_half_close() {
+        let (server, client) = pair();
+        let stream = server.open(0).await.unwrap();
+        let (mut r, mut w) = tokio::io::split(stream);
+        // 写完后半关闭，对端仍然会把数据全部发回来
+        let data = vec![7u8; STREAM_WINDOW as usize * 2];
+        w.write_all(&data).await.unwrap();
+        w.shutdown().await.unwrap();
+        let mut received = vec![];
+        timeout(Duration::from_secs(5), r.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, data);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(server.stream_count(), 0);
+        assert_eq!(client.stream_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_window_violation_closes_stream() {
+        let (tx, mut rx) = mpsc::channel(1000);
+        let mux = Multiplexer::new(tx, false);
+        let mut stream = mux.accept(1).unwrap();
+        let data = Bytes::from(vec![0u8; STREAM_WINDOW as usize + 1]);
+        mux.handle_frame(&RTCPType::StreamData(1, data));
+        assert_eq!(mux.stream_count(), 0);
+        let msg = timeout(Duration::from_secs(1), rx.recv()).await.unwrap();
+        assert!(matches!(
+            msg.unwrap().message_type,
+            RTCPType::StreamClose(1)
+        ));
+        // 超出窗口的数据不会交给本地
+        let mut received = vec![];
+        timeout(Duration::from_secs(5), stream.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(received.is_empty());
+    }
+}
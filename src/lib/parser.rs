@@ -1,11 +1,18 @@
-use std::collections::HashMap;
-
+use bytes::{BufMut, BytesMut};
 use nom::{
+    branch::alt,
     bytes::streaming::{tag, take_until},
+    combinator::map,
+    error::{Error, ErrorKind},
     sequence::{terminated, tuple},
     IResult, Parser,
 };
 
+/// 请求行的最大长度（不含 CRLF）
+pub const MAX_REQUEST_LINE_LEN: usize = 8 * 1024;
+/// 一个首部中头部字段的最大数量
+pub const MAX_HEADER_COUNT: usize = 100;
+
 /**
  * 解析请求首部的请求行
  */
@@ -23,23 +30,175 @@ impl RequestLine {
     }
 }
 
-pub type Headers = HashMap<String, String>;
+/**
+ * 解析响应首部的状态行
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusLine {
+    pub protocol: String,
+    pub status: u16,
+    pub reason: String,
+}
+
+impl StatusLine {
+    /// 1xx 响应之后还会有最终响应
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.status)
+    }
+}
+
+/// 一个头部字段
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HeaderField {
+    name: String,
+    value: String,
+    /// 解析时的原始数据（含 CRLF），字段没有被修改时原样输出
+    raw: Option<Vec<u8>>,
+}
+
+/// http 头部字段，保留原始的大小写和顺序，同名字段可以出现多次，查找时忽略大小写
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    fields: Vec<HeaderField>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// 第一个同名字段的值
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|field| field.name.eq_ignore_ascii_case(name))
+            .map(|field| field.value.as_str())
+    }
+
+    /// 所有同名字段的值，按出现的顺序
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |field| field.name.eq_ignore_ascii_case(name))
+            .map(|field| field.value.as_str())
+    }
+
+    /// 所有同名字段的值用逗号连接，对于列表类型的字段等价于一个字段
+    pub fn get_joined(&self, name: &str) -> Option<String> {
+        let values: Vec<_> = self.get_all(name).collect();
+        (!values.is_empty()).then(|| values.join(", "))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// 在末尾追加一个字段，不影响已有的同名字段
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.fields.push(HeaderField {
+            name: name.into(),
+            value: value.into(),
+            raw: None,
+        });
+    }
+
+    /// 设置字段的值：在第一个同名字段的位置替换并删除其余的，不存在时追加在末尾
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        let value = value.into();
+        let mut replaced = false;
+        self.fields.retain_mut(|field| {
+            if !field.name.eq_ignore_ascii_case(&name) {
+                return true;
+            }
+            if replaced {
+                return false;
+            }
+            replaced = true;
+            *field = HeaderField {
+                name: name.clone(),
+                value: value.clone(),
+                raw: None,
+            };
+            true
+        });
+        if !replaced {
+            self.append(name, value);
+        }
+    }
+
+    /// 删除所有同名字段，返回是否删除了字段
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.fields.len();
+        self.fields
+            .retain(|field| !field.name.eq_ignore_ascii_case(name));
+        self.fields.len() != len
+    }
+
+    /// 按顺序遍历所有字段
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|field| (field.name.as_str(), field.value.as_str()))
+    }
+
+    /// 输出所有字段，没有修改过的字段与解析时的数据完全一致
+    pub fn write_to(&self, buf: &mut BytesMut) {
+        for field in &self.fields {
+            match &field.raw {
+                Some(raw) => buf.put_slice(raw),
+                None => buf.put_slice(format!("{}: {}\r\n", field.name, field.value).as_bytes()),
+            }
+        }
+    }
+
+    /// 追加解析得到的字段
+    fn push_raw(&mut self, (name, value): RequestHeader, raw: &[u8]) {
+        self.fields.push(HeaderField {
+            name,
+            value,
+            raw: Some(raw.to_vec()),
+        });
+    }
+}
 
 pub type RequestHeader = (String, String);
 
-/// 解析请求首部的请求行
+/// 解析请求首部的请求行，方法、请求目标和协议版本都按 RFC 9112 严格校验
 pub fn parser_request_line(input: &[u8]) -> IResult<&[u8], RequestLine> {
-    let (input, (method, _sp, path, _sp2, protocol)) = tuple((
+    let line_len = match input.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end <= MAX_REQUEST_LINE_LEN => end + 2,
+        None if input.len() <= MAX_REQUEST_LINE_LEN + 1 => {
+            return Err(nom::Err::Incomplete(nom::Needed::Unknown))
+        }
+        _ => return Err(failure(input, ErrorKind::TooLarge)),
+    };
+    // 请求行已经完整，缺少空格不再是数据不完整
+    let line: IResult<&[u8], _> = tuple((
         take_until(" "),
         tag(" "),
         take_until(" "),
         tag(" "),
         terminated(take_until("\r\n"), tag("\r\n")),
     ))
-    .parse(input)?;
+    .parse(&input[..line_len]);
+    let (_, (method, _sp, path, _sp2, protocol)) =
+        line.map_err(|_| failure(input, ErrorKind::Verify))?;
+
+    if !is_token(method) || !is_request_target(path) || !is_http_version(protocol) {
+        return Err(failure(input, ErrorKind::Verify));
+    }
 
     Ok((
-        input,
+        &input[line_len..],
         RequestLine {
             method: String::from_utf8(method.to_vec()).unwrap_or_default(),
             path: String::from_utf8(path.to_vec()).unwrap_or_default(),
@@ -48,17 +207,29 @@ pub fn parser_request_line(input: &[u8]) -> IResult<&[u8], RequestLine> {
     ))
 }
 
-/// 解析请求首部的请求头
+/// 解析请求首部的请求头，字段名必须是 token，冒号前不能有空白，值里不能有控制字符
 pub fn parser_request_header(input: &[u8]) -> IResult<&[u8], RequestHeader> {
-    let (input, (key, _colon, value)) = tuple((
+    let (rest, (key, _colon, value)) = tuple((
         take_until(":"),
-        tag(": "),
+        tag(":"),
         terminated(take_until("\r\n"), tag("\r\n")),
     ))
     .parse(input)?;
 
+    // 值前后可以有空格或制表符，其他空白字符不能去掉
+    let is_ows = |b: &u8| *b == b' ' || *b == b'\t';
+    let start = value.iter().position(|b| !is_ows(b)).unwrap_or(value.len());
+    let end = value
+        .iter()
+        .rposition(|b| !is_ows(b))
+        .map_or(start, |end| end + 1);
+    let value = &value[start..end];
+    // 以空白开头的行是已废弃的折行（obs-fold），字段名校验会拒绝它
+    if !is_token(key) || !is_field_value(value) {
+        return Err(failure(input, ErrorKind::Verify));
+    }
     Ok((
-        input,
+        rest,
         (
             String::from_utf8(key.to_vec()).unwrap_or_default(),
             String::from_utf8(value.to_vec()).unwrap_or_default(),
@@ -66,33 +237,171 @@ pub fn parser_request_header(input: &[u8]) -> IResult<&[u8], RequestHeader> {
     ))
 }
 
-/// 解析请求首部，一次性解析完全头部
-pub fn parser_request_head_all(input: &[u8]) -> IResult<&[u8], (RequestLine, Headers)> {
-    let (input, (request_line, row_headers, _end_lines)) =
-        tuple((parser_request_line, take_until("\r\n\r\n"), tag("\r\n\r\n"))).parse(input)?;
-
-    // 解析请求头
-    let mut headers = HashMap::<String, String>::new();
-    let mut row_headers = row_headers.to_owned();
-    row_headers.extend_from_slice(b"\r\n");
-    loop {
-        // 请求头解析完毕
-        if row_headers.is_empty() {
-            break;
-        }
-        match parser_request_header(&row_headers) {
-            Ok((rest, request_header)) => {
-                headers.insert(request_header.0, request_header.1);
+/// 格式错误，不会因为更多的数据而改变
+fn failure(input: &[u8], kind: ErrorKind) -> nom::Err<Error<&[u8]>> {
+    nom::Err::Failure(Error::new(input, kind))
+}
 
-                row_headers = rest.to_owned();
-            }
-            Err(_) => {
-                break;
+/// RFC 9110 的 token，方法名和字段名只能由这些字符组成
+fn is_token(input: &[u8]) -> bool {
+    !input.is_empty() && input.iter().all(is_tchar)
+}
+
+fn is_tchar(b: &u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(b)
+}
+
+/// 请求目标不能为空，只能是可见的 ASCII 字符
+fn is_request_target(input: &[u8]) -> bool {
+    !input.is_empty() && input.iter().all(|b| b.is_ascii_graphic())
+}
+
+/// 协议版本必须是 `HTTP/x.y`，区分大小写
+fn is_http_version(input: &[u8]) -> bool {
+    matches!(input, [b'H', b'T', b'T', b'P', b'/', major, b'.', minor]
+        if major.is_ascii_digit() && minor.is_ascii_digit())
+}
+
+/// 字段值除了制表符之外不能有控制字符，允许 obs-text
+fn is_field_value(input: &[u8]) -> bool {
+    input.iter().all(is_field_char)
+}
+
+/// 字段值中允许的字符：制表符、空格、可见字符和 obs-text
+fn is_field_char(b: &u8) -> bool {
+    *b == b'\t' || (*b >= 0x20 && *b != 0x7f)
+}
+
+/// RFC 9112 的块扩展 `;name` 或 `;name=value`，值是 token 或 quoted-string，
+/// 分号和等号两边可以有空白。input 从块大小之后开始，可以为空
+pub fn is_chunk_ext(input: &[u8]) -> bool {
+    fn skip_ws(input: &[u8]) -> &[u8] {
+        let start = input.iter().position(|b| *b != b' ' && *b != b'\t');
+        &input[start.unwrap_or(input.len())..]
+    }
+    let token_len = |input: &[u8]| input.iter().take_while(|b| is_tchar(b)).count();
+    let mut rest = skip_ws(input);
+    while !rest.is_empty() {
+        let Some(ext) = rest.strip_prefix(b";") else {
+            return false;
+        };
+        rest = skip_ws(ext);
+        let name_len = token_len(rest);
+        if name_len == 0 {
+            return false;
+        }
+        rest = skip_ws(&rest[name_len..]);
+        if let Some(value) = rest.strip_prefix(b"=") {
+            rest = skip_ws(value);
+            let value_len = match rest.first() {
+                Some(b'"') => quoted_string_len(rest),
+                _ => token_len(rest),
+            };
+            if value_len == 0 {
+                return false;
             }
+            rest = skip_ws(&rest[value_len..]);
         }
     }
+    true
+}
 
-    Ok((input, (request_line, headers)))
+/// 以 input 开头的 quoted-string 的长度（含引号），格式错误时返回 0
+fn quoted_string_len(input: &[u8]) -> usize {
+    let mut i = 1;
+    while let Some(b) = input.get(i) {
+        match b {
+            b'"' => return i + 1,
+            // quoted-pair 只能转义字段值中允许的字符
+            b'\\' if input.get(i + 1).is_some_and(is_field_char) => i += 2,
+            b'\\' => return 0,
+            b if is_field_char(b) => i += 1,
+            _ => return 0,
+        }
+    }
+    0
+}
+
+/// 解析响应首部的状态行
+pub fn parser_status_line(input: &[u8]) -> IResult<&[u8], StatusLine> {
+    // 先匹配协议名，不是 http 响应时尽早失败，而不是一直等待更多数据
+    let (rest, (_http, version, _sp, status, line)) = tuple((
+        tag("HTTP/"),
+        take_until(" "),
+        tag(" "),
+        take_until_either_end,
+        terminated(take_until("\r\n"), tag("\r\n")),
+    ))
+    .parse(input)?;
+
+    let status = std::str::from_utf8(status)
+        .ok()
+        .filter(|status| status.len() == 3)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| {
+            nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Digit))
+        })?;
+    // 原因短语可以为空，此时状态码后面的空格也可以省略
+    let reason = line.strip_prefix(b" ").unwrap_or(line);
+
+    Ok((
+        rest,
+        StatusLine {
+            protocol: format!("HTTP/{}", String::from_utf8_lossy(version)),
+            status,
+            reason: String::from_utf8(reason.to_vec()).unwrap_or_default(),
+        },
+    ))
+}
+
+/// 状态码后面可能是空格或者直接换行
+fn take_until_either_end(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    let end = input
+        .iter()
+        .position(|b| *b == b' ' || *b == b'\r')
+        .ok_or(nom::Err::Incomplete(nom::Needed::Unknown))?;
+    Ok((&input[end..], &input[..end]))
+}
+
+/// 解析首部中起始行之后的所有头部字段，直到空行
+fn parser_header_block(input: &[u8]) -> IResult<&[u8], Headers> {
+    let (rest, block) = alt((
+        // 没有任何头部字段
+        map(tag("\r\n"), |_| &input[..0]),
+        // 保留最后一个字段的 CRLF
+        map(
+            terminated(take_until("\r\n\r\n"), tag("\r\n\r\n")),
+            |block: &[u8]| &input[..block.len() + 2],
+        ),
+    ))
+    .parse(input)?;
+    Ok((rest, parser_headers(block)?))
+}
+
+/// 解析请求首部，一次性解析完全头部
+pub fn parser_request_head_all(input: &[u8]) -> IResult<&[u8], (RequestLine, Headers)> {
+    tuple((parser_request_line, parser_header_block)).parse(input)
+}
+
+/// 解析响应首部，一次性解析完全头部
+pub fn parser_response_head_all(input: &[u8]) -> IResult<&[u8], (StatusLine, Headers)> {
+    tuple((parser_status_line, parser_header_block)).parse(input)
+}
+
+/// 逐行解析头部字段，任何一行格式错误都拒绝整个首部
+fn parser_headers(mut input: &[u8]) -> Result<Headers, nom::Err<Error<&[u8]>>> {
+    let mut headers = Headers::new();
+    while !input.is_empty() {
+        if headers.len() >= MAX_HEADER_COUNT {
+            return Err(failure(input, ErrorKind::TooLarge));
+        }
+        // 头部块已经完整，不完整的行也是格式错误
+        let (rest, request_header) =
+            parser_request_header(input).map_err(|_| failure(input, ErrorKind::Verify))?;
+        headers.push_raw(request_header, &input[..input.len() - rest.len()]);
+        input = rest;
+    }
+    Ok(headers)
 }
 
 #[cfg(test)]
@@ -108,7 +417,7 @@ mod test {
 
     #[test]
     fn request_line_by_str() {
-        let row = "Get /index.html Http/1.1\r\n";
+        let row = "Get /index.html HTTP/1.1\r\n";
 
         fn parser(input: &str) -> IResult<&str, RequestLine> {
             let (input, (method, _sp, path, _sp2, protocol)) = tuple((
@@ -136,7 +445,7 @@ mod test {
 
     #[test]
     fn parse_request_line_by_steam() {
-        let row = b"Get /index.html Http/1.1\r\n";
+        let row = b"Get /index.html HTTP/1.1\r\n";
         let res = parser_request_line(row);
         println!("{:?}", res);
     }
@@ -154,11 +463,11 @@ mod test {
 
     #[test]
     fn test_parse_request_head() {
-        let row = b"Get /index.html?a=1 Http/1.1\r\nHost: www.baidu.com\r\nContent-Type: text/html;charset=utf-8\r\nContent-Length: 100\r\n\r\n";
+        let row = b"Get /index.html?a=1 HTTP/1.1\r\nHost: www.baidu.com\r\nContent-Type: text/html;charset=utf-8\r\nContent-Length: 100\r\n\r\n";
         let (input, request_line) = parser_request_line(row).unwrap();
         assert_eq!(request_line.method, "Get");
         assert_eq!(request_line.path, "/index.html?a=1");
-        assert_eq!(request_line.protocol, "Http/1.1");
+        assert_eq!(request_line.protocol, "HTTP/1.1");
 
         let (input, header) = parser_request_header(input).unwrap();
         assert_eq!(header, ("Host".to_owned(), "www.baidu.com".to_owned()));
@@ -183,17 +492,164 @@ mod test {
     #[test]
     /// 测试解析请求头
     fn parse_head() {
-        let row = b"Get /index.html?a=1 Http/1.1\r\nHost: www.baidu.com\r\nContent-Type: text/html;charset=utf-8\r\nContent-Length: 100\r\n\r\n";
+        let row = b"Get /index.html?a=1 HTTP/1.1\r\nHost: www.baidu.com\r\nContent-Type: text/html;charset=utf-8\r\nContent-Length: 100\r\n\r\n";
 
         let (input, (request_line, headers)) = parser_request_head_all(row).unwrap();
 
         assert!(input.is_empty(), "剩余内容：{input:?}");
+        assert_eq!(request_line.method, "Get");
+        assert_eq!(headers.len(), 3);
+    }
+
+    #[test]
+    fn test_headers() {
+        let row = b"GET / HTTP/1.1\r\nhost: www.baidu.com\r\nAccept: text/html\r\ncontent-length:5\r\naccept: */*\r\n\r\n";
+        let (_, (_, mut headers)) = parser_request_head_all(row).unwrap();
+        assert_eq!(headers.get("Host").unwrap(), "www.baidu.com");
+        assert_eq!(headers.get("Content-Length").unwrap(), "5");
+        assert!(headers.get("Cookie").is_none());
+        // 同名字段都保留，按出现的顺序
+        assert_eq!(
+            headers.get_all("Accept").collect::<Vec<_>>(),
+            ["text/html", "*/*"]
+        );
+        assert_eq!(headers.get_joined("accept").unwrap(), "text/html, */*");
+
+        // 替换时保留第一个字段的位置，删除其余的同名字段
+        headers.insert("ACCEPT", "application/json");
+        headers.append("Set-Cookie", "a=1");
+        headers.append("Set-Cookie", "b=2");
+        assert!(headers.remove("content-length"));
+        assert!(!headers.remove("content-length"));
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            [
+                ("host", "www.baidu.com"),
+                ("ACCEPT", "application/json"),
+                ("Set-Cookie", "a=1"),
+                ("Set-Cookie", "b=2")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_headers_round_trip() {
+        // 原始的大小写、空白和顺序都保留
+        let block = b"host:  www.baidu.com \r\nX-B: 1\r\nx-a:\t2\r\nX-B: 3\r\n";
+        let mut row = b"GET / HTTP/1.1\r\n".to_vec();
+        row.extend_from_slice(block);
+        row.extend_from_slice(b"\r\n");
+        let (_, (_, mut headers)) = parser_request_head_all(&row).unwrap();
+        assert_eq!(headers.get("Host").unwrap(), "www.baidu.com");
+
+        let mut buf = BytesMut::new();
+        headers.write_to(&mut buf);
+        assert_eq!(&buf[..], block);
+
+        // 只有修改过的字段重新生成
+        headers.insert("x-a", "4");
+        let mut buf = BytesMut::new();
+        headers.write_to(&mut buf);
+        assert_eq!(
+            &buf[..],
+            b"host:  www.baidu.com \r\nX-B: 1\r\nx-a: 4\r\nX-B: 3\r\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_response_head() {
+        let row = b"HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\nServer: nginx\r\n\r\nnot found";
+        let (input, (status_line, headers)) = parser_response_head_all(row).unwrap();
+        assert_eq!(input, b"not found");
+        assert_eq!(status_line.protocol, "HTTP/1.1");
+        assert_eq!(status_line.status, 404);
+        assert_eq!(status_line.reason, "Not Found");
+        assert_eq!(headers.get("server").unwrap(), "nginx");
+
+        // 没有头部字段、原因短语为空
+        let (input, (status_line, headers)) =
+            parser_response_head_all(b"HTTP/1.1 204\r\n\r\n").unwrap();
+        assert!(input.is_empty());
+        assert_eq!(status_line.status, 204);
+        assert_eq!(status_line.reason, "");
+        assert!(headers.is_empty());
+
+        let (_, (status_line, _)) =
+            parser_response_head_all(b"HTTP/1.1 101 Switching Protocols\r\n\r\n").unwrap();
+        assert!(status_line.is_informational());
+
+        assert!(parser_response_head_all(b"HTTP/1.1 200 OK\r\nServer: x").is_err());
+        assert!(parser_response_head_all(b"HTTP/1.1 2000 OK\r\n\r\n").is_err());
+        assert!(parser_response_head_all(b"HTTP/1.1 abc OK\r\n\r\n").is_err());
+        assert!(!parser_response_head_all(b"SSH-2.0\r\n")
+            .unwrap_err()
+            .is_incomplete());
+        assert!(parser_response_head_all(b"HT").unwrap_err().is_incomplete());
+    }
+
+    #[test]
+    fn test_parse_request_without_headers() {
+        let (input, (request_line, headers)) =
+            parser_request_head_all(b"GET / HTTP/1.0\r\n\r\nrest").unwrap();
+        assert_eq!(input, b"rest");
+        assert_eq!(request_line.path, "/");
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn test_parse_invalid_request() {
+        let is_failure = |input: &[u8]| {
+            matches!(
+                parser_request_head_all(input),
+                Err(nom::Err::Error(_) | nom::Err::Failure(_))
+            )
+        };
+        // 请求行
+        assert!(is_failure(b"GET /\r\n\r\n"));
+        assert!(is_failure(b"GET  / HTTP/1.1\r\n\r\n"));
+        assert!(is_failure(b"GET / HTTP/1.1 \r\n\r\n"));
+        assert!(is_failure(b"GET /a b HTTP/1.1\r\n\r\n"));
+        assert!(is_failure(b"G(T / HTTP/1.1\r\n\r\n"));
+        assert!(is_failure(b"GET / http/1.1\r\n\r\n"));
+        assert!(is_failure(b"GET / HTTP/1.10\r\n\r\n"));
+        assert!(is_failure(b"GET /\x00 HTTP/1.1\r\n\r\n"));
+        // 头部字段
+        assert!(is_failure(b"GET / HTTP/1.1\r\nHost : a\r\n\r\n"));
+        assert!(is_failure(b"GET / HTTP/1.1\r\n: a\r\n\r\n"));
+        assert!(is_failure(b"GET / HTTP/1.1\r\nX-A: 1\r\n 2\r\n\r\n"));
+        assert!(is_failure(b"GET / HTTP/1.1\r\n Host: a\r\n\r\n"));
+        assert!(is_failure(b"GET / HTTP/1.1\r\nHost\r\n\r\n"));
+        assert!(is_failure(b"GET / HTTP/1.1\r\nX-A: 1\x002\r\n\r\n"));
+        assert!(is_failure(b"GET / HTTP/1.1\r\nX-A: 1\n2\r\n\r\n"));
+        // 长度限制
+        let mut row = b"GET /".to_vec();
+        row.resize(MAX_REQUEST_LINE_LEN + 2, b'a');
+        assert!(matches!(
+            parser_request_head_all(&row),
+            Err(nom::Err::Failure(e)) if e.code == ErrorKind::TooLarge
+        ));
+        let mut row = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..=MAX_HEADER_COUNT {
+            row.extend_from_slice(format!("X-{i}: {i}\r\n").as_bytes());
+        }
+        row.extend_from_slice(b"\r\n");
+        assert!(matches!(
+            parser_request_head_all(&row),
+            Err(nom::Err::Failure(e)) if e.code == ErrorKind::TooLarge
+        ));
+        // 还没读完时仍然是不完整
+        assert!(parser_request_head_all(b"GET / HTTP/1.1")
+            .unwrap_err()
+            .is_incomplete());
+        assert!(parser_request_head_all(b"GET / HTTP/1.1\r\nHost: a\r\n")
+            .unwrap_err()
+            .is_incomplete());
     }
 
     #[test]
     /// 测试解析请求头，不完整
     fn parse_head_no_complete() {
-        let row = b"Get /index.html?a=1 Http/1.1\r\nHost: www.baidu.com\r\nContent-Type: text/html;charset=utf-8\r\nContent-Length: ";
+        let row = b"Get /index.html?a=1 HTTP/1.1\r\nHost: www.baidu.com\r\nContent-Type: text/html;charset=utf-8\r\nContent-Length: ";
 
         let res = parser_request_head_all(row);
 
@@ -203,3 +659,70 @@ mod test {
         // println!("{:?}", headers);
     }
 }
+
+#[cfg(test)]
+mod parser_proptest {
+    use proptest::{collection::vec, prelude::*};
+
+    use super::*;
+
+    /// 生成合法的请求首部，返回首部和其中的头部块
+    fn request_head() -> impl Strategy<Value = (Vec<u8>, Vec<u8>)> {
+        (
+            "[A-Z]{1,8}",
+            "/[!-~]{0,32}",
+            vec(("[A-Za-z0-9!#$%&'*+.^_`|~-]{1,16}", "[ -~]{0,32}"), 0..16),
+        )
+            .prop_map(|(method, path, fields)| {
+                let block: String = fields
+                    .iter()
+                    .map(|(name, value)| format!("{name}:{value}\r\n"))
+                    .collect();
+                let head = format!("{method} {path} HTTP/1.1\r\n{block}\r\n");
+                (head.into_bytes(), block.into_bytes())
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn parse_arbitrary_input(input in vec(any::<u8>(), 0..1024)) {
+            // 任何输入都不会 panic，成功时剩余数据是输入的后缀
+            if let Ok((rest, _)) = parser_request_head_all(&input) {
+                prop_assert!(input.ends_with(rest));
+            }
+            if let Ok((rest, _)) = parser_response_head_all(&input) {
+                prop_assert!(input.ends_with(rest));
+            }
+        }
+
+        #[test]
+        fn parse_valid_request((head, block) in request_head(), body in vec(any::<u8>(), 0..64)) {
+            let mut input = head.clone();
+            input.extend_from_slice(&body);
+            let (rest, (_, headers)) = parser_request_head_all(&input).unwrap();
+            prop_assert_eq!(rest, &body[..]);
+
+            // 头部字段原样输出
+            let mut buf = BytesMut::new();
+            headers.write_to(&mut buf);
+            prop_assert_eq!(&buf[..], &block[..]);
+
+            // 首部的任何前缀都是不完整，而不是格式错误
+            for len in 0..head.len() {
+                prop_assert!(parser_request_head_all(&head[..len]).unwrap_err().is_incomplete());
+            }
+        }
+
+        #[test]
+        fn reject_control_characters((head, block) in request_head(), ctl in 0u8..0x20, pos in any::<prop::sample::Index>()) {
+            prop_assume!(!block.is_empty() && ctl != b'\t');
+            // 在头部块的某个位置插入控制字符，不能被当作合法的首部
+            let start = head.len() - block.len() - 2;
+            let pos = start + pos.index(block.len() - 1);
+            let mut input = head.clone();
+            input.insert(pos, ctl);
+            let res = parser_request_head_all(&input);
+            prop_assert!(res.is_err(), "{:?}", String::from_utf8_lossy(&input));
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::HashSet,
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+/// 端口范围列表，例如 `7000-7999,8080`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortRanges(Vec<RangeInclusive<u16>>);
+
+impl PortRanges {
+    /// 端口是否在任一范围内
+    pub fn contains(&self, port: u16) -> bool {
+        self.0.iter().any(|range| range.contains(&port))
+    }
+
+    /// 全部范围
+    pub fn ranges(&self) -> &[RangeInclusive<u16>] {
+        &self.0
+    }
+
+    /// 端口总数
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|range| range.len()).sum()
+    }
+
+    /// 是否没有任何端口
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 按顺序取第 index 个端口
+    fn nth(&self, mut index: usize) -> Option<u16> {
+        for range in &self.0 {
+            if index < range.len() {
+                return Some(range.start() + index as u16);
+            }
+            index -= range.len();
+        }
+        None
+    }
+}
+
+impl FromStr for PortRanges {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_port = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("端口 `{port}` 无效"))
+        };
+        let mut ranges = vec![];
+        for part in s.split(',') {
+            let range = match part.split_once('-') {
+                Some((start, end)) => parse_port(start)?..=parse_port(end)?,
+                None => {
+                    let port = parse_port(part)?;
+                    port..=port
+                }
+            };
+            if range.is_empty() {
+                return Err(format!("端口范围 `{part}` 起始端口大于结束端口"));
+            }
+            ranges.push(range);
+        }
+        Ok(Self(ranges))
+    }
+}
+
+/// 从配置的端口范围中为 client 分配访问端口
+#[derive(Debug)]
+pub struct PortAllocator {
+    ranges: PortRanges,
+    state: Mutex<AllocatorState>,
+}
+
+#[derive(Debug, Default)]
+struct AllocatorState {
+    in_use: HashSet<u16>,
+    /// 下一次开始查找的位置
+    cursor: usize,
+}
+
+impl PortAllocator {
+    pub fn new(ranges: PortRanges) -> Arc<Self> {
+        Arc::new(Self {
+            ranges,
+            state: Mutex::new(AllocatorState::default()),
+        })
+    }
+
+    /// 可分配的端口总数
+    pub fn capacity(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// 正在使用的端口数量
+    pub fn in_use(&self) -> usize {
+        self.state.lock().unwrap().in_use.len()
+    }
+
+    /// 预留一个空闲端口，从上次分配的位置继续查找，避免刚释放的端口立即被复用。
+    /// 返回的租约被丢弃时端口自动释放。
+    pub fn reserve(self: &Arc<Self>) -> Option<PortLease> {
+        let capacity = self.capacity();
+        let mut state = self.state.lock().unwrap();
+        for _ in 0..capacity {
+            let index = state.cursor;
+            state.cursor = (state.cursor + 1) % capacity;
+            let port = self.ranges.nth(index)?;
+            if state.in_use.insert(port) {
+                return Some(PortLease {
+                    port,
+                    allocator: self.clone(),
+                });
+            }
+        }
+        None
+    }
+
+    fn release(&self, port: u16) {
+        self.state.lock().unwrap().in_use.remove(&port);
+    }
+}
+
+/// 已分配的端口，丢弃时归还给分配器
+#[derive(Debug)]
+pub struct PortLease {
+    port: u16,
+    allocator: Arc<PortAllocator>,
+}
+
+impl PortLease {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for PortLease {
+    fn drop(&mut self) {
+        self.allocator.release(self.port);
+    }
+}
+
+#[cfg(test)]
+mod ports_test {
+    use super::*;
+
+    #[test]
+    fn test_parse_port_ranges() {
+        let ranges: PortRanges = "7000-7999, 8080".parse().unwrap();
+        assert_eq!(ranges.ranges(), &[7000..=7999, 8080..=8080]);
+        assert!(ranges.contains(7000));
+        assert!(ranges.contains(7999));
+        assert!(ranges.contains(8080));
+        assert!(!ranges.contains(8000));
+
+        assert!("".parse::<PortRanges>().is_err());
+        assert!("8000-7000".parse::<PortRanges>().is_err());
+        assert!("7000-".parse::<PortRanges>().is_err());
+        assert!("http".parse::<PortRanges>().is_err());
+    }
+
+    #[test]
+    fn test_port_allocator() {
+        let allocator = PortAllocator::new("7000-7001,7005".parse().unwrap());
+        assert_eq!(allocator.capacity(), 3);
+
+        let a = allocator.reserve().unwrap();
+        let b = allocator.reserve().unwrap();
+        let c = allocator.reserve().unwrap();
+        assert_eq!([a.port(), b.port(), c.port()], [7000, 7001, 7005]);
+        assert!(allocator.reserve().is_none());
+
+        drop(b);
+        assert_eq!(allocator.in_use(), 2);
+        assert_eq!(allocator.reserve().unwrap().port(), 7001);
+
+        // 租约丢弃后端口全部归还
+        drop(a);
+        drop(c);
+        assert_eq!(allocator.in_use(), 0);
+    }
+}
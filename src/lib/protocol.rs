@@ -1,12 +1,18 @@
-use std::{fmt::Display, io};
+use std::{
+    fmt::Display,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
 
-use bytes::{Buf, Bytes, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use nom::{
     bytes::streaming::{tag, take_until},
     error::Error,
-    sequence::{preceded, terminated, tuple},
+    sequence::{terminated, tuple},
     Parser,
 };
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use uuid::Uuid;
 
 /// 传输唯一id
@@ -15,32 +21,274 @@ pub type ConnectId = Option<String>;
 /// 传输数据长度
 pub type TransformationDataLen = usize;
 
+/// 隧道 id，`Initialize` 注册的隧道固定为 0
+pub type TunnelId = u32;
+
+/// 二进制帧魔数 `RT`
+pub const FRAME_MAGIC: [u8; 2] = [0x52, 0x54];
+
+/// 二进制帧协议版本
+pub const FRAME_VERSION: u8 = 1;
+
+/// 二进制帧头部长度：magic(2) + version(1) + type(1) + flags(1) + length(4)
+pub const FRAME_HEADER_LEN: usize = 9;
+
+/// 单帧负载最大长度，超过视为损坏帧
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// 文本协议单行最大长度，超过视为损坏消息
+pub const MAX_TEXT_LINE_LEN: usize = 1024;
+
+/// connect_id 最大长度，二进制帧中用一个字节记录长度
+pub const MAX_CONNECT_ID_LEN: usize = u8::MAX as usize;
+
+/// 帧标记：负载以 connect_id 开头
+pub const FLAG_CONNECT_ID: u8 = 0b0000_0001;
+
+/// 帧标记：负载在 connect_id 之后携带用户地址
+pub const FLAG_PEER_ADDR: u8 = 0b0000_0010;
+
+/// 帧标记：负载在用户地址之后携带用户连接的服务器地址
+pub const FLAG_LOCAL_ADDR: u8 = 0b0000_0100;
+
+/// 消息编解码格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// 定长头部的二进制帧
+    Binary,
+    /// 旧版空格、CRLF 分隔的文本行，仅用于兼容旧客户端，只支持初始化、新连接、关闭和心跳消息
+    Text,
+}
+
+impl Codec {
+    /// 根据连接上收到的首个字节判断对端使用的格式
+    pub fn detect(input: &[u8]) -> Option<Codec> {
+        match input.first() {
+            None => None,
+            Some(b) if *b == FRAME_MAGIC[0] => Some(Codec::Binary),
+            Some(_) => Some(Codec::Text),
+        }
+    }
+}
+
+/// 消息解析错误
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// 数据不完整，需要继续读取
+    Incomplete,
+    /// 数据损坏，无法继续解析
+    Corrupt(String),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Incomplete => write!(f, "incomplete rtcp message"),
+            DecodeError::Corrupt(reason) => write!(f, "corrupt rtcp message: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<DecodeError> for io::Error {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::Incomplete => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+            DecodeError::Corrupt(_) => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// 服务器拒绝初始化或增加隧道的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// 端口已被占用
+    PortInUse,
+    /// 端口不在服务器允许的范围内
+    PortNotAllowed,
+    /// 未通过认证
+    AuthFailed,
+    /// 主机名已被占用
+    HostInUse,
+    /// 主机名不允许注册
+    HostNotAllowed,
+    /// 其他错误
+    Other,
+}
+
+impl RejectReason {
+    fn code(self) -> u8 {
+        match self {
+            RejectReason::Other => 0,
+            RejectReason::PortInUse => 1,
+            RejectReason::PortNotAllowed => 2,
+            RejectReason::AuthFailed => 3,
+            RejectReason::HostInUse => 4,
+            RejectReason::HostNotAllowed => 5,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self, DecodeError> {
+        match code {
+            0 => Ok(RejectReason::Other),
+            1 => Ok(RejectReason::PortInUse),
+            2 => Ok(RejectReason::PortNotAllowed),
+            3 => Ok(RejectReason::AuthFailed),
+            4 => Ok(RejectReason::HostInUse),
+            5 => Ok(RejectReason::HostNotAllowed),
+            _ => Err(corrupt(format!("unknown reject reason {code}"))),
+        }
+    }
+
+    /// 稍后重试是否可能成功，例如上一个会话释放端口之后
+    pub fn is_transient(self) -> bool {
+        matches!(
+            self,
+            RejectReason::PortInUse | RejectReason::HostInUse | RejectReason::Other
+        )
+    }
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::PortInUse => write!(f, "port_in_use"),
+            RejectReason::PortNotAllowed => write!(f, "port_not_allowed"),
+            RejectReason::AuthFailed => write!(f, "auth_failed"),
+            RejectReason::HostInUse => write!(f, "host_in_use"),
+            RejectReason::HostNotAllowed => write!(f, "host_not_allowed"),
+            RejectReason::Other => write!(f, "other"),
+        }
+    }
+}
+
+fn corrupt(reason: impl Into<String>) -> DecodeError {
+    DecodeError::Corrupt(reason.into())
+}
+
+/// 隧道转发用户数据的方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TunnelMode {
+    /// 解析 http 请求，改写请求头
+    #[default]
+    Http,
+    /// 原样转发字节，用于 http 以外的协议
+    Tcp,
+}
+
+impl TunnelMode {
+    fn code(self) -> u8 {
+        match self {
+            TunnelMode::Http => 0,
+            TunnelMode::Tcp => 1,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self, DecodeError> {
+        match code {
+            0 => Ok(TunnelMode::Http),
+            1 => Ok(TunnelMode::Tcp),
+            _ => Err(corrupt(format!("unknown tunnel mode {code}"))),
+        }
+    }
+}
+
+impl FromStr for TunnelMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(TunnelMode::Http),
+            "tcp" => Ok(TunnelMode::Tcp),
+            _ => Err(format!("隧道模式 `{s}` 无效，应为 http 或 tcp")),
+        }
+    }
+}
+
+impl Display for TunnelMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TunnelMode::Http => write!(f, "http"),
+            TunnelMode::Tcp => write!(f, "tcp"),
+        }
+    }
+}
+
 /// Represents the different types of RTCP messages.
 #[derive(Debug)]
 pub enum RTCPType {
-    /// 初始化
+    /// 初始化，注册 0 号隧道，端口为 0 时由服务器分配
     Initialize(u16),
-    /// 创建新链接，携带唯一id
-    NewConnection,
+    /// 创建新链接，携带唯一id和隧道 id
+    NewConnection(TunnelId),
     /// 互传数据，携带唯一id
     // Transformation(TransformationDataLen),
     /// 关闭
     CloseConnection,
     /// 心跳
     Heartbeat,
+    /// 认证请求，携带 client_id
+    Auth(String),
+    /// 认证挑战，携带随机数
+    AuthChallenge(Bytes),
+    /// 认证应答，携带 HMAC
+    AuthResponse(Bytes),
+    /// 认证成功
+    AuthOk,
+    /// 错误原因，发送后服务器会关闭连接
+    Error(String),
+    /// 请求使用多路复用模式，所有用户连接都通过控制连接上的逻辑流传输
+    Multiplex,
+    /// 打开逻辑流，携带流 id 和隧道 id
+    StreamOpen(u32, TunnelId),
+    /// 逻辑流数据
+    StreamData(u32, Bytes),
+    /// 归还逻辑流的发送窗口
+    StreamWindowUpdate(u32, u32),
+    /// 关闭逻辑流
+    StreamClose(u32),
+    /// 代理连接的第一帧，携带服务器下发的 connect_id，用于归属到对应的会话
+    Attach(String),
+    /// 在当前会话中增加隧道，携带隧道 id 和访问端口，端口为 0 时由服务器分配
+    TunnelAdd(TunnelId, u16),
+    /// 移除隧道
+    TunnelRemove(TunnelId),
+    /// 隧道增加或移除成功，携带访问端口
+    TunnelOk(TunnelId, u16),
+    /// 隧道增加或移除失败，携带原因
+    TunnelError(TunnelId, RejectReason, String),
+    /// 初始化成功，携带访问端口
+    InitializeOk(u16),
+    /// 初始化失败，携带原因
+    InitializeError(RejectReason, String),
+    /// 在当前会话中增加按 Host 头路由的隧道，携带隧道 id 和主机名
+    TunnelAddHost(TunnelId, String),
+    /// 设置隧道转发时的 Host 头，None 表示保留原始 Host，需要在增加隧道之前发送
+    TunnelHostRewrite(TunnelId, Option<String>),
+    /// 请求服务器在 NewConnection 和 StreamOpen 中携带用户地址和用户连接的服务器地址
+    PeerAddr,
+    /// 设置隧道的转发方式，需要在增加隧道之前发送
+    TunnelMode(TunnelId, TunnelMode),
+    /// 逻辑流半关闭，发送方不再发送数据
+    StreamShutdown(u32),
 }
 
 impl RTCPType {
     /// Create a new RTCPType from the given string.
     pub fn new_from_str(s: &str) -> io::Result<RTCPType> {
-        if s.starts_with("initialize") {
-            let size_str = &s["initialize:".len()..];
+        if let Some(size_str) = s.strip_prefix("initialize:") {
             if let Ok(size) = size_str.parse::<u16>() {
                 return Ok(RTCPType::Initialize(size));
             }
         }
+        if let Some(tunnel) = s.strip_prefix("new_connection:") {
+            if let Ok(tunnel) = tunnel.parse::<TunnelId>() {
+                return Ok(RTCPType::NewConnection(tunnel));
+            }
+        }
         match s {
-            "new_connection" => Ok(RTCPType::NewConnection),
+            "new_connection" => Ok(RTCPType::NewConnection(0)),
             "close_connection" => Ok(RTCPType::CloseConnection),
             "heartbeat" => Ok(RTCPType::Heartbeat),
             _ => Err(io::Error::new(
@@ -49,15 +297,257 @@ impl RTCPType {
             )),
         }
     }
+
+    /// 二进制帧中的类型编号
+    pub fn code(&self) -> u8 {
+        match self {
+            RTCPType::Initialize(_) => 1,
+            RTCPType::NewConnection(_) => 2,
+            RTCPType::CloseConnection => 3,
+            RTCPType::Heartbeat => 4,
+            RTCPType::Auth(_) => 5,
+            RTCPType::AuthChallenge(_) => 6,
+            RTCPType::AuthResponse(_) => 7,
+            RTCPType::AuthOk => 8,
+            RTCPType::Error(_) => 9,
+            RTCPType::Multiplex => 10,
+            RTCPType::StreamOpen(_, _) => 11,
+            RTCPType::StreamData(_, _) => 12,
+            RTCPType::StreamWindowUpdate(_, _) => 13,
+            RTCPType::StreamClose(_) => 14,
+            RTCPType::Attach(_) => 15,
+            RTCPType::TunnelAdd(_, _) => 16,
+            RTCPType::TunnelRemove(_) => 17,
+            RTCPType::TunnelOk(_, _) => 18,
+            RTCPType::TunnelError(_, _, _) => 19,
+            RTCPType::InitializeOk(_) => 20,
+            RTCPType::InitializeError(_, _) => 21,
+            RTCPType::TunnelAddHost(_, _) => 22,
+            RTCPType::TunnelHostRewrite(_, _) => 23,
+            RTCPType::PeerAddr => 24,
+            RTCPType::TunnelMode(_, _) => 25,
+            RTCPType::StreamShutdown(_) => 26,
+        }
+    }
+
+    /// 写入类型自带的负载
+    fn write_payload(&self, buf: &mut BytesMut) {
+        match self {
+            RTCPType::Initialize(port) | RTCPType::InitializeOk(port) => buf.put_u16(*port),
+            RTCPType::Auth(client_id) => buf.put_slice(client_id.as_bytes()),
+            RTCPType::AuthChallenge(data) | RTCPType::AuthResponse(data) => buf.put_slice(data),
+            RTCPType::Error(text) | RTCPType::Attach(text) => buf.put_slice(text.as_bytes()),
+            RTCPType::NewConnection(id)
+            | RTCPType::StreamClose(id)
+            | RTCPType::StreamShutdown(id)
+            | RTCPType::TunnelRemove(id) => buf.put_u32(*id),
+            RTCPType::StreamOpen(id, tunnel) => {
+                buf.put_u32(*id);
+                buf.put_u32(*tunnel);
+            }
+            RTCPType::TunnelAdd(tunnel, port) | RTCPType::TunnelOk(tunnel, port) => {
+                buf.put_u32(*tunnel);
+                buf.put_u16(*port);
+            }
+            RTCPType::TunnelAddHost(tunnel, host) => {
+                buf.put_u32(*tunnel);
+                buf.put_slice(host.as_bytes());
+            }
+            RTCPType::TunnelHostRewrite(tunnel, host) => {
+                // 空字符串表示保留原始 Host
+                buf.put_u32(*tunnel);
+                buf.put_slice(host.as_deref().unwrap_or_default().as_bytes());
+            }
+            RTCPType::TunnelError(tunnel, reason, message) => {
+                buf.put_u32(*tunnel);
+                buf.put_u8(reason.code());
+                buf.put_slice(message.as_bytes());
+            }
+            RTCPType::TunnelMode(tunnel, mode) => {
+                buf.put_u32(*tunnel);
+                buf.put_u8(mode.code());
+            }
+            RTCPType::InitializeError(reason, message) => {
+                buf.put_u8(reason.code());
+                buf.put_slice(message.as_bytes());
+            }
+            RTCPType::StreamData(id, data) => {
+                buf.put_u32(*id);
+                buf.put_slice(data);
+            }
+            RTCPType::StreamWindowUpdate(id, delta) => {
+                buf.put_u32(*id);
+                buf.put_u32(*delta);
+            }
+            _ => {}
+        }
+    }
+
+    /// 根据类型编号和负载还原类型
+    fn from_frame(code: u8, mut payload: &[u8]) -> Result<RTCPType, DecodeError> {
+        let message_type = match code {
+            1 => RTCPType::Initialize(take_u16(&mut payload)?),
+            2 => RTCPType::NewConnection(take_u32(&mut payload)?),
+            3 => RTCPType::CloseConnection,
+            4 => RTCPType::Heartbeat,
+            5 => RTCPType::Auth(take_string(&mut payload)?),
+            6 => RTCPType::AuthChallenge(take_bytes(&mut payload)),
+            7 => RTCPType::AuthResponse(take_bytes(&mut payload)),
+            8 => RTCPType::AuthOk,
+            9 => RTCPType::Error(take_string(&mut payload)?),
+            10 => RTCPType::Multiplex,
+            11 => RTCPType::StreamOpen(take_u32(&mut payload)?, take_u32(&mut payload)?),
+            12 => RTCPType::StreamData(take_u32(&mut payload)?, take_bytes(&mut payload)),
+            13 => RTCPType::StreamWindowUpdate(take_u32(&mut payload)?, take_u32(&mut payload)?),
+            14 => RTCPType::StreamClose(take_u32(&mut payload)?),
+            15 => RTCPType::Attach(take_string(&mut payload)?),
+            16 => RTCPType::TunnelAdd(take_u32(&mut payload)?, take_u16(&mut payload)?),
+            17 => RTCPType::TunnelRemove(take_u32(&mut payload)?),
+            18 => RTCPType::TunnelOk(take_u32(&mut payload)?, take_u16(&mut payload)?),
+            19 => RTCPType::TunnelError(
+                take_u32(&mut payload)?,
+                take_reason(&mut payload)?,
+                take_string(&mut payload)?,
+            ),
+            20 => RTCPType::InitializeOk(take_u16(&mut payload)?),
+            21 => RTCPType::InitializeError(take_reason(&mut payload)?, take_string(&mut payload)?),
+            22 => RTCPType::TunnelAddHost(take_u32(&mut payload)?, take_string(&mut payload)?),
+            23 => {
+                let tunnel = take_u32(&mut payload)?;
+                let host = take_string(&mut payload)?;
+                RTCPType::TunnelHostRewrite(tunnel, Some(host).filter(|host| !host.is_empty()))
+            }
+            24 => RTCPType::PeerAddr,
+            25 => RTCPType::TunnelMode(take_u32(&mut payload)?, take_mode(&mut payload)?),
+            26 => RTCPType::StreamShutdown(take_u32(&mut payload)?),
+            _ => return Err(corrupt(format!("unknown message type {code}"))),
+        };
+        if !payload.is_empty() {
+            return Err(corrupt("trailing bytes in payload"));
+        }
+        Ok(message_type)
+    }
+}
+
+/// 取出拒绝原因
+fn take_reason(payload: &mut &[u8]) -> Result<RejectReason, DecodeError> {
+    if payload.is_empty() {
+        return Err(corrupt("payload too short"));
+    }
+    RejectReason::from_code(payload.get_u8())
+}
+
+/// 取出隧道模式
+fn take_mode(payload: &mut &[u8]) -> Result<TunnelMode, DecodeError> {
+    if payload.is_empty() {
+        return Err(corrupt("payload too short"));
+    }
+    TunnelMode::from_code(payload.get_u8())
+}
+
+/// 写入地址：`family(1) ip(4 或 16) port(2)`
+fn put_addr(buf: &mut BytesMut, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.put_u8(4);
+            buf.put_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.put_u8(6);
+            buf.put_slice(&ip.octets());
+        }
+    }
+    buf.put_u16(addr.port());
+}
+
+/// 取出地址
+fn take_addr(payload: &mut &[u8]) -> Result<SocketAddr, DecodeError> {
+    let ip_len = match payload.first() {
+        Some(4) => 4,
+        Some(6) => 16,
+        Some(family) => return Err(corrupt(format!("unknown address family {family}"))),
+        None => return Err(corrupt("missing address")),
+    };
+    if payload.len() < 1 + ip_len + 2 {
+        return Err(corrupt("address exceeds payload"));
+    }
+    payload.advance(1);
+    let ip = if ip_len == 4 {
+        IpAddr::V4(Ipv4Addr::from(payload.get_u32()))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(payload.get_u128()))
+    };
+    Ok(SocketAddr::new(ip, payload.get_u16()))
+}
+
+/// 取出 u16
+fn take_u16(payload: &mut &[u8]) -> Result<u16, DecodeError> {
+    if payload.len() < 2 {
+        return Err(corrupt("payload too short"));
+    }
+    Ok(payload.get_u16())
+}
+
+/// 取出 u32
+fn take_u32(payload: &mut &[u8]) -> Result<u32, DecodeError> {
+    if payload.len() < 4 {
+        return Err(corrupt("payload too short"));
+    }
+    Ok(payload.get_u32())
+}
+
+/// 取出剩余负载作为字节
+fn take_bytes(payload: &mut &[u8]) -> Bytes {
+    let data = Bytes::copy_from_slice(payload);
+    payload.advance(payload.len());
+    data
+}
+
+/// 取出剩余负载作为 utf-8 字符串
+fn take_string(payload: &mut &[u8]) -> Result<String, DecodeError> {
+    let data = take_bytes(payload);
+    String::from_utf8(data.to_vec()).map_err(|_| corrupt("payload is not utf-8"))
 }
 
 impl Display for RTCPType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             RTCPType::Initialize(port) => write!(f, "initialize:{port}"),
-            RTCPType::NewConnection => write!(f, "new_connection"),
+            RTCPType::NewConnection(0) => write!(f, "new_connection"),
+            RTCPType::NewConnection(tunnel) => write!(f, "new_connection:{tunnel}"),
             RTCPType::CloseConnection => write!(f, "close_connection"),
             RTCPType::Heartbeat => write!(f, "heartbeat"),
+            RTCPType::Auth(client_id) => write!(f, "auth:{client_id}"),
+            RTCPType::AuthChallenge(_) => write!(f, "auth_challenge"),
+            RTCPType::AuthResponse(_) => write!(f, "auth_response"),
+            RTCPType::AuthOk => write!(f, "auth_ok"),
+            RTCPType::Error(reason) => write!(f, "error:{reason}"),
+            RTCPType::Multiplex => write!(f, "multiplex"),
+            RTCPType::StreamOpen(id, tunnel) => write!(f, "stream_open:{id}:{tunnel}"),
+            RTCPType::StreamData(id, data) => write!(f, "stream_data:{id}:{}", data.len()),
+            RTCPType::StreamWindowUpdate(id, delta) => {
+                write!(f, "stream_window_update:{id}:{delta}")
+            }
+            RTCPType::StreamClose(id) => write!(f, "stream_close:{id}"),
+            RTCPType::Attach(connect_id) => write!(f, "attach:{connect_id}"),
+            RTCPType::TunnelAdd(tunnel, port) => write!(f, "tunnel_add:{tunnel}:{port}"),
+            RTCPType::TunnelRemove(tunnel) => write!(f, "tunnel_remove:{tunnel}"),
+            RTCPType::TunnelOk(tunnel, port) => write!(f, "tunnel_ok:{tunnel}:{port}"),
+            RTCPType::TunnelError(tunnel, reason, message) => {
+                write!(f, "tunnel_error:{tunnel}:{reason}:{message}")
+            }
+            RTCPType::InitializeOk(port) => write!(f, "initialize_ok:{port}"),
+            RTCPType::TunnelAddHost(tunnel, host) => write!(f, "tunnel_add_host:{tunnel}:{host}"),
+            RTCPType::TunnelHostRewrite(tunnel, host) => match host {
+                Some(host) => write!(f, "tunnel_host_rewrite:{tunnel}:{host}"),
+                None => write!(f, "tunnel_host_rewrite:{tunnel}"),
+            },
+            RTCPType::PeerAddr => write!(f, "peer_addr"),
+            RTCPType::TunnelMode(tunnel, mode) => write!(f, "tunnel_mode:{tunnel}:{mode}"),
+            RTCPType::StreamShutdown(id) => write!(f, "stream_shutdown:{id}"),
+            RTCPType::InitializeError(reason, message) => {
+                write!(f, "initialize_error:{reason}:{message}")
+            }
         }
     }
 }
@@ -69,6 +559,10 @@ pub struct RTCPMessage {
     pub message_type: RTCPType,
     /// 连接id
     pub connect_id: ConnectId,
+    /// 用户地址，只在二进制帧中传输
+    pub peer_addr: Option<SocketAddr>,
+    /// 用户连接的服务器地址，只在二进制帧中传输
+    pub local_addr: Option<SocketAddr>,
 }
 
 impl RTCPMessage {
@@ -76,38 +570,184 @@ impl RTCPMessage {
     pub fn new(message_type: RTCPType) -> Self {
         let connect_id = match message_type {
             RTCPType::Initialize(_) => None,
-            RTCPType::NewConnection => Some(Uuid::new_v4().to_string()),
+            RTCPType::NewConnection(_) => Some(Uuid::new_v4().to_string()),
             // other types of message,need return  None， if use other types of message, need use fromExactMessage fn
             _ => None,
         };
         Self {
             message_type,
             connect_id,
+            peer_addr: None,
+            local_addr: None,
         }
     }
 
-    /// Serialize the RTCPMessage into a byte array.
-    /// the protocol formate type:
+    /// 携带用户地址
+    pub fn with_peer_addr(mut self, peer_addr: Option<SocketAddr>) -> Self {
+        self.peer_addr = peer_addr;
+        self
+    }
+
+    /// 携带用户连接的服务器地址
+    pub fn with_local_addr(mut self, local_addr: Option<SocketAddr>) -> Self {
+        self.local_addr = local_addr;
+        self
+    }
+
+    /// 按指定格式序列化
+    pub fn encode(&self, codec: Codec) -> io::Result<Bytes> {
+        match codec {
+            Codec::Binary => self.serialize(),
+            Codec::Text => Ok(self.serialize_text()),
+        }
+    }
+
+    /// 按指定格式反序列化，返回消息和消耗的字节数
+    pub fn decode(codec: Codec, input: &[u8]) -> Result<(Self, usize), DecodeError> {
+        match codec {
+            Codec::Binary => Self::deserialize(input),
+            Codec::Text => Self::deserialize_text(input),
+        }
+    }
+
+    /// Serialize the RTCPMessage into a binary frame.
+    /// the frame layout:
+    /// ```text
+    /// magic(2) version(1) type(1) flags(1) length(4, big endian) payload(length)
     /// ```
+    /// payload 以 `len(1) connect_id` 开头（flags 带 FLAG_CONNECT_ID 时），
+    /// 然后是 `family(1) ip(4 或 16) port(2)` 用户地址（flags 带 FLAG_PEER_ADDR 时）
+    /// 和同样格式的服务器地址（flags 带 FLAG_LOCAL_ADDR 时），之后是类型自带的数据。
+    /// connect_id 超过 [`MAX_CONNECT_ID_LEN`] 或者负载超过 [`MAX_FRAME_LEN`] 时返回错误
+    pub fn serialize(&self) -> io::Result<Bytes> {
+        let mut payload = BytesMut::new();
+        let mut flags = 0;
+        if let Some(connect_id) = &self.connect_id {
+            if connect_id.len() > MAX_CONNECT_ID_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("connect_id too long: {}", connect_id.len()),
+                ));
+            }
+            flags |= FLAG_CONNECT_ID;
+            payload.put_u8(connect_id.len() as u8);
+            payload.put_slice(connect_id.as_bytes());
+        }
+        if let Some(peer_addr) = &self.peer_addr {
+            flags |= FLAG_PEER_ADDR;
+            put_addr(&mut payload, peer_addr);
+        }
+        if let Some(local_addr) = &self.local_addr {
+            flags |= FLAG_LOCAL_ADDR;
+            put_addr(&mut payload, local_addr);
+        }
+        self.message_type.write_payload(&mut payload);
+        if payload.len() > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("frame too large: {}", payload.len()),
+            ));
+        }
+
+        let mut frame = BytesMut::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.put_slice(&FRAME_MAGIC);
+        frame.put_u8(FRAME_VERSION);
+        frame.put_u8(self.message_type.code());
+        frame.put_u8(flags);
+        frame.put_u32(payload.len() as u32);
+        frame.put_slice(&payload);
+        Ok(frame.freeze())
+    }
+
+    /// Deserialize a binary frame into an RTCPMessage.
+    pub fn deserialize(input: &[u8]) -> Result<(Self, usize), DecodeError> {
+        // 头部可以在不完整的时候提前校验，尽早拒绝垃圾数据
+        let magic_len = input.len().min(FRAME_MAGIC.len());
+        if input[..magic_len] != FRAME_MAGIC[..magic_len] {
+            return Err(corrupt("bad magic"));
+        }
+        if input.len() > 2 && input[2] != FRAME_VERSION {
+            return Err(corrupt(format!("unsupported version {}", input[2])));
+        }
+        if input.len() < FRAME_HEADER_LEN {
+            return Err(DecodeError::Incomplete);
+        }
+
+        let mut header = &input[3..FRAME_HEADER_LEN];
+        let code = header.get_u8();
+        let flags = header.get_u8();
+        let length = header.get_u32() as usize;
+        if length > MAX_FRAME_LEN {
+            return Err(corrupt(format!("frame too large: {length}")));
+        }
+        if flags & !(FLAG_CONNECT_ID | FLAG_PEER_ADDR | FLAG_LOCAL_ADDR) != 0 {
+            return Err(corrupt(format!("unknown flags {flags:#010b}")));
+        }
+
+        let frame_len = FRAME_HEADER_LEN + length;
+        if input.len() < frame_len {
+            return Err(DecodeError::Incomplete);
+        }
+
+        let mut payload = &input[FRAME_HEADER_LEN..frame_len];
+        let connect_id = if flags & FLAG_CONNECT_ID != 0 {
+            if payload.is_empty() {
+                return Err(corrupt("missing connect_id length"));
+            }
+            let id_len = payload.get_u8() as usize;
+            if payload.len() < id_len {
+                return Err(corrupt("connect_id exceeds payload"));
+            }
+            let id = String::from_utf8(payload[..id_len].to_vec())
+                .map_err(|_| corrupt("connect_id is not utf-8"))?;
+            payload.advance(id_len);
+            Some(id)
+        } else {
+            None
+        };
+
+        let peer_addr = if flags & FLAG_PEER_ADDR != 0 {
+            Some(take_addr(&mut payload)?)
+        } else {
+            None
+        };
+        let local_addr = if flags & FLAG_LOCAL_ADDR != 0 {
+            Some(take_addr(&mut payload)?)
+        } else {
+            None
+        };
+
+        let message_type = RTCPType::from_frame(code, payload)?;
+
+        Ok((
+            Self {
+                message_type,
+                connect_id,
+                peer_addr,
+                local_addr,
+            },
+            frame_len,
+        ))
+    }
+
+    /// Serialize the RTCPMessage into the legacy text line.
+    /// the protocol formate type:
+    /// ```text
     /// message_type connect_id\r\n
     /// ```
-    pub fn serialize(&self) -> Bytes {
-        // Serialize the message type and data into a byte array.
-
-        return Bytes::copy_from_slice(
+    pub fn serialize_text(&self) -> Bytes {
+        Bytes::copy_from_slice(
             format!(
                 "{} {}\r\n",
                 self.message_type,
                 self.connect_id.clone().unwrap_or_default()
             )
             .as_bytes(),
-        );
+        )
     }
 
-    /// Deserialize the byte array into an RTCPMessage.
-    pub fn deserialize(input: &[u8]) -> io::Result<(Self, usize)> {
-        // Deserialize the byte array into an RTCPMessage.
-
+    /// Deserialize the legacy text line into an RTCPMessage.
+    pub fn deserialize_text(input: &[u8]) -> Result<(Self, usize), DecodeError> {
         let parse_res = tuple((
             take_until::<&str, &[u8], Error<&[u8]>>(" "),
             tag(" "),
@@ -115,22 +755,26 @@ impl RTCPMessage {
         ))
         .parse(input);
 
-        if parse_res.is_err() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "invalid rtcp message",
-            ));
-        }
-
-        let (output, (message_type, _, connect_id)) = parse_res.unwrap();
+        let (output, (message_type, _, connect_id)) = match parse_res {
+            Ok(res) => res,
+            Err(nom::Err::Incomplete(_)) if input.len() <= MAX_TEXT_LINE_LEN => {
+                return Err(DecodeError::Incomplete)
+            }
+            Err(_) => return Err(corrupt("invalid text line")),
+        };
 
         let message_type =
-            RTCPType::new_from_str(String::from_utf8(message_type.to_vec()).unwrap().as_str())?;
+            std::str::from_utf8(message_type).map_err(|_| corrupt("message type is not utf-8"))?;
+        let message_type =
+            RTCPType::new_from_str(message_type).map_err(|e| corrupt(e.to_string()))?;
 
         let connect_id = if connect_id.is_empty() {
             None
         } else {
-            Some(String::from_utf8(connect_id.to_vec()).unwrap())
+            Some(
+                String::from_utf8(connect_id.to_vec())
+                    .map_err(|_| corrupt("connect_id is not utf-8"))?,
+            )
         };
 
         let msg_size = input.len() - output.len();
@@ -138,15 +782,89 @@ impl RTCPMessage {
             Self {
                 message_type,
                 connect_id,
+                peer_addr: None,
+                local_addr: None,
             },
             msg_size,
         ))
     }
 
     pub fn get_size(&self) -> usize {
-        return self.message_type.to_string().as_bytes().len()
-            + self.connect_id.clone().unwrap_or_default().as_bytes().len();
+        self.message_type.to_string().len() + self.connect_id.clone().unwrap_or_default().len()
+    }
+}
+
+/// 消息读取器，缓存未解析完的数据，保证一次读取中的多条消息都不会丢失
+#[derive(Debug)]
+pub struct MessageReader {
+    buf: BytesMut,
+    codec: Option<Codec>,
+}
+
+impl MessageReader {
+    /// 固定格式的读取器
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            buf: BytesMut::with_capacity(4 * 1024),
+            codec: Some(codec),
+        }
+    }
+
+    /// 根据收到的首个字节自动识别格式的读取器
+    pub fn detect() -> Self {
+        Self {
+            buf: BytesMut::with_capacity(4 * 1024),
+            codec: None,
+        }
+    }
+
+    /// 当前连接使用的格式，未收到数据前为 None
+    pub fn codec(&self) -> Option<Codec> {
+        self.codec
+    }
+
+    /// 取出已经读取但还没有解析的数据，连接之后不再用于传输消息时调用
+    pub fn into_remaining(self) -> BytesMut {
+        self.buf
     }
+
+    /// 读取一条完整消息，数据损坏时返回 InvalidData 错误
+    pub async fn read_msg<T>(&mut self, reader: &mut T) -> io::Result<RTCPMessage>
+    where
+        T: AsyncRead + Unpin,
+    {
+        loop {
+            if self.codec.is_none() {
+                self.codec = Codec::detect(&self.buf);
+            }
+            if let Some(codec) = self.codec {
+                match RTCPMessage::decode(codec, &self.buf) {
+                    Ok((msg, size)) => {
+                        self.buf.advance(size);
+                        return Ok(msg);
+                    }
+                    Err(DecodeError::Incomplete) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            if reader.read_buf(&mut self.buf).await? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "tcp连接已关闭",
+                ));
+            }
+        }
+    }
+}
+
+/// 写入一条消息
+pub async fn write_msg<W>(writer: &mut W, msg: &RTCPMessage, codec: Codec) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(&msg.encode(codec)?).await?;
+    writer.flush().await
 }
 
 #[cfg(test)]
@@ -156,7 +874,7 @@ mod tests_protocol {
     #[test]
     fn test_serialize() {
         let message = RTCPMessage::new(RTCPType::Initialize(8830));
-        let serialized = message.serialize();
+        let serialized = message.serialize_text();
         let b = BytesMut::from("initialize:8830 \r\n");
         assert_eq!(
             serialized, b,
@@ -168,8 +886,8 @@ mod tests_protocol {
     #[test]
     fn test_deserialize() {
         let message = RTCPMessage::new(RTCPType::Initialize(8830));
-        let serialized = message.serialize();
-        let (deserialized, size) = RTCPMessage::deserialize(&serialized).unwrap();
+        let serialized = message.serialize_text();
+        let (deserialized, size) = RTCPMessage::deserialize_text(&serialized).unwrap();
         assert_eq!(
             deserialized.connect_id, message.connect_id,
             "反检查序列化 connect_id 失败 {:?} - {:?}",
@@ -180,6 +898,277 @@ mod tests_protocol {
             message.message_type.to_string(),
             "反检查序列化 message_type 失败",
         );
-        assert_eq!(size, b"initialize:8830 \r\n".len(), "反检查序列化 size 失败");
+        assert_eq!(
+            size,
+            b"initialize:8830 \r\n".len(),
+            "反检查序列化 size 失败"
+        );
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let message = RTCPMessage::new(RTCPType::NewConnection(0));
+        let serialized = message.serialize().unwrap();
+        assert_eq!(&serialized[..2], &FRAME_MAGIC);
+        let (deserialized, size) = RTCPMessage::deserialize(&serialized).unwrap();
+        assert_eq!(size, serialized.len());
+        assert_eq!(deserialized.connect_id, message.connect_id);
+        assert!(matches!(
+            deserialized.message_type,
+            RTCPType::NewConnection(0)
+        ));
+
+        let serialized = RTCPMessage::new(RTCPType::Initialize(8830))
+            .serialize()
+            .unwrap();
+        let (deserialized, _) = RTCPMessage::deserialize(&serialized).unwrap();
+        assert!(matches!(
+            deserialized.message_type,
+            RTCPType::Initialize(8830)
+        ));
+    }
+
+    #[test]
+    fn test_binary_connect_id_len() {
+        // 长度刚好是一个字节能表示的最大值时可以完整往返
+        let mut message = RTCPMessage::new(RTCPType::NewConnection(0));
+        message.connect_id = Some("a".repeat(MAX_CONNECT_ID_LEN));
+        let serialized = message.serialize().unwrap();
+        let (deserialized, size) = RTCPMessage::deserialize(&serialized).unwrap();
+        assert_eq!(size, serialized.len());
+        assert_eq!(deserialized.connect_id, message.connect_id);
+
+        // 超过一个字节时不能截断长度，否则对端会把 connect_id 的尾部当作后面的字段
+        message.connect_id = Some("a".repeat(MAX_CONNECT_ID_LEN + 1));
+        let err = message.serialize().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(message.encode(Codec::Binary).is_err());
+    }
+
+    #[test]
+    fn test_binary_peer_addr() {
+        for peer_addr in ["10.0.0.1:5000", "[2001:db8::1]:443"] {
+            let peer_addr = peer_addr.parse().unwrap();
+            let local_addr = "192.168.1.10:7002".parse().unwrap();
+            let message = RTCPMessage::new(RTCPType::NewConnection(2))
+                .with_peer_addr(Some(peer_addr))
+                .with_local_addr(Some(local_addr));
+            let serialized = message.serialize().unwrap();
+            let (deserialized, _) = RTCPMessage::deserialize(&serialized).unwrap();
+            assert_eq!(deserialized.connect_id, message.connect_id);
+            assert_eq!(deserialized.peer_addr, Some(peer_addr));
+            assert_eq!(deserialized.local_addr, Some(local_addr));
+            assert!(matches!(
+                deserialized.message_type,
+                RTCPType::NewConnection(2)
+            ));
+        }
+
+        let serialized = RTCPMessage::new(RTCPType::StreamOpen(7, 3))
+            .with_peer_addr(Some("10.0.0.1:5000".parse().unwrap()))
+            .serialize()
+            .unwrap();
+        let (deserialized, _) = RTCPMessage::deserialize(&serialized).unwrap();
+        assert!(matches!(
+            deserialized.message_type,
+            RTCPType::StreamOpen(7, 3)
+        ));
+        assert_eq!(deserialized.local_addr, None);
+
+        // 地址族错误或地址不完整视为损坏
+        let mut frame = BytesMut::from(&serialized[..]);
+        frame[FRAME_HEADER_LEN] = 5;
+        assert!(matches!(
+            RTCPMessage::deserialize(&frame),
+            Err(DecodeError::Corrupt(_))
+        ));
+        let mut frame =
+            BytesMut::from(&RTCPMessage::new(RTCPType::Heartbeat).serialize().unwrap()[..]);
+        frame[4] = FLAG_PEER_ADDR;
+        assert!(matches!(
+            RTCPMessage::deserialize(&frame),
+            Err(DecodeError::Corrupt(_))
+        ));
+        frame[4] = FLAG_LOCAL_ADDR;
+        assert!(matches!(
+            RTCPMessage::deserialize(&frame),
+            Err(DecodeError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_binary_payload_types() {
+        let messages = [
+            RTCPType::Auth("alice".to_string()),
+            RTCPType::AuthChallenge(Bytes::from_static(&[1, 2, 3])),
+            RTCPType::AuthResponse(Bytes::from_static(&[4, 5])),
+            RTCPType::AuthOk,
+            RTCPType::Error("auth failed".to_string()),
+            RTCPType::Multiplex,
+            RTCPType::NewConnection(3),
+            RTCPType::StreamOpen(7, 3),
+            RTCPType::StreamData(7, Bytes::from_static(b"hello")),
+            RTCPType::StreamWindowUpdate(7, 1024),
+            RTCPType::StreamClose(7),
+            RTCPType::Attach("connect".to_string()),
+            RTCPType::TunnelAdd(3, 7003),
+            RTCPType::TunnelRemove(3),
+            RTCPType::TunnelOk(3, 7003),
+            RTCPType::TunnelError(3, RejectReason::PortInUse, "port in use".to_string()),
+            RTCPType::InitializeOk(7002),
+            RTCPType::TunnelAddHost(4, "alice.example.com".to_string()),
+            RTCPType::TunnelHostRewrite(4, Some("127.0.0.1:3000".to_string())),
+            RTCPType::TunnelHostRewrite(4, None),
+            RTCPType::PeerAddr,
+            RTCPType::TunnelMode(4, TunnelMode::Tcp),
+            RTCPType::StreamShutdown(7),
+            RTCPType::TunnelError(4, RejectReason::HostInUse, "host in use".to_string()),
+            RTCPType::InitializeError(RejectReason::AuthFailed, "auth failed".to_string()),
+        ];
+        for message_type in messages {
+            let expected = format!("{message_type:?}");
+            let serialized = RTCPMessage::new(message_type).serialize().unwrap();
+            let (deserialized, _) = RTCPMessage::deserialize(&serialized).unwrap();
+            assert_eq!(format!("{:?}", deserialized.message_type), expected);
+        }
+    }
+
+    #[test]
+    fn test_unknown_reject_reason() {
+        let mut frame = BytesMut::from(
+            &RTCPMessage::new(RTCPType::InitializeError(
+                RejectReason::Other,
+                String::new(),
+            ))
+            .serialize()
+            .unwrap()[..],
+        );
+        frame[FRAME_HEADER_LEN] = 200;
+        assert!(matches!(
+            RTCPMessage::deserialize(&frame),
+            Err(DecodeError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_binary_truncated() {
+        let serialized = RTCPMessage::new(RTCPType::NewConnection(0))
+            .serialize()
+            .unwrap();
+        for end in 0..serialized.len() {
+            assert_eq!(
+                RTCPMessage::deserialize(&serialized[..end]).unwrap_err(),
+                DecodeError::Incomplete,
+                "截断到 {end} 字节应当视为不完整"
+            );
+        }
+    }
+
+    #[test]
+    fn test_binary_corrupt() {
+        assert!(matches!(
+            RTCPMessage::deserialize(b"GET / HTTP/1.1\r\n"),
+            Err(DecodeError::Corrupt(_))
+        ));
+
+        let mut frame =
+            BytesMut::from(&RTCPMessage::new(RTCPType::Heartbeat).serialize().unwrap()[..]);
+        frame[2] = 9;
+        assert!(matches!(
+            RTCPMessage::deserialize(&frame),
+            Err(DecodeError::Corrupt(_))
+        ));
+
+        let mut frame =
+            BytesMut::from(&RTCPMessage::new(RTCPType::Heartbeat).serialize().unwrap()[..]);
+        frame[3] = 200;
+        assert!(matches!(
+            RTCPMessage::deserialize(&frame),
+            Err(DecodeError::Corrupt(_))
+        ));
+
+        let mut frame = BytesMut::from(&FRAME_MAGIC[..]);
+        frame.put_u8(FRAME_VERSION);
+        frame.put_u8(4);
+        frame.put_u8(0);
+        frame.put_u32(MAX_FRAME_LEN as u32 + 1);
+        assert!(matches!(
+            RTCPMessage::deserialize(&frame),
+            Err(DecodeError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_text_garbage() {
+        assert_eq!(
+            RTCPMessage::deserialize_text(b"heartbeat").unwrap_err(),
+            DecodeError::Incomplete
+        );
+        assert!(matches!(
+            RTCPMessage::deserialize_text(b"bogus \r\n"),
+            Err(DecodeError::Corrupt(_))
+        ));
+        let long_line = vec![b'a'; MAX_TEXT_LINE_LEN + 1];
+        assert!(matches!(
+            RTCPMessage::deserialize_text(&long_line),
+            Err(DecodeError::Corrupt(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_message_reader_remaining() {
+        // 后端先发送数据时，数据可能和 Attach 帧在同一次读取中到达
+        let attach = RTCPMessage::new(RTCPType::Attach("abc".to_string()));
+        let mut data = BytesMut::from(&attach.encode(Codec::Binary).unwrap()[..]);
+        data.extend_from_slice(b"SSH-2.0-OpenSSH_9.6\r\n");
+        let mut input = &data[..];
+
+        let mut reader = MessageReader::new(Codec::Binary);
+        let msg = reader.read_msg(&mut input).await.unwrap();
+        assert!(matches!(msg.message_type, RTCPType::Attach(id) if id == "abc"));
+        assert!(input.is_empty());
+        assert_eq!(&reader.into_remaining()[..], b"SSH-2.0-OpenSSH_9.6\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_message_reader_multiple_frames() {
+        let mut data = BytesMut::new();
+        data.extend_from_slice(&RTCPMessage::new(RTCPType::Heartbeat).serialize().unwrap());
+        data.extend_from_slice(
+            &RTCPMessage::new(RTCPType::Initialize(7002))
+                .serialize()
+                .unwrap(),
+        );
+        let mut input = &data[..];
+
+        let mut reader = MessageReader::detect();
+        let first = reader.read_msg(&mut input).await.unwrap();
+        assert!(matches!(first.message_type, RTCPType::Heartbeat));
+        assert_eq!(reader.codec(), Some(Codec::Binary));
+        let second = reader.read_msg(&mut input).await.unwrap();
+        assert!(matches!(second.message_type, RTCPType::Initialize(7002)));
+        assert!(reader.read_msg(&mut input).await.is_err());
+    }
+
+    #[test]
+    fn test_text_new_connection_tunnel() {
+        let serialized = RTCPMessage::new(RTCPType::NewConnection(0)).serialize_text();
+        assert!(serialized.starts_with(b"new_connection "));
+        let serialized = RTCPMessage::new(RTCPType::NewConnection(2)).serialize_text();
+        let (deserialized, _) = RTCPMessage::deserialize_text(&serialized).unwrap();
+        assert!(matches!(
+            deserialized.message_type,
+            RTCPType::NewConnection(2)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_message_reader_text() {
+        let mut input = &b"heartbeat \r\ninitialize:7002 \r\n"[..];
+        let mut reader = MessageReader::detect();
+        reader.read_msg(&mut input).await.unwrap();
+        assert_eq!(reader.codec(), Some(Codec::Text));
+        let msg = reader.read_msg(&mut input).await.unwrap();
+        assert!(matches!(msg.message_type, RTCPType::Initialize(7002)));
     }
 }
@@ -0,0 +1,185 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// PROXY protocol v2 的签名
+pub const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+/// 连接后端时在数据之前发送的 PROXY protocol 版本
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    /// 文本格式
+    V1,
+    /// 二进制格式
+    V2,
+}
+
+impl ProxyProtocol {
+    /// 构造 PROXY 头，src 为用户地址，dst 为用户连接的服务器地址，不知道其中之一时声明为未知来源
+    pub fn header(self, src: Option<SocketAddr>, dst: Option<SocketAddr>) -> Bytes {
+        let addrs = src.zip(dst).map(|(src, dst)| same_family(src, dst));
+        match self {
+            ProxyProtocol::V1 => Self::header_v1(addrs),
+            ProxyProtocol::V2 => Self::header_v2(addrs),
+        }
+    }
+
+    fn header_v1(addrs: Option<(SocketAddr, SocketAddr)>) -> Bytes {
+        let line = match addrs {
+            Some((src, dst)) => {
+                let family = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!(
+                    "PROXY {family} {} {} {} {}\r\n",
+                    src.ip(),
+                    dst.ip(),
+                    src.port(),
+                    dst.port()
+                )
+            }
+            None => "PROXY UNKNOWN\r\n".to_string(),
+        };
+        Bytes::from(line)
+    }
+
+    fn header_v2(addrs: Option<(SocketAddr, SocketAddr)>) -> Bytes {
+        let mut buf = BytesMut::with_capacity(V2_SIGNATURE.len() + 4 + 36);
+        buf.put_slice(&V2_SIGNATURE);
+        let Some((src, dst)) = addrs else {
+            // LOCAL 命令，后端使用连接本身的地址
+            buf.put_slice(&[0x20, 0x00, 0x00, 0x00]);
+            return buf.freeze();
+        };
+
+        // PROXY 命令
+        buf.put_u8(0x21);
+        match (src.ip(), dst.ip()) {
+            (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+                buf.put_u8(0x11);
+                buf.put_u16(12);
+                buf.put_slice(&src_ip.octets());
+                buf.put_slice(&dst_ip.octets());
+            }
+            (src_ip, dst_ip) => {
+                buf.put_u8(0x21);
+                buf.put_u16(36);
+                buf.put_slice(&to_v6(src_ip).octets());
+                buf.put_slice(&to_v6(dst_ip).octets());
+            }
+        }
+        buf.put_u16(src.port());
+        buf.put_u16(dst.port());
+        buf.freeze()
+    }
+}
+
+/// 来源和目标地址需要属于同一地址族，不一致时都使用 IPv6
+fn same_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let src = SocketAddr::new(src.ip().to_canonical(), src.port());
+    let dst = SocketAddr::new(dst.ip().to_canonical(), dst.port());
+    if src.is_ipv4() == dst.is_ipv4() {
+        return (src, dst);
+    }
+    (
+        SocketAddr::new(IpAddr::V6(to_v6(src.ip())), src.port()),
+        SocketAddr::new(IpAddr::V6(to_v6(dst.ip())), dst.port()),
+    )
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" | "1" => Ok(ProxyProtocol::V1),
+            "v2" | "2" => Ok(ProxyProtocol::V2),
+            _ => Err(format!("PROXY protocol 版本 `{s}` 无效，应为 v1 或 v2")),
+        }
+    }
+}
+
+impl Display for ProxyProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyProtocol::V1 => write!(f, "v1"),
+            ProxyProtocol::V2 => write!(f, "v2"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod proxy_protocol_test {
+    use super::*;
+
+    #[test]
+    fn test_header_v1() {
+        let src = "10.0.0.1:5000".parse().unwrap();
+        let dst = "127.0.0.1:3000".parse().unwrap();
+        assert_eq!(
+            ProxyProtocol::V1.header(Some(src), Some(dst)),
+            "PROXY TCP4 10.0.0.1 127.0.0.1 5000 3000\r\n"
+        );
+
+        let src = "[2001:db8::1]:5000".parse().unwrap();
+        assert_eq!(
+            ProxyProtocol::V1.header(Some(src), Some(dst)),
+            "PROXY TCP6 2001:db8::1 ::ffff:127.0.0.1 5000 3000\r\n"
+        );
+
+        // 双栈监听时的 IPv4 映射地址按 IPv4 发送
+        let src = "[::ffff:10.0.0.1]:5000".parse().unwrap();
+        assert_eq!(
+            ProxyProtocol::V1.header(Some(src), Some(dst)),
+            "PROXY TCP4 10.0.0.1 127.0.0.1 5000 3000\r\n"
+        );
+
+        assert_eq!(
+            ProxyProtocol::V1.header(None, Some(dst)),
+            "PROXY UNKNOWN\r\n"
+        );
+        // 旧版服务器不发送服务器地址
+        assert_eq!(
+            ProxyProtocol::V1.header(Some(src), None),
+            "PROXY UNKNOWN\r\n"
+        );
+    }
+
+    #[test]
+    fn test_header_v2() {
+        let src = "10.0.0.1:5000".parse().unwrap();
+        let dst = "127.0.0.1:3000".parse().unwrap();
+        let header = ProxyProtocol::V2.header(Some(src), Some(dst));
+        assert_eq!(&header[..12], &V2_SIGNATURE);
+        assert_eq!(
+            &header[12..],
+            &[0x21, 0x11, 0, 12, 10, 0, 0, 1, 127, 0, 0, 1, 0x13, 0x88, 0x0B, 0xB8]
+        );
+
+        let src = "[2001:db8::1]:5000".parse().unwrap();
+        let header = ProxyProtocol::V2.header(Some(src), Some(dst));
+        assert_eq!(&header[12..16], &[0x21, 0x21, 0, 36]);
+        assert_eq!(header.len(), 16 + 36);
+
+        let header = ProxyProtocol::V2.header(None, Some(dst));
+        assert_eq!(&header[12..], &[0x20, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("v1".parse(), Ok(ProxyProtocol::V1));
+        assert_eq!("2".parse(), Ok(ProxyProtocol::V2));
+        assert!("v3".parse::<ProxyProtocol>().is_err());
+    }
+}
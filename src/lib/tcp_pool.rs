@@ -1,30 +1,99 @@
-use bytes::BytesMut;
+use std::{collections::VecDeque, fmt::Display, net::SocketAddr, time::Duration};
+
 use deadpool::managed::{self, RecycleError};
 use tokio::{
-    io::AsyncWriteExt,
-    net::{TcpSocket, TcpStream},
+    io,
+    net::{lookup_host, TcpSocket, TcpStream},
+    task::JoinSet,
+    time::{sleep, timeout},
 };
 
+use crate::tls::{RtcpStream, StreamConnector};
+
+/// 上一个连接还没有结果时，等待多久开始尝试下一个地址（RFC 8305 Connection Attempt Delay）
+const CONNECT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// 默认的连接超时时间，包括域名解析和 tls 握手
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct TcpPoolManager {
     name: String,
     host: String,
     port: u16,
+    /// 建立连接后是否进行 tls 握手
+    connector: StreamConnector,
+    /// 建立一个连接最多等待的时间
+    connect_timeout: Duration,
 }
 
+/// 建立连接失败的原因
 #[derive(Debug)]
 pub enum Error {
-    Fail,
+    /// 连接或 tls 握手出错
+    Io(io::Error),
+    /// 超过连接超时时间仍未建立连接
+    Timeout(Duration),
+    /// 主机名解析失败或者没有解析出地址
+    Resolve(String, io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Timeout(duration) => write!(f, "connect timed out after {duration:?}"),
+            Error::Resolve(host, e) => write!(f, "failed to resolve `{host}`: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) | Error::Resolve(_, e) => Some(e),
+            Error::Timeout(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
 }
 
 impl TcpPoolManager {
     pub fn new(name: String, host: String, port: u16) -> Self {
-        TcpPoolManager { name, host, port }
+        TcpPoolManager {
+            name,
+            host,
+            port,
+            connector: StreamConnector::Plain,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+
+    /// 设置连接超时时间
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// 设置连接方式，用于开启 tls
+    pub fn with_connector(mut self, connector: StreamConnector) -> Self {
+        self.connector = connector;
+        self
+    }
+
+    /// 连接池名称
+    pub fn name(&self) -> &str {
+        &self.name
     }
 }
 
 #[derive(Debug)]
 pub struct TcpStreamData {
-    pub stream: TcpStream,
+    pub stream: RtcpStream,
     pub id: uuid::Uuid,
     pub disconnect: bool,
     /// 最后一次使用结束的时间
@@ -32,9 +101,9 @@ pub struct TcpStreamData {
 }
 
 impl TcpStreamData {
-    pub fn new(stream: TcpStream) -> Self {
+    pub fn new(stream: impl Into<RtcpStream>) -> Self {
         TcpStreamData {
-            stream,
+            stream: stream.into(),
             id: uuid::Uuid::new_v4(),
             disconnect: false,
             latest_time: None,
@@ -48,9 +117,13 @@ impl managed::Manager for TcpPoolManager {
     type Error = Error;
 
     async fn create(&self) -> Result<Self::Type, Self::Error> {
-        let tcp_socket = TcpSocket::new_v4().unwrap();
-        let addr = format!("{}:{}", self.host, self.port).parse().unwrap();
-        let stream = tcp_socket.connect(addr).await.unwrap();
+        let create = async {
+            let stream = connect(&self.host, self.port).await?;
+            Ok::<_, Error>(self.connector.connect(stream).await?)
+        };
+        let stream = timeout(self.connect_timeout, create)
+            .await
+            .map_err(|_| Error::Timeout(self.connect_timeout))??;
         // println!(" 🚀 创建 steam 成功");
         Ok(TcpStreamData::new(stream))
     }
@@ -58,14 +131,14 @@ impl managed::Manager for TcpPoolManager {
     async fn recycle(
         &self,
         obj: &mut Self::Type,
-        metrics: &managed::Metrics,
+        _metrics: &managed::Metrics,
     ) -> managed::RecycleResult<Self::Error> {
         if obj.disconnect {
             return Err(RecycleError::message("steam 已断开，不再回收"));
         }
 
         if let Some(latest_time) = obj.latest_time {
-            if latest_time.elapsed().as_millis() > 10*1000 {
+            if latest_time.elapsed().as_millis() > 10 * 1000 {
                 return Err(RecycleError::message("steam 超过10秒未使用，不再回收"));
             }
         }
@@ -97,16 +170,93 @@ impl managed::Manager for TcpPoolManager {
 
 pub type Pool = managed::Pool<TcpPoolManager>;
 
+/// 连接 `host:port`，host 可以是主机名、IPv4 或 IPv6 地址。
+/// 解析出多个地址时按 happy eyeballs（RFC 8305）交替尝试两种地址族，使用最先建立的连接
+pub async fn connect(host: &str, port: u16) -> Result<TcpStream, Error> {
+    // `[::1]` 形式的 IPv6 地址去掉括号
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    let addrs: Vec<_> = lookup_host((host, port))
+        .await
+        .map_err(|e| Error::Resolve(host.to_string(), e))?
+        .collect();
+    if addrs.is_empty() {
+        let e = io::Error::new(io::ErrorKind::NotFound, "no address found");
+        return Err(Error::Resolve(host.to_string(), e));
+    }
+
+    let mut pending = interleave(addrs).into_iter();
+    let mut attempts = JoinSet::new();
+    let mut last_err = None;
+    loop {
+        // 上一个地址连接失败或者等待超过 CONNECT_ATTEMPT_DELAY 后尝试下一个地址
+        if let Some(addr) = pending.next() {
+            attempts.spawn(connect_addr(addr));
+        }
+        if attempts.is_empty() {
+            return Err(Error::Io(last_err.expect("至少尝试过一个地址")));
+        }
+        tokio::select! {
+            Some(res) = attempts.join_next() => match res {
+                // 丢弃 attempts 时会取消其余的连接尝试
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => last_err = Some(e),
+                Err(e) => last_err = Some(io::Error::other(e)),
+            },
+            _ = sleep(CONNECT_ATTEMPT_DELAY), if pending.len() > 0 => {}
+        }
+    }
+}
+
+/// 按地址的协议族创建 socket 并连接
+async fn connect_addr(addr: SocketAddr) -> io::Result<TcpStream> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.connect(addr).await
+}
+
+/// 交替排列 IPv6 和 IPv4 地址，解析结果中第一个地址的协议族排在前面
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let mut result = Vec::with_capacity(preferred.len() + other.len());
+    while !preferred.is_empty() || !other.is_empty() {
+        result.extend(preferred.pop_front());
+        result.extend(other.pop_front());
+    }
+    result
+}
+
 #[cfg(test)]
 mod tcp_poll_test {
 
-    use deadpool::unmanaged;
+    use std::{net::SocketAddr, time::Duration};
+
+    use deadpool::{managed::PoolError, unmanaged};
+    use tokio::net::TcpListener;
 
-    use super::{Pool, TcpPoolManager, TcpStreamData};
+    use super::{connect, interleave, Error, Pool, TcpPoolManager, TcpStreamData};
+    use crate::tls::{StreamConnector, TlsVerify};
 
     #[tokio::test]
     async fn test_tcp_pool() {
-        let mgr = TcpPoolManager::new("test".to_string(), "127.0.0.1".to_string(), 8081);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut streams = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        let mgr = TcpPoolManager::new("test".to_string(), "127.0.0.1".to_string(), port);
         let poll_builder = Pool::builder(mgr);
         let poll = poll_builder.build().unwrap();
         let a = poll.get().await.unwrap();
@@ -117,8 +267,105 @@ mod tcp_poll_test {
         println!("🚀{:?}", a.id);
     }
 
+    #[tokio::test]
+    async fn test_connect_hostname() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+
+        // localhost 可能先解析出 ::1，连接被拒绝后应该换成 127.0.0.1
+        let stream = connect("localhost", port).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap().port(), port);
+
+        let mgr = TcpPoolManager::new("test".to_string(), "localhost".to_string(), port);
+        let poll = Pool::builder(mgr).build().unwrap();
+        assert!(poll.get().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connect_ipv6() {
+        let Ok(listener) = TcpListener::bind("[::1]:0").await else {
+            // 环境不支持 IPv6
+            return;
+        };
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+
+        for host in ["::1", "[::1]"] {
+            let stream = connect(host, port).await.unwrap();
+            assert!(stream.peer_addr().unwrap().is_ipv6());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_errors() {
+        // 拿到一个空闲端口后关闭监听，连接会被拒绝
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let mgr = TcpPoolManager::new("test".to_string(), "127.0.0.1".to_string(), port);
+        let poll = Pool::builder(mgr).build().unwrap();
+        assert!(matches!(
+            poll.get().await,
+            Err(PoolError::Backend(Error::Io(_)))
+        ));
+
+        let mgr = TcpPoolManager::new("test".to_string(), "host.invalid".to_string(), port);
+        let poll = Pool::builder(mgr).build().unwrap();
+        assert!(matches!(
+            poll.get().await,
+            Err(PoolError::Backend(Error::Resolve(..) | Error::Timeout(_)))
+        ));
+
+        // 接受连接但不回应 tls 握手
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut streams = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+        let connector =
+            StreamConnector::tls(&TlsVerify::Fingerprint("00".repeat(32)), "localhost").unwrap();
+        let mgr = TcpPoolManager::new("test".to_string(), "127.0.0.1".to_string(), port)
+            .with_connector(connector)
+            .with_connect_timeout(Duration::from_millis(100));
+        let poll = Pool::builder(mgr).build().unwrap();
+        assert!(matches!(
+            poll.get().await,
+            Err(PoolError::Backend(Error::Timeout(_)))
+        ));
+    }
+
+    #[test]
+    fn test_interleave() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let order = |addrs: Vec<SocketAddr>| -> Vec<String> {
+            interleave(addrs)
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect()
+        };
+        assert_eq!(
+            order(addrs.clone()),
+            ["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]
+        );
+        let mut v4_first = addrs;
+        v4_first.rotate_left(3);
+        assert_eq!(
+            order(v4_first),
+            ["10.0.0.1:1", "[::1]:1", "10.0.0.2:1", "[::2]:1", "[::3]:1"]
+        );
+    }
+
+    #[tokio::test]
     async fn test_tcp_pool_2() {
-        let a: unmanaged::Pool<TcpStreamData> = unmanaged::Pool::new(1000);
+        let _a: unmanaged::Pool<TcpStreamData> = unmanaged::Pool::new(1000);
         // a.add()
     }
 }
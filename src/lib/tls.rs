@@ -0,0 +1,420 @@
+use std::{
+    io,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    client,
+    rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{ring, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+    },
+    server, TlsAcceptor, TlsConnector,
+};
+
+/// client 与 server 之间的连接，可能是明文 tcp 也可能是 tls
+#[derive(Debug)]
+pub enum RtcpStream {
+    Plain(TcpStream),
+    ClientTls(Box<client::TlsStream<TcpStream>>),
+    ServerTls(Box<server::TlsStream<TcpStream>>),
+}
+
+impl RtcpStream {
+    /// 底层 tcp 连接
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            RtcpStream::Plain(stream) => stream,
+            RtcpStream::ClientTls(stream) => stream.get_ref().0,
+            RtcpStream::ServerTls(stream) => stream.get_ref().0,
+        }
+    }
+}
+
+impl From<TcpStream> for RtcpStream {
+    fn from(stream: TcpStream) -> Self {
+        RtcpStream::Plain(stream)
+    }
+}
+
+impl AsyncRead for RtcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RtcpStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            RtcpStream::ClientTls(stream) => Pin::new(stream).poll_read(cx, buf),
+            RtcpStream::ServerTls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for RtcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            RtcpStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            RtcpStream::ClientTls(stream) => Pin::new(stream).poll_write(cx, buf),
+            RtcpStream::ServerTls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RtcpStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            RtcpStream::ClientTls(stream) => Pin::new(stream).poll_flush(cx),
+            RtcpStream::ServerTls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RtcpStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            RtcpStream::ClientTls(stream) => Pin::new(stream).poll_shutdown(cx),
+            RtcpStream::ServerTls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn invalid_input(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+}
+
+/// server 端接收连接，按配置决定是否进行 tls 握手
+#[derive(Clone)]
+pub enum StreamAcceptor {
+    Plain,
+    Tls(TlsAcceptor),
+}
+
+impl StreamAcceptor {
+    /// 从 pem 格式的证书链和私钥文件加载
+    pub fn from_pem_files(cert: &Path, key: &Path) -> io::Result<Self> {
+        let certs = CertificateDer::pem_file_iter(cert)
+            .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| invalid_input(format!("读取证书失败 {cert:?}: {e}")))?;
+        let key = PrivateKeyDer::from_pem_file(key)
+            .map_err(|e| invalid_input(format!("读取私钥失败 {key:?}: {e}")))?;
+        Self::from_der(certs, key)
+    }
+
+    /// 从 der 格式的证书链和私钥创建
+    pub fn from_der(
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> io::Result<Self> {
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_input)?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(invalid_input)?;
+        Ok(StreamAcceptor::Tls(TlsAcceptor::from(Arc::new(config))))
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<RtcpStream> {
+        match self {
+            StreamAcceptor::Plain => Ok(RtcpStream::Plain(stream)),
+            StreamAcceptor::Tls(acceptor) => {
+                let stream = acceptor.accept(stream).await?;
+                Ok(RtcpStream::ServerTls(Box::new(stream)))
+            }
+        }
+    }
+}
+
+/// client 端校验服务器证书的方式
+#[derive(Debug, Clone)]
+pub enum TlsVerify {
+    /// 使用 pem 格式的 CA 证书文件校验
+    CaFile(std::path::PathBuf),
+    /// 校验服务器证书 sha256 指纹
+    Fingerprint(String),
+}
+
+/// client 端发起连接，按配置决定是否进行 tls 握手
+#[derive(Clone)]
+pub enum StreamConnector {
+    Plain,
+    Tls {
+        connector: TlsConnector,
+        server_name: ServerName<'static>,
+    },
+}
+
+impl std::fmt::Debug for StreamConnector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamConnector::Plain => write!(f, "Plain"),
+            StreamConnector::Tls { server_name, .. } => write!(f, "Tls({server_name:?})"),
+        }
+    }
+}
+
+impl StreamConnector {
+    /// 创建 tls 连接器，server_name 为服务器域名或 ip
+    pub fn tls(verify: &TlsVerify, server_name: &str) -> io::Result<Self> {
+        let server_name = ServerName::try_from(server_name.to_string()).map_err(invalid_input)?;
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_input)?;
+
+        let config = match verify {
+            TlsVerify::CaFile(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(path)
+                    .map_err(|e| invalid_input(format!("读取 CA 证书失败 {path:?}: {e}")))?
+                {
+                    let cert = cert.map_err(invalid_input)?;
+                    roots.add(cert).map_err(invalid_input)?;
+                }
+                builder.with_root_certificates(roots).with_no_client_auth()
+            }
+            TlsVerify::Fingerprint(fingerprint) => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(FingerprintVerifier {
+                    fingerprint: parse_fingerprint(fingerprint)?,
+                    provider: provider(),
+                }))
+                .with_no_client_auth(),
+        };
+
+        Ok(StreamConnector::Tls {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    pub async fn connect(&self, stream: TcpStream) -> io::Result<RtcpStream> {
+        match self {
+            StreamConnector::Plain => Ok(RtcpStream::Plain(stream)),
+            StreamConnector::Tls {
+                connector,
+                server_name,
+            } => {
+                let stream = connector.connect(server_name.clone(), stream).await?;
+                Ok(RtcpStream::ClientTls(Box::new(stream)))
+            }
+        }
+    }
+}
+
+/// 计算证书的 sha256 指纹，格式为冒号分隔的大写十六进制
+pub fn cert_fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// 解析十六进制指纹，允许使用冒号分隔
+pub fn parse_fingerprint(fingerprint: &str) -> io::Result<Vec<u8>> {
+    let hex: String = fingerprint.chars().filter(|c| *c != ':').collect();
+    if !hex.is_ascii() || hex.len() != 64 {
+        return Err(invalid_input("sha256 指纹长度应为 32 字节"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(invalid_input))
+        .collect()
+}
+
+/// 只校验证书指纹的校验器，用于自签名证书
+#[derive(Debug)]
+struct FingerprintVerifier {
+    fingerprint: Vec<u8>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity).as_slice() == self.fingerprint.as_slice() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "certificate fingerprint mismatch".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tls_test {
+    use rcgen::{CertificateParams, CertifiedKey, KeyPair};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// 生成本地 CA 和由它签发的服务器证书
+    fn generate_certs() -> (CertifiedKey, CertifiedKey) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let server_params =
+            CertificateParams::new(vec!["localhost".to_string(), "127.0.0.1".to_string()]).unwrap();
+        let server_cert = server_params
+            .signed_by(&server_key, &ca_cert, &ca_key)
+            .unwrap();
+
+        (
+            CertifiedKey {
+                cert: ca_cert,
+                key_pair: ca_key,
+            },
+            CertifiedKey {
+                cert: server_cert,
+                key_pair: server_key,
+            },
+        )
+    }
+
+    /// 启动 tls echo 服务器，返回端口
+    async fn echo_server(server: &CertifiedKey) -> u16 {
+        let acceptor = StreamAcceptor::from_der(
+            vec![server.cert.der().clone()],
+            PrivateKeyDer::try_from(server.key_pair.serialize_der()).unwrap(),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(tcp).await else {
+                        return;
+                    };
+                    let mut buf = [0u8; 5];
+                    if stream.read_exact(&mut buf).await.is_ok() {
+                        let _ = stream.write_all(&buf).await;
+                        let _ = stream.flush().await;
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    async fn echo(connector: &StreamConnector, port: u16) -> io::Result<[u8; 5]> {
+        let tcp = TcpStream::connect(("127.0.0.1", port)).await?;
+        let mut stream = connector.connect(tcp).await?;
+        stream.write_all(b"hello").await?;
+        stream.flush().await?;
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
+
+    #[tokio::test]
+    async fn test_tls_with_ca_file() {
+        let (ca, server) = generate_certs();
+        let port = echo_server(&server).await;
+
+        let ca_path = std::env::temp_dir().join(format!("rtcp-ca-{}.pem", uuid::Uuid::new_v4()));
+        std::fs::write(&ca_path, ca.cert.pem()).unwrap();
+        let connector =
+            StreamConnector::tls(&TlsVerify::CaFile(ca_path.clone()), "localhost").unwrap();
+        let res = echo(&connector, port).await;
+        std::fs::remove_file(ca_path).unwrap();
+        assert_eq!(&res.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_tls_with_fingerprint() {
+        let (_ca, server) = generate_certs();
+        let port = echo_server(&server).await;
+
+        let fingerprint = cert_fingerprint(server.cert.der());
+        let connector =
+            StreamConnector::tls(&TlsVerify::Fingerprint(fingerprint), "127.0.0.1").unwrap();
+        assert_eq!(&echo(&connector, port).await.unwrap(), b"hello");
+
+        let (_ca, other) = generate_certs();
+        let fingerprint = cert_fingerprint(other.cert.der());
+        let connector =
+            StreamConnector::tls(&TlsVerify::Fingerprint(fingerprint), "127.0.0.1").unwrap();
+        assert!(
+            echo(&connector, port).await.is_err(),
+            "指纹不一致时应当握手失败"
+        );
+    }
+
+    #[test]
+    fn test_parse_fingerprint() {
+        let fingerprint = cert_fingerprint(b"rtcp");
+        assert_eq!(
+            parse_fingerprint(&fingerprint).unwrap(),
+            Sha256::digest(b"rtcp").to_vec()
+        );
+        assert!(parse_fingerprint("AB:CD").is_err());
+        assert!(parse_fingerprint(&format!("0é{}", "0".repeat(61))).is_err());
+    }
+}
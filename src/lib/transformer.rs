@@ -1,43 +1,352 @@
-use std::{collections::HashMap, marker::PhantomPinned, net::SocketAddr};
+use std::{
+    marker::PhantomPinned,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
+use nom::IResult;
 use tokio::{
     io::{self, AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+};
+
+use crate::{
+    chunked::{ChunkedDecoder, ChunkedError},
+    forwarded::{forwarded_node, forwarded_value},
+    parser::{parser_request_head_all, parser_response_head_all, Headers, RequestLine},
 };
 
-use crate::parser::{parser_request_head_all, RequestLine};
+/// 转发给后端时 Host 头的处理方式
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum HostRewrite {
+    /// 保留用户请求中的 Host
+    #[default]
+    Preserve,
+    /// 改写为指定的值
+    Rewrite(String),
+}
+
+/// 用户连接的信息，用于生成转发相关的请求头
+#[derive(Debug, Clone, Copy)]
+pub struct Forwarding {
+    /// 用户地址
+    pub user_addr: SocketAddr,
+    /// 用户访问的端口
+    pub port: u16,
+    /// 用户地址是否为可信代理，可信时保留请求中已有的转发头并追加，否则丢弃
+    pub trusted: bool,
+}
+
+/// 用户连接使用的协议，用户端口目前只支持明文 http
+const FORWARDED_PROTO: &str = "http";
+
+/// 由代理添加的转发头，来自不可信地址时丢弃
+const FORWARDING_HEADERS: [&str; 6] = [
+    "Forwarded",
+    "X-Forwarded-For",
+    "X-Forwarded-Host",
+    "X-Forwarded-Proto",
+    "X-Forwarded-Port",
+    "X-Real-IP",
+];
+
+/// 请求头和响应头的最大长度
+pub const MAX_HEAD_LEN: usize = 16 * 1024;
+
+/// 转发消息体时每次读取的最大长度，转发完才会继续读取
+const BODY_BUF_LEN: usize = 16 * 1024;
+
+/// 转发请求失败的原因
+#[derive(Debug)]
+pub enum HttpError {
+    /// 连接读写失败
+    Io(io::Error),
+    /// 请求不能转发，需要回复给用户的状态码和原因
+    Reject(u16, &'static str),
+}
+
+impl From<io::Error> for HttpError {
+    fn from(e: io::Error) -> Self {
+        HttpError::Io(e)
+    }
+}
+
+impl From<ChunkedError> for HttpError {
+    fn from(e: ChunkedError) -> Self {
+        println!("❌chunked 消息体格式错误 {e}");
+        HttpError::Reject(400, "Bad Request")
+    }
+}
 
 pub struct HttpTransformer {
-    user_addr: SocketAddr,
-    /// 请求首部
-    request_head: Option<RequestHead>,
+    /// 用户连接的信息，None 表示不添加转发头
+    forwarding: Option<Forwarding>,
+    /// Host 头的处理方式
+    host_rewrite: HostRewrite,
+    /// 已读取但还没有转发的数据，可能是下一个请求的开头
+    buf: HttpBuffer,
+    /// 请求体的最大长度，None 表示不限制
+    max_body_size: Option<u64>,
+    /// 已转发的请求，按顺序交给响应一侧匹配
+    requests: Option<UnboundedSender<RequestInfo>>,
+    /// 协议已经升级，之后的数据原样转发
+    upgraded: bool,
     _marker: PhantomPinned,
 }
 
+/// 已经转发给后端、等待响应的请求
+#[derive(Debug)]
+struct RequestInfo {
+    method: String,
+    path: String,
+    /// 收到完整请求头的时间
+    started: Instant,
+    /// 请求协议升级时，响应一侧通过它告知后端是否同意
+    upgrade: Option<oneshot::Sender<bool>>,
+}
+
+/// 响应头的处理规则，例如添加 CORS、HSTS 或者删除 Server
+#[derive(Debug, Clone, Default)]
+pub struct ResponseRules {
+    /// 添加的响应头，已有的同名响应头会被替换
+    pub set: Vec<(String, String)>,
+    /// 删除的响应头
+    pub remove: Vec<String>,
+}
+
+impl ResponseRules {
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.remove.is_empty()
+    }
+
+    /// 按规则改写原始的响应头，其余的行（包括重复的 Set-Cookie）原样保留
+    fn apply(&self, head: &[u8]) -> BytesMut {
+        let mut res = BytesMut::with_capacity(head.len());
+        let mut lines = head.split_inclusive(|b| *b == b'\n');
+        // 状态行
+        res.put_slice(lines.next().unwrap_or_default());
+        for line in lines {
+            if line == b"\r\n" {
+                break;
+            }
+            let name = line.split(|b| *b == b':').next().unwrap_or_default();
+            let matches = |rule: &String| rule.as_bytes().eq_ignore_ascii_case(name);
+            if self.remove.iter().any(matches) || self.set.iter().any(|(k, _)| matches(k)) {
+                continue;
+            }
+            res.put_slice(line);
+        }
+        for (key, value) in &self.set {
+            res.put_slice(format!("{key}: {value}\r\n").as_bytes());
+        }
+        res.put_slice(b"\r\n");
+        res
+    }
+}
+
+/// 解析 `Name: value` 格式的头部字段
+pub fn parse_header_field(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("头部字段 `{s}` 应为 `Name: value`"))?;
+    let name = name.trim();
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(format!("头部字段名 `{name}` 无效"));
+    }
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+/// 一次请求的记录
+#[derive(Debug)]
+pub struct ResponseLog {
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    /// 从收到完整请求头到响应转发完的时间
+    pub latency: Duration,
+    /// 响应体转发的字节数
+    pub body_len: u64,
+}
+
+/// 读取 http 消息的缓冲区，请求和响应共用
+#[derive(Debug)]
+struct HttpBuffer {
+    buf: BytesMut,
+}
+
+impl HttpBuffer {
+    fn new() -> Self {
+        Self {
+            buf: BytesMut::with_capacity(4 * 1024),
+        }
+    }
+
+    /// 读取更多数据，连接在消息中途断开时返回错误
+    async fn fill<R>(&mut self, reader: &mut R) -> io::Result<()>
+    where
+        R: AsyncReadExt + Unpin,
+    {
+        self.buf.reserve(BODY_BUF_LEN);
+        if reader.read_buf(&mut self.buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    /// 读取一个完整的首部，返回解析结果和原始数据，连接在消息之间关闭时返回 None
+    async fn read_head<R, T, F>(
+        &mut self,
+        reader: &mut R,
+        parse: F,
+    ) -> Result<Option<(T, BytesMut)>, HttpError>
+    where
+        R: AsyncReadExt + Unpin,
+        F: Fn(&[u8]) -> IResult<&[u8], T>,
+    {
+        loop {
+            match parse(&self.buf) {
+                Ok((rest, head)) => {
+                    let head_len = self.buf.len() - rest.len();
+                    return Ok(Some((head, self.buf.split_to(head_len))));
+                }
+                Err(e) if e.is_incomplete() => {}
+                Err(nom::Err::Error(e) | nom::Err::Failure(e))
+                    if e.code == nom::error::ErrorKind::TooLarge =>
+                {
+                    return Err(HttpError::Reject(431, "Request Header Fields Too Large"));
+                }
+                Err(_) => return Err(HttpError::Reject(400, "Bad Request")),
+            }
+            if self.buf.len() >= MAX_HEAD_LEN {
+                return Err(HttpError::Reject(431, "Request Header Fields Too Large"));
+            }
+            if reader.read_buf(&mut self.buf).await? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// 转发固定长度的消息体
+    async fn copy_length<R, W>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+        len: u64,
+    ) -> io::Result<u64>
+    where
+        R: AsyncReadExt + Unpin,
+        W: AsyncWriteExt + Unpin,
+    {
+        let mut remaining = len;
+        while remaining > 0 {
+            if self.buf.is_empty() {
+                self.fill(reader).await?;
+            }
+            let size = self
+                .buf
+                .len()
+                .min(remaining.try_into().unwrap_or(usize::MAX));
+            writer.write_all(&self.buf.split_to(size)).await?;
+            remaining -= size as u64;
+        }
+        Ok(len)
+    }
+
+    /// 转发 chunked 消息体，收到一部分就转发一部分，包括结尾的 trailer
+    async fn copy_chunked<R, W>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+        max_body_size: Option<u64>,
+    ) -> Result<u64, HttpError>
+    where
+        R: AsyncReadExt + Unpin,
+        W: AsyncWriteExt + Unpin,
+    {
+        let mut decoder = ChunkedDecoder::new();
+        let mut written = 0;
+        loop {
+            let size = decoder.decode(&self.buf, None)?;
+            // 对端已经收到首部，超过限制时只能中断这个消息
+            if max_body_size.is_some_and(|max| decoder.body_len() > max) {
+                return Err(HttpError::Reject(413, "Payload Too Large"));
+            }
+            if size > 0 {
+                writer.write_all(&self.buf.split_to(size)).await?;
+                written += size as u64;
+            }
+            if decoder.is_done() {
+                return Ok(written);
+            }
+            // 把已经转发的块尽快送到对端
+            writer.flush().await?;
+            self.fill(reader).await?;
+        }
+    }
+
+    /// 转发数据直到连接关闭
+    async fn copy_until_close<R, W>(&mut self, reader: &mut R, writer: &mut W) -> io::Result<u64>
+    where
+        R: AsyncReadExt + Unpin,
+        W: AsyncWriteExt + Unpin,
+    {
+        let buffered = self.buf.len() as u64;
+        writer.write_all(&self.buf.split()).await?;
+        Ok(buffered + io::copy(reader, writer).await?)
+    }
+}
+
 /// 请求首部
 #[derive(Debug)]
 struct RequestHead {
     request_line: RequestLine,
-    headers: HashMap<String, String>,
+    headers: Headers,
 }
 
 impl RequestHead {
-    /// 修改请求头
+    /// 获取请求头，忽略大小写，同名的请求头用逗号连接
+    fn get_head(&self, k: &str) -> Option<String> {
+        self.headers.get_joined(k)
+    }
+
+    /// 删除请求头，忽略大小写
+    fn remove_head(&mut self, k: &str) {
+        self.headers.remove(k);
+    }
+
+    /// 请求头不存在时设置
+    fn default_head(&mut self, k: &str, v: String) {
+        if !self.headers.contains(k) {
+            self.headers.append(k, v);
+        }
+    }
+
+    /// 在请求头已有的列表后面追加
+    fn append_head(&mut self, k: &str, v: String) {
+        let v = match self.get_head(k) {
+            Some(existing) => format!("{existing}, {v}"),
+            None => v,
+        };
+        self.change_head(k.to_string(), v);
+    }
+
+    /// 修改请求头，同名的请求头忽略大小写替换
     pub fn change_head(&mut self, k: String, v: String) {
         self.headers.insert(k, v);
     }
 
-    /// 构造请求头
+    /// 构造请求头，没有修改过的请求头与用户发送的完全一致
     pub fn build_request_head(&self) -> BytesMut {
         let request_line_byte = self.request_line.to_byte();
 
         let mut request_head = BytesMut::from_iter(request_line_byte);
 
-        for (key, value) in self.headers.iter() {
-            let header = format!("{}: {}\r\n", key, value);
-            request_head.put_slice(header.as_bytes());
-        }
+        self.headers.write_to(&mut request_head);
 
         request_head.put_slice(b"\r\n");
 
@@ -46,111 +355,973 @@ impl RequestHead {
 
     /// 获取请求头长度
     pub fn get_content_length(&self) -> Option<String> {
-        self.headers.get("Content-Length").cloned()
+        self.get_head("Content-Length")
+    }
+
+    /// 是否请求升级协议，例如 WebSocket
+    fn is_upgrade(&self) -> bool {
+        let connection_upgrade = self.get_head("Connection").is_some_and(|connection| {
+            connection
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+        });
+        connection_upgrade && self.get_head("Upgrade").is_some()
+    }
+
+    /// 按 RFC 9112 确定请求体的长度，可能导致请求走私的歧义请求都拒绝
+    fn body_length(&self) -> Result<BodyLength, String> {
+        if self.headers.get_all("Host").count() > 1 {
+            return Err("请求带有多个 Host".to_string());
+        }
+        let Some(encoding) = self.get_head("Transfer-Encoding") else {
+            return Ok(BodyLength::Length(
+                content_length(&self.headers)?.unwrap_or(0),
+            ));
+        };
+        if self.headers.contains("Content-Length") {
+            return Err("请求同时带有 Transfer-Encoding 和 Content-Length".to_string());
+        }
+        if self.request_line.protocol == "HTTP/1.0" {
+            return Err("HTTP/1.0 请求不能使用 Transfer-Encoding".to_string());
+        }
+        // chunked 必须是最后一个编码，并且只能出现一次
+        let codings: Vec<_> = encoding.split(',').map(str::trim).collect();
+        let chunked = codings
+            .iter()
+            .filter(|coding| coding.eq_ignore_ascii_case("chunked"))
+            .count();
+        if chunked != 1 || !codings[codings.len() - 1].eq_ignore_ascii_case("chunked") {
+            return Err(format!("不支持的 Transfer-Encoding `{encoding}`"));
+        }
+        Ok(BodyLength::Chunked)
     }
 }
 
+/// 解析 Content-Length，重复的字段或逗号分隔的多个值必须完全相同
+fn content_length(headers: &Headers) -> Result<Option<u64>, String> {
+    let mut length = None;
+    for value in headers
+        .get_all("Content-Length")
+        .flat_map(|value| value.split(','))
+    {
+        let value = value.trim();
+        // u64 的 parse 接受 `+` 前缀，这里只允许数字
+        let len = Some(value)
+            .filter(|value| !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|value| value.parse::<u64>().ok())
+            .ok_or_else(|| format!("Content-Length `{value}` 无效"))?;
+        if length.is_some_and(|length| length != len) {
+            return Err("Content-Length 的值不一致".to_string());
+        }
+        length = Some(len);
+    }
+    Ok(length)
+}
+
+/// 消息体的长度
+#[derive(Debug, PartialEq, Eq)]
+enum BodyLength {
+    /// 固定长度
+    Length(u64),
+    /// chunked 编码，读到最后一个块和 trailer 为止
+    Chunked,
+    /// 读到连接关闭为止，只用于响应
+    Close,
+}
+
+/// 响应体的长度，request 为对应的请求
+fn response_body_length(
+    status: u16,
+    headers: &Headers,
+    request: &RequestInfo,
+) -> Result<BodyLength, String> {
+    if request.method.eq_ignore_ascii_case("HEAD") || status < 200 || status == 204 || status == 304
+    {
+        return Ok(BodyLength::Length(0));
+    }
+    if let Some(encoding) = headers.get_joined("Transfer-Encoding") {
+        let last = encoding.rsplit(',').next().unwrap_or_default().trim();
+        return Ok(match last.eq_ignore_ascii_case("chunked") {
+            true => BodyLength::Chunked,
+            false => BodyLength::Close,
+        });
+    }
+    match content_length(headers).map_err(|e| format!("响应的{e}"))? {
+        Some(len) => Ok(BodyLength::Length(len)),
+        None => Ok(BodyLength::Close),
+    }
+}
+
+/// 构造服务器直接返回给用户的错误响应，发送后关闭连接
+pub fn error_response(status: u16, reason: &str) -> Bytes {
+    let body = format!("{status} {reason}\n");
+    Bytes::from(format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    ))
+}
+
 impl HttpTransformer {
-    pub fn new(user_addr: SocketAddr) -> Self {
+    pub fn new(forwarding: Forwarding, host_rewrite: HostRewrite) -> Self {
         Self {
-            user_addr,
-            request_head: None,
+            forwarding: Some(forwarding),
+            host_rewrite,
+            buf: HttpBuffer::new(),
+            max_body_size: None,
+            requests: None,
+            upgraded: false,
             _marker: PhantomPinned,
         }
     }
-    /// 解析请求头
-    fn parse_header(&mut self, buf: &mut BytesMut) -> Result<usize, ()> {
-        match parser_request_head_all(buf) {
-            Ok((rest, (request_line, headers))) => {
-                self.request_head = Some(RequestHead {
-                    request_line,
-                    headers,
-                });
-                let head_len = buf.len() - rest.len();
-                Ok(head_len)
-            }
-            Err(_) => Err(()),
+
+    /// 不改写请求头的转换器，只按消息边界转发，用于已经由服务器改写过的请求
+    pub fn passthrough() -> Self {
+        Self {
+            forwarding: None,
+            host_rewrite: HostRewrite::Preserve,
+            buf: HttpBuffer::new(),
+            max_body_size: None,
+            requests: None,
+            upgraded: false,
+            _marker: PhantomPinned,
         }
     }
 
-    /// 解析一条 http 请求
-    async fn parse_http_request<'a, R>(&mut self, reader: &'a mut R) -> BytesMut
-    where
-        R: AsyncReadExt + Unpin,
-    {
-        let mut buf = BytesMut::with_capacity(4 * 1024);
-        loop {
-            match reader.read_buf(&mut buf).await {
-                Ok(size) => {
-                    if size == 0 {
-                        return buf;
-                    }
-
-                    if self.request_head.is_none() {
-                        // 头部没有读取完整，继续读取
-                        let res = self.parse_header(&mut buf);
-                        if res.is_err() {
-                            continue;
-                        }
-
-                        // 头部解析完之后，丢弃掉头部的数据
-                        let head_len = res.unwrap();
-                        buf.advance(head_len);
-                    }
-
-                    let request_head = self.request_head.as_ref().unwrap();
-                    if let Some(content_len) = request_head.get_content_length() {
-                        // 如果当前读取的数据没有超过内容长度，继续读取
-                        if buf.len() < content_len.parse::<usize>().unwrap() {
-                            continue;
-                        }
-                    };
-                    break;
-                }
-                Err(e) => {
-                    eprintln!("parse_http_request 传输读取失败 {e:?}");
-                    return buf;
-                }
-            };
-        }
+    /// 设置请求体的最大长度，声明的长度超过时回复 413，chunked 请求体在转发途中超过时断开连接
+    pub fn with_max_body_size(mut self, max_body_size: Option<u64>) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
 
-        let request_head = self.request_head.as_mut().unwrap();
-        let header_bytes = Self::transformer(request_head, self.user_addr);
+    /// 协议是否已经升级，升级之后的数据不再是 http
+    pub fn is_upgraded(&self) -> bool {
+        self.upgraded
+    }
 
-        let mut res = BytesMut::new();
-        res.extend_from_slice(&header_bytes);
-        res.extend_from_slice(&buf);
+    /// 创建处理同一连接上响应的 ResponseTransformer，按请求的顺序匹配响应
+    pub fn response_transformer(&mut self, rules: Arc<ResponseRules>) -> ResponseTransformer {
+        let (requests_tx, requests_rx) = unbounded_channel();
+        self.requests = Some(requests_tx);
+        ResponseTransformer {
+            rules,
+            requests: requests_rx,
+            buf: HttpBuffer::new(),
+            upgraded: false,
+        }
+    }
 
-        self.request_head = None;
-        res
+    /// 请求体长度是否超过限制
+    fn body_too_large(&self, len: u64) -> bool {
+        self.max_body_size.is_some_and(|max| len > max)
     }
 
     /// 修改请求头
-    fn transformer(request_head: &mut RequestHead, user_addr: SocketAddr) -> BytesMut {
-        request_head.change_head("Host".to_string(), "8.0.0.1".to_string());
-        request_head.change_head("X-Forwarded-For".to_string(), user_addr.ip().to_string());
+    fn transformer(
+        request_head: &mut RequestHead,
+        forwarding: &Forwarding,
+        host_rewrite: &HostRewrite,
+    ) -> BytesMut {
+        if !forwarding.trusted {
+            for header in FORWARDING_HEADERS {
+                request_head.remove_head(header);
+            }
+        }
+        let user_ip = forwarding.user_addr.ip().to_canonical().to_string();
+        // 可信代理转发的请求，真实 ip 是转发链中的第一个地址
+        let real_ip = request_head
+            .get_head("X-Forwarded-For")
+            .and_then(|chain| chain.split(',').next().map(|ip| ip.trim().to_string()))
+            .unwrap_or_else(|| user_ip.clone());
+        request_head.default_head("X-Real-IP", real_ip);
+        request_head.append_head("X-Forwarded-For", user_ip);
+
+        // 改写之前保留用户请求的原始 Host
+        let host = request_head.get_head("Host");
+        let mut forwarded = format!("for={}", forwarded_node(forwarding.user_addr));
+        if let Some(host) = &host {
+            request_head.default_head("X-Forwarded-Host", host.clone());
+            forwarded.push_str(&format!(";host={}", forwarded_value(host)));
+        }
+        forwarded.push_str(&format!(";proto={FORWARDED_PROTO}"));
+        request_head.append_head("Forwarded", forwarded);
+        request_head.default_head("X-Forwarded-Proto", FORWARDED_PROTO.to_string());
+        request_head.default_head("X-Forwarded-Port", forwarding.port.to_string());
+
+        if let HostRewrite::Rewrite(host) = host_rewrite {
+            request_head.change_head("Host".to_string(), host.clone());
+        }
         // request_head.change_head("User-Agent".to_string(), "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/91.0.4472.124 Safari/537.36".to_string());
         request_head.build_request_head()
     }
 
-    /// 拷贝数据
-    pub async fn copy<'a, R, W>(&mut self, reader: &'a mut R, writer: &'a mut W) -> io::Result<u64>
+    /// 转发一条 http 请求，返回转发的字节数，连接在请求之间关闭时返回 0。
+    /// 只有请求头会被改写，请求体边读边转发，不会整个缓存在内存中
+    pub async fn copy<'a, R, W>(
+        &mut self,
+        reader: &'a mut R,
+        writer: &'a mut W,
+    ) -> Result<u64, HttpError>
     where
         R: AsyncReadExt + Unpin,
         W: AsyncWriteExt + Unpin,
     {
-        let parsed_byte = self.parse_http_request(reader).await;
-        if parsed_byte.is_empty() {
-            return Ok(0);
+        if self.upgraded {
+            return Ok(self.buf.copy_until_close(reader, writer).await?);
         }
 
-        let write_res = writer.write_all(&parsed_byte).await;
-        if write_res.is_err() {
-            println!("❌写入出错{write_res:?}");
+        let Some(((request_line, headers), _)) =
+            self.buf.read_head(reader, parser_request_head_all).await?
+        else {
             return Ok(0);
+        };
+        let mut request_head = RequestHead {
+            request_line,
+            headers,
+        };
+        let started = Instant::now();
+        let body_length = match request_head.body_length() {
+            Ok(body_length) => body_length,
+            Err(e) => {
+                println!("❌{e}");
+                return Err(HttpError::Reject(400, "Bad Request"));
+            }
+        };
+        if let BodyLength::Length(len) = body_length {
+            if self.body_too_large(len) {
+                return Err(HttpError::Reject(413, "Payload Too Large"));
+            }
+            // 重复的 Content-Length 合并成一个再转发
+            if request_head
+                .get_content_length()
+                .is_some_and(|value| value != len.to_string())
+            {
+                request_head.change_head("Content-Length".to_string(), len.to_string());
+            }
+        }
+
+        let header_bytes = match &self.forwarding {
+            Some(forwarding) => {
+                Self::transformer(&mut request_head, forwarding, &self.host_rewrite)
+            }
+            None => request_head.build_request_head(),
+        };
+        // 先登记请求再转发，后端收到请求头后的响应总能找到对应的请求
+        let mut upgrade = None;
+        if let Some(requests) = &self.requests {
+            let upgrade_tx = request_head.is_upgrade().then(|| {
+                let (upgrade_tx, upgrade_rx) = oneshot::channel();
+                upgrade = Some(upgrade_rx);
+                upgrade_tx
+            });
+            let _ = requests.send(RequestInfo {
+                method: request_head.request_line.method.clone(),
+                path: request_head.request_line.path.clone(),
+                started,
+                upgrade: upgrade_tx,
+            });
+        }
+        writer.write_all(&header_bytes).await?;
+        // 请求体中途出错时后端已经收到了请求头，可能已经开始响应，不能再回复用户错误，只能断开连接
+        let body_len = match body_length {
+            BodyLength::Length(len) => self.buf.copy_length(reader, writer, len).await?,
+            BodyLength::Chunked => self
+                .buf
+                .copy_chunked(reader, writer, self.max_body_size)
+                .await
+                .map_err(abort)?,
+            BodyLength::Close => unreachable!("请求体不会读到连接关闭为止"),
+        };
+        writer.flush().await?;
+
+        if let Some(upgrade) = upgrade {
+            // 后端回复之前用户发来的数据可能已经不是 http，等响应确定之后再继续读取
+            self.upgraded = upgrade.await.unwrap_or(false);
+        }
+
+        Ok(header_bytes.len() as u64 + body_len)
+    }
+}
+
+/// 转发后端的响应，按规则改写响应头并记录每个请求的状态码和耗时
+pub struct ResponseTransformer {
+    rules: Arc<ResponseRules>,
+    /// 已转发、等待响应的请求
+    requests: UnboundedReceiver<RequestInfo>,
+    /// 已读取但还没有转发的数据
+    buf: HttpBuffer,
+    /// 收到 101 之后不再是 http，之后的数据原样转发
+    upgraded: bool,
+}
+
+impl ResponseTransformer {
+    /// 转发一条响应，连接关闭或协议升级后的数据转发完时返回 None。
+    /// 响应头格式错误时返回 502，此时还没有向用户写入这条响应
+    pub async fn copy<'a, R, W>(
+        &mut self,
+        reader: &'a mut R,
+        writer: &'a mut W,
+    ) -> Result<Option<ResponseLog>, HttpError>
+    where
+        R: AsyncReadExt + Unpin,
+        W: AsyncWriteExt + Unpin,
+    {
+        if self.upgraded {
+            self.buf.copy_until_close(reader, writer).await?;
+            return Ok(None);
+        }
+
+        loop {
+            let Some(((status_line, headers), raw_head)) = self
+                .buf
+                .read_head(reader, parser_response_head_all)
+                .await
+                .map_err(bad_gateway)?
+            else {
+                return Ok(None);
+            };
+            let status = status_line.status;
+            if status_line.is_informational() && status != 101 {
+                // 100 Continue 等中间响应，请求还在等待最终响应
+                writer.write_all(&raw_head).await?;
+                writer.flush().await?;
+                continue;
+            }
+
+            // 没有对应请求的响应无法确定响应体的长度，不能猜测
+            let Ok(mut request) = self.requests.try_recv() else {
+                println!("❌后端返回了没有对应请求的响应 {status}");
+                return Err(HttpError::Reject(502, "Bad Gateway"));
+            };
+            if let Some(upgrade) = request.upgrade.take() {
+                let _ = upgrade.send(status == 101);
+            }
+            let body_length = if status == 101 {
+                // 之后的数据不再是 http，下一次调用时原样转发
+                self.upgraded = true;
+                BodyLength::Length(0)
+            } else {
+                response_body_length(status, &headers, &request).map_err(|e| {
+                    println!("❌{e}");
+                    HttpError::Reject(502, "Bad Gateway")
+                })?
+            };
+
+            let head = match self.rules.is_empty() {
+                true => raw_head,
+                false => self.rules.apply(&raw_head),
+            };
+            writer.write_all(&head).await?;
+            // 响应体中途出错时已经向用户写入了响应头，只能断开连接
+            let body_len = match body_length {
+                BodyLength::Length(len) => self.buf.copy_length(reader, writer, len).await?,
+                BodyLength::Chunked => self
+                    .buf
+                    .copy_chunked(reader, writer, None)
+                    .await
+                    .map_err(abort)?,
+                BodyLength::Close => self.buf.copy_until_close(reader, writer).await?,
+            };
+            writer.flush().await?;
+
+            return Ok(Some(ResponseLog {
+                method: request.method,
+                path: request.path,
+                status,
+                latency: request.started.elapsed(),
+                body_len,
+            }));
+        }
+    }
+}
+
+/// 消息体中途出错时对端已经收到了首部，不能再回复错误，转换成连接错误
+fn abort(e: HttpError) -> io::Error {
+    match e {
+        HttpError::Reject(status, reason) => {
+            println!("❌消息体中途出错 {status} {reason}，断开连接");
+            io::Error::new(io::ErrorKind::InvalidData, reason)
+        }
+        HttpError::Io(e) => e,
+    }
+}
+
+/// 后端的响应无法解析时回复用户 502
+fn bad_gateway(e: HttpError) -> HttpError {
+    match e {
+        HttpError::Reject(..) => HttpError::Reject(502, "Bad Gateway"),
+        e => e,
+    }
+}
+
+#[cfg(test)]
+mod transformer_test {
+    use super::*;
+    use crate::parser::{Headers, MAX_HEADER_COUNT, MAX_REQUEST_LINE_LEN};
+    use tokio::time::timeout;
+
+    fn transform_from(
+        request: &[u8],
+        host_rewrite: HostRewrite,
+        forwarding: Forwarding,
+    ) -> Headers {
+        let (_, (request_line, headers)) = parser_request_head_all(request).unwrap();
+        let mut request_head = RequestHead {
+            request_line,
+            headers,
+        };
+        let head = HttpTransformer::transformer(&mut request_head, &forwarding, &host_rewrite);
+        parser_request_head_all(&head).unwrap().1 .1
+    }
+
+    fn transform(request: &[u8], host_rewrite: HostRewrite) -> Headers {
+        let forwarding = Forwarding {
+            user_addr: "10.0.0.1:5000".parse().unwrap(),
+            port: 7002,
+            trusted: false,
+        };
+        transform_from(request, host_rewrite, forwarding)
+    }
+
+    #[test]
+    fn test_host_rewrite() {
+        let request = b"GET / HTTP/1.1\r\nhost: alice.example.com\r\n\r\n";
+
+        let headers = transform(request, HostRewrite::Preserve);
+        assert_eq!(headers.get("Host").unwrap(), "alice.example.com");
+        assert_eq!(
+            headers.get("X-Forwarded-Host").unwrap(),
+            "alice.example.com"
+        );
+
+        let headers = transform(request, HostRewrite::Rewrite("127.0.0.1:3000".to_string()));
+        assert_eq!(headers.get("Host").unwrap(), "127.0.0.1:3000");
+        assert_eq!(
+            headers.get("X-Forwarded-Host").unwrap(),
+            "alice.example.com"
+        );
+        // 原来小写的 host 被替换，不会出现两个 Host
+        assert_eq!(headers.get_all("host").count(), 1);
+    }
+
+    #[test]
+    fn test_build_request_head() {
+        let request = b"POST /a?b=1 HTTP/1.1\r\nhost: example.com\r\naccept: text/html\r\nCookie: a=1\r\nAccept: */*\r\nCookie:b=2\r\ncontent-length: 0\r\n\r\n";
+        let (_, (request_line, headers)) = parser_request_head_all(request).unwrap();
+        let mut request_head = RequestHead {
+            request_line,
+            headers,
+        };
+        // 没有修改时与原始数据完全一致
+        assert_eq!(&request_head.build_request_head()[..], request);
+        assert_eq!(request_head.get_content_length().unwrap(), "0");
+
+        // 改写之后重复的请求头和原来的顺序仍然保留
+        let forwarding = Forwarding {
+            user_addr: "10.0.0.1:5000".parse().unwrap(),
+            port: 7002,
+            trusted: false,
+        };
+        let head = HttpTransformer::transformer(
+            &mut request_head,
+            &forwarding,
+            &HostRewrite::Rewrite("backend".to_string()),
+        );
+        let head = std::str::from_utf8(&head).unwrap();
+        assert!(head.starts_with("POST /a?b=1 HTTP/1.1\r\nHost: backend\r\naccept: text/html\r\nCookie: a=1\r\nAccept: */*\r\nCookie:b=2\r\ncontent-length: 0\r\n"));
+    }
+
+    #[test]
+    fn test_forwarding_header_lines() {
+        // 可信代理转发的请求中，分成多行的转发头按一个列表处理
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 1.1.1.1\r\nX-Forwarded-For: 2.2.2.2\r\n\r\n";
+        let forwarding = Forwarding {
+            user_addr: "10.0.0.1:5000".parse().unwrap(),
+            port: 80,
+            trusted: true,
+        };
+        let headers = transform_from(request, HostRewrite::Preserve, forwarding);
+        assert_eq!(
+            headers.get_all("X-Forwarded-For").collect::<Vec<_>>(),
+            ["1.1.1.1, 2.2.2.2, 10.0.0.1"]
+        );
+        assert_eq!(headers.get("X-Real-IP").unwrap(), "1.1.1.1");
+    }
+
+    #[test]
+    fn test_forwarding_headers() {
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 1.1.1.1\r\nX-Real-IP: 1.1.1.1\r\nForwarded: for=1.1.1.1\r\nX-Forwarded-Proto: https\r\n\r\n";
+
+        // 不可信的地址伪造的转发头被丢弃
+        let headers = transform(request, HostRewrite::Preserve);
+        let header = |name| headers.get(name).unwrap();
+        assert_eq!(header("X-Forwarded-For"), "10.0.0.1");
+        assert_eq!(header("X-Real-IP"), "10.0.0.1");
+        assert_eq!(
+            header("Forwarded"),
+            "for=10.0.0.1;host=example.com;proto=http"
+        );
+        assert_eq!(header("X-Forwarded-Proto"), "http");
+        assert_eq!(header("X-Forwarded-Port"), "7002");
+        assert_eq!(header("X-Forwarded-Host"), "example.com");
+
+        // 可信代理的转发头保留并追加
+        let forwarding = Forwarding {
+            user_addr: "[2001:db8::1]:5000".parse().unwrap(),
+            port: 80,
+            trusted: true,
+        };
+        let headers = transform_from(request, HostRewrite::Preserve, forwarding);
+        let header = |name| headers.get(name).unwrap();
+        assert_eq!(header("X-Forwarded-For"), "1.1.1.1, 2001:db8::1");
+        assert_eq!(header("X-Real-IP"), "1.1.1.1");
+        assert_eq!(
+            header("Forwarded"),
+            "for=1.1.1.1, for=\"[2001:db8::1]\";host=example.com;proto=http"
+        );
+        assert_eq!(header("X-Forwarded-Proto"), "https");
+        assert_eq!(header("X-Forwarded-Port"), "80");
+    }
+
+    fn http_transformer() -> HttpTransformer {
+        let forwarding = Forwarding {
+            user_addr: "10.0.0.1:5000".parse().unwrap(),
+            port: 7002,
+            trusted: false,
+        };
+        HttpTransformer::new(forwarding, HostRewrite::Preserve)
+    }
+
+    /// 逐条转发请求，返回每条请求转发后的内容
+    async fn copy_all(input: &[u8]) -> Vec<Vec<u8>> {
+        // 容量很小的管道，让请求分多次读到
+        let (mut user, mut reader) = io::duplex(7);
+        let input = input.to_vec();
+        tokio::spawn(async move { user.write_all(&input).await });
+
+        let mut transformer = http_transformer();
+        let mut requests = vec![];
+        loop {
+            let mut writer = vec![];
+            if transformer.copy(&mut reader, &mut writer).await.unwrap() == 0 {
+                break requests;
+            }
+            requests.push(writer);
+        }
+    }
+
+    /// 拆分转发后的请求头和请求体
+    fn split_request(request: &[u8]) -> (Headers, &[u8]) {
+        let (body, (_, headers)) = parser_request_head_all(request).unwrap();
+        (headers, body)
+    }
+
+    #[tokio::test]
+    async fn test_copy_chunked() {
+        let chunked = b"5\r\nhello\r\n7;ext=1\r\n world!\r\n0\r\nX-Checksum: 42\r\n\r\n";
+        let mut input =
+            b"POST /upload HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n"
+                .to_vec();
+        input.extend_from_slice(chunked);
+        input.extend_from_slice(
+            b"POST /next HTTP/1.1\r\nHost: example.com\r\nContent-Length: 4\r\n\r\nnext",
+        );
+        input.extend_from_slice(b"GET /last HTTP/1.1\r\nHost: example.com\r\n\r\n");
+
+        let requests = copy_all(&input).await;
+        assert_eq!(requests.len(), 3);
+
+        // chunked 请求体连同 trailer 原样转发
+        let (headers, body) = split_request(&requests[0]);
+        assert_eq!(body, chunked);
+        assert_eq!(headers.get("X-Real-IP").unwrap(), "10.0.0.1");
+
+        // 后面的请求没有和前一个请求体混在一起，同样会被改写
+        let (headers, body) = split_request(&requests[1]);
+        assert_eq!(body, b"next");
+        assert_eq!(headers.get("X-Real-IP").unwrap(), "10.0.0.1");
+        let (headers, body) = split_request(&requests[2]);
+        assert!(body.is_empty());
+        assert_eq!(headers.get("X-Real-IP").unwrap(), "10.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn test_copy_invalid_body() {
+        let copy = |input: &'static [u8]| async move {
+            let mut reader = input;
+            http_transformer().copy(&mut reader, &mut vec![]).await
+        };
+        // 请求体没有读完连接就断开
+        assert!(copy(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc")
+            .await
+            .is_err());
+        assert!(
+            copy(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nab")
+                .await
+                .is_err()
+        );
+        assert!(
+            copy(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n")
+                .await
+                .is_err()
+        );
+        assert!(copy(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_copy_smuggling() {
+        let copy = |input: &'static [u8]| async move {
+            let mut reader = input;
+            let mut writer = vec![];
+            let res = http_transformer().copy(&mut reader, &mut writer).await;
+            // 被拒绝的请求不会转发给后端
+            assert!(res.is_ok() || writer.is_empty());
+            match res {
+                Ok(_) => 200,
+                Err(HttpError::Reject(status, _)) => status,
+                Err(HttpError::Io(e)) => panic!("{e}"),
+            }
+        };
+
+        assert_eq!(copy(b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n").await, 400);
+        assert_eq!(
+            copy(b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\nabcd").await,
+            400
+        );
+        assert_eq!(
+            copy(b"POST / HTTP/1.1\r\nContent-Length: 3, 4\r\n\r\nabcd").await,
+            400
+        );
+        assert_eq!(
+            copy(b"POST / HTTP/1.1\r\nContent-Length: +3\r\n\r\nabc").await,
+            400
+        );
+        assert_eq!(
+            copy(b"POST / HTTP/1.1\r\nContent-Length: abc\r\n\r\n").await,
+            400
+        );
+        assert_eq!(
+            copy(b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999\r\n\r\n").await,
+            400
+        );
+        assert_eq!(
+            copy(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, chunked\r\n\r\n0\r\n\r\n").await,
+            400
+        );
+        assert_eq!(
+            copy(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n0\r\n\r\n").await,
+            400
+        );
+        assert_eq!(
+            copy(b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n").await,
+            400
+        );
+        assert_eq!(
+            copy(b"GET / HTTP/1.1\r\nHost: a.com\r\nHost: b.com\r\n\r\n").await,
+            400
+        );
+        assert_eq!(copy(b"GET / HTTP/1.1\r\nHost : a.com\r\n\r\n").await, 400);
+        assert_eq!(
+            copy(b"GET / HTTP/1.1\r\nX-A: 1\r\n folded\r\n\r\n").await,
+            400
+        );
+        assert_eq!(copy(b"GET /a b HTTP/1.1\r\n\r\n").await, 400);
+
+        let mut long_line = b"GET /".to_vec();
+        long_line.resize(MAX_REQUEST_LINE_LEN + 1, b'a');
+        long_line.extend_from_slice(b" HTTP/1.1\r\n\r\n");
+        let long_line: &'static [u8] = long_line.leak();
+        assert_eq!(copy(long_line).await, 431);
+
+        let mut many_headers = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..=MAX_HEADER_COUNT {
+            many_headers.extend_from_slice(format!("X-{i}: {i}\r\n").as_bytes());
         }
-        let _ = writer.flush().await;
+        many_headers.extend_from_slice(b"\r\n");
+        let many_headers: &'static [u8] = many_headers.leak();
+        assert_eq!(copy(many_headers).await, 431);
+
+        // 相同的重复值合并成一个转发
+        let mut reader: &[u8] =
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nabc";
+        let mut writer = vec![];
+        http_transformer()
+            .copy(&mut reader, &mut writer)
+            .await
+            .unwrap();
+        let (headers, body) = split_request(&writer);
+        assert_eq!(headers.get_all("Content-Length").collect::<Vec<_>>(), ["3"]);
+        assert_eq!(body, b"abc");
+    }
+
+    #[tokio::test]
+    async fn test_copy_limits() {
+        let copy = |input: &'static [u8]| async move {
+            let mut reader = input;
+            let mut writer = vec![];
+            let res = http_transformer()
+                .with_max_body_size(Some(4))
+                .copy(&mut reader, &mut writer)
+                .await;
+            (res, writer)
+        };
+        let status = |res: Result<u64, HttpError>| match res {
+            Err(HttpError::Reject(status, _)) => Some(status),
+            _ => None,
+        };
+
+        let (res, _) = copy(b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nabcd").await;
+        assert!(res.is_ok());
+
+        // 声明的长度超过限制时不会转发给后端
+        let (res, writer) = copy(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nabcde").await;
+        assert_eq!(status(res), Some(413));
+        assert!(writer.is_empty());
+
+        // chunked 请求体超过限制时请求头已经转发，不能再回复 413，只能断开连接
+        let (res, writer) = copy(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n").await;
+        assert!(matches!(res, Err(HttpError::Io(e)) if e.kind() == io::ErrorKind::InvalidData));
+        assert!(writer.starts_with(b"POST / HTTP/1.1\r\n"));
+
+        let (res, _) = copy(b"POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n").await;
+        assert_eq!(status(res), Some(400));
+
+        let mut long_head = b"GET / HTTP/1.1\r\nX-Long: ".to_vec();
+        long_head.resize(MAX_HEAD_LEN * 2, b'a');
+        let mut reader = &long_head[..];
+        let res = http_transformer().copy(&mut reader, &mut vec![]).await;
+        assert_eq!(status(res), Some(431));
+    }
+
+    #[tokio::test]
+    async fn test_body_limit_mid_stream() {
+        let (mut user, mut reader) = io::duplex(1024);
+        let (mut writer, mut backend) = io::duplex(1024);
+        let copy = tokio::spawn(async move {
+            http_transformer()
+                .with_max_body_size(Some(4))
+                .copy(&mut reader, &mut writer)
+                .await
+        });
+        user.write_all(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n")
+            .await
+            .unwrap();
+        // 没有超过限制的块已经到达后端，后端可能已经开始响应
+        let forwarded = read_until(&mut backend, b"3\r\nabc\r\n").await;
+        assert!(forwarded.starts_with(b"POST / HTTP/1.1\r\n"));
+
+        // 之后超过限制时不能再回复 413，否则会和后端的响应混在一起
+        user.write_all(b"2\r\nde\r\n0\r\n\r\n").await.unwrap();
+        let res = timeout(Duration::from_secs(5), copy)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(res, Err(HttpError::Io(e)) if e.kind() == io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn test_response_rules() {
+        let rules = ResponseRules {
+            set: vec![
+                parse_header_field("Strict-Transport-Security: max-age=63072000").unwrap(),
+                parse_header_field("Access-Control-Allow-Origin: *").unwrap(),
+            ],
+            remove: vec!["Server".to_string()],
+        };
+        let head = b"HTTP/1.1 200 OK\r\nserver: nginx\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\nAccess-Control-Allow-Origin: example.com\r\n\r\n";
+        assert_eq!(
+            &rules.apply(head)[..],
+            b"HTTP/1.1 200 OK\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\nStrict-Transport-Security: max-age=63072000\r\nAccess-Control-Allow-Origin: *\r\n\r\n"
+        );
+
+        assert!(parse_header_field("X-Frame-Options").is_err());
+        assert!(parse_header_field(": x").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_copy_responses() {
+        let mut transformer = http_transformer();
+        let mut responses = transformer.response_transformer(Arc::default());
+        let mut requests: &[u8] =
+            b"HEAD /a HTTP/1.1\r\n\r\nPOST /b HTTP/1.1\r\nContent-Length: 1\r\n\r\nxGET /c HTTP/1.1\r\n\r\n";
+        while transformer.copy(&mut requests, &mut vec![]).await.unwrap() > 0 {}
+
+        let input = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nHTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\nHTTP/1.1 404 Not Found\r\n\r\nuntil close";
+        let (mut backend, mut reader) = io::duplex(7);
+        tokio::spawn(async move { backend.write_all(input).await });
+
+        let mut writer = vec![];
+        let mut logs = vec![];
+        while let Some(log) = responses.copy(&mut reader, &mut writer).await.unwrap() {
+            logs.push(log);
+        }
+        // 没有规则时响应原样转发
+        assert_eq!(writer, input);
+        let logs: Vec<_> = logs
+            .iter()
+            .map(|log| {
+                (
+                    log.method.as_str(),
+                    log.path.as_str(),
+                    log.status,
+                    log.body_len,
+                )
+            })
+            .collect();
+        assert_eq!(
+            logs,
+            [
+                // HEAD 的响应没有响应体
+                ("HEAD", "/a", 200, 0),
+                ("POST", "/b", 200, 13),
+                ("GET", "/c", 404, 11)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_copy_invalid_response() {
+        let mut responses = http_transformer().response_transformer(Arc::default());
+        let mut reader: &[u8] = b"SSH-2.0-OpenSSH\r\n\r\n";
+        let res = responses.copy(&mut reader, &mut vec![]).await;
+        assert!(matches!(res, Err(HttpError::Reject(502, _))));
+
+        // 后端在请求之前返回的响应不能和之后的请求配对
+        let mut transformer = http_transformer();
+        let mut responses = transformer.response_transformer(Arc::default());
+        let mut reader: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+        let mut writer = vec![];
+        let res = responses.copy(&mut reader, &mut writer).await;
+        assert!(matches!(res, Err(HttpError::Reject(502, _))));
+        assert!(writer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_early_response() {
+        let mut transformer = http_transformer();
+        let mut responses = transformer.response_transformer(Arc::default());
+        let mut request: &[u8] = b"HEAD /a HTTP/1.1\r\n\r\n";
+        // 后端只读到请求头的开头就回复，此时请求头还没有全部写出
+        let (mut backend_writer, mut backend) = io::duplex(16);
+        let request =
+            tokio::spawn(async move { transformer.copy(&mut request, &mut backend_writer).await });
+        let mut chunk = [0u8; 4];
+        backend.read_exact(&mut chunk).await.unwrap();
+        assert!(!request.is_finished());
+
+        let mut reader: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n";
+        let log = responses.copy(&mut reader, &mut vec![]).await.unwrap();
+        // HEAD 的响应没有响应体，不会等待 5 字节
+        let log = log.unwrap();
+        assert_eq!(
+            (log.method.as_str(), log.path.as_str(), log.body_len),
+            ("HEAD", "/a", 0)
+        );
+        drop(backend);
+        let _ = request.await;
+    }
+
+    /// 在用户和后端之间同时转发请求和响应，返回用户一侧的连接
+    fn proxy_pair(mut transformer: HttpTransformer) -> (io::DuplexStream, io::DuplexStream) {
+        let mut responses = transformer.response_transformer(Arc::default());
+        let (user, proxy_user) = io::duplex(1024);
+        let (proxy_backend, backend) = io::duplex(1024);
+        let (mut user_reader, mut user_writer) = io::split(proxy_user);
+        let (mut backend_reader, mut backend_writer) = io::split(proxy_backend);
+        tokio::spawn(async move {
+            while transformer
+                .copy(&mut user_reader, &mut backend_writer)
+                .await
+                .unwrap_or(0)
+                > 0
+            {}
+        });
+        tokio::spawn(async move {
+            while let Ok(Some(_)) = responses.copy(&mut backend_reader, &mut user_writer).await {}
+        });
+        (user, backend)
+    }
+
+    /// 读取直到数据以 end 结尾
+    async fn read_until<R: AsyncReadExt + Unpin>(reader: &mut R, end: &[u8]) -> Vec<u8> {
+        let mut buf = vec![];
+        while !buf.ends_with(end) {
+            let mut chunk = [0u8; 1024];
+            let size = timeout(Duration::from_secs(5), reader.read(&mut chunk))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(size > 0, "连接意外关闭 {buf:?}");
+            buf.extend_from_slice(&chunk[..size]);
+        }
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_upgrade() {
+        let (mut user, mut backend) = proxy_pair(http_transformer());
+        // 握手之后紧跟着的 WebSocket 帧不能被当作请求头解析
+        user.write_all(b"GET /ws HTTP/1.1\r\nConnection: keep-alive, Upgrade\r\nUpgrade: websocket\r\n\r\n\x81\x05hello")
+            .await
+            .unwrap();
+        let head = read_until(&mut backend, b"\r\n\r\n").await;
+        assert!(head.starts_with(b"GET /ws HTTP/1.1\r\n"));
+        backend
+            .write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(read_until(&mut backend, b"hello").await, b"\x81\x05hello");
+
+        backend.write_all(b"\x81\x05world").await.unwrap();
+        let res = read_until(&mut user, b"world").await;
+        assert!(res.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(res.ends_with(b"\r\n\r\n\x81\x05world"));
+
+        // 升级之后的数据原样双向转发
+        user.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(
+            read_until(&mut backend, b"\r\n\r\n").await,
+            b"GET / HTTP/1.1\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_refused() {
+        let (mut user, mut backend) = proxy_pair(http_transformer());
+        user.write_all(b"GET /ws HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\nGET /next HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        read_until(&mut backend, b"\r\n\r\n").await;
+        backend
+            .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        // 后端拒绝升级，后面的数据仍然按 http 请求处理
+        let next = read_until(&mut backend, b"\r\n\r\n").await;
+        let (headers, _) = split_request(&next);
+        assert!(next.starts_with(b"GET /next HTTP/1.1\r\n"));
+        assert_eq!(headers.get("X-Real-IP").unwrap(), "10.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn test_passthrough() {
+        let (mut user, mut backend) = proxy_pair(HttpTransformer::passthrough());
+        // 请求原样转发，不添加转发头
+        let request =
+            b"GET /ws HTTP/1.1\r\nHost: h\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n";
+        user.write_all(request).await.unwrap();
+        assert_eq!(read_until(&mut backend, b"\r\n\r\n").await, request);
 
-        Ok(parsed_byte.len().try_into().unwrap())
+        // 响应头被拆到多次读取时仍然能识别出升级
+        backend.write_all(b"HTTP/1.1 101 Switching").await.unwrap();
+        backend
+            .write_all(b" Protocols\r\nUpgrade: websocket\r\n\r\n\x81\x05hello")
+            .await
+            .unwrap();
+        let res = read_until(&mut user, b"hello").await;
+        assert!(res.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        user.write_all(b"\x81\x05world").await.unwrap();
+        assert_eq!(read_until(&mut backend, b"world").await, b"\x81\x05world");
     }
 }
@@ -0,0 +1,328 @@
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use crate::{
+    protocol::{RTCPType, TunnelId, TunnelMode},
+    proxy_protocol::ProxyProtocol,
+    transformer::HostRewrite,
+};
+
+/// 用户访问隧道的方式
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TunnelAccess {
+    /// 服务器上的访问端口，0 表示由服务器分配
+    Port(u16),
+    /// 服务器共享 http 端口上的主机名
+    Host(String),
+}
+
+impl Display for TunnelAccess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TunnelAccess::Port(port) => write!(f, "访问端口 {port}"),
+            TunnelAccess::Host(host) => write!(f, "主机名 {host}"),
+        }
+    }
+}
+
+/// 转发给后端时 Host 头的配置
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum HostHeader {
+    /// 保留用户请求中的 Host
+    #[default]
+    Preserve,
+    /// 改写为后端的 `ip:port`
+    Backend,
+    /// 改写为指定的值
+    Value(String),
+}
+
+impl FromStr for HostHeader {
+    type Err = String;
+
+    /// 解析 `preserve`、`backend` 或者指定的 Host
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err("Host 不能为空".to_string()),
+            "preserve" => Ok(HostHeader::Preserve),
+            "backend" => Ok(HostHeader::Backend),
+            host => Ok(HostHeader::Value(host.to_string())),
+        }
+    }
+}
+
+/// 隧道配置：client 本地的后端地址和用户访问隧道的方式
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelSpec {
+    /// 被代理服务器的主机名或 ip
+    pub backend_ip: String,
+    /// 被代理服务器端口
+    pub backend_port: u16,
+    /// 用户访问方式
+    pub access: TunnelAccess,
+    /// Host 头的处理方式
+    pub host_header: HostHeader,
+    /// 连接后端时发送的 PROXY protocol 头，None 表示不发送
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// 转发方式
+    pub mode: TunnelMode,
+}
+
+impl TunnelSpec {
+    /// 服务器转发时对 Host 头的处理
+    pub fn host_rewrite(&self) -> HostRewrite {
+        match &self.host_header {
+            HostHeader::Preserve => HostRewrite::Preserve,
+            // IPv6 地址在 Host 头中需要加上括号
+            HostHeader::Backend if self.backend_ip.contains(':') => {
+                HostRewrite::Rewrite(format!("[{}]:{}", self.backend_ip, self.backend_port))
+            }
+            HostHeader::Backend => {
+                HostRewrite::Rewrite(format!("{}:{}", self.backend_ip, self.backend_port))
+            }
+            HostHeader::Value(host) => HostRewrite::Rewrite(host.clone()),
+        }
+    }
+
+    /// 注册隧道的消息，转发方式和 Host 头的处理方式需要在增加隧道之前设置
+    pub fn register_msgs(&self, id: TunnelId) -> Vec<RTCPType> {
+        let mut messages = vec![];
+        if self.mode != TunnelMode::Http {
+            messages.push(RTCPType::TunnelMode(id, self.mode));
+        }
+        if let HostRewrite::Rewrite(host) = self.host_rewrite() {
+            messages.push(RTCPType::TunnelHostRewrite(id, Some(host)));
+        }
+        messages.push(match (&self.access, id) {
+            (TunnelAccess::Port(port), 0) => RTCPType::Initialize(*port),
+            (TunnelAccess::Port(port), _) => RTCPType::TunnelAdd(id, *port),
+            (TunnelAccess::Host(host), _) => RTCPType::TunnelAddHost(id, host.clone()),
+        });
+        messages
+    }
+}
+
+impl FromStr for TunnelSpec {
+    type Err = String;
+
+    /// 解析 `backend_ip:backend_port:access_port` 或 `backend_ip:backend_port:hostname`，
+    /// 后面可以跟 `,key=value` 形式的选项，例如 `,mode=tcp,proxy=v2`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = s.split(',');
+        let address = options.next().unwrap_or_default();
+        let mut host_header = HostHeader::default();
+        let mut proxy_protocol = None;
+        let mut mode = TunnelMode::default();
+        for option in options {
+            match option.split_once('=') {
+                Some(("host", value)) => host_header = value.parse()?,
+                Some(("proxy", value)) => proxy_protocol = Some(value.parse()?),
+                Some(("mode", value)) => mode = value.parse()?,
+                _ => return Err(format!("隧道 `{s}` 中的选项 `{option}` 无效")),
+            }
+        }
+
+        let mut parts = address.rsplitn(3, ':');
+        let (Some(access), Some(backend_port), Some(backend_ip)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!(
+                "隧道格式错误 `{s}`，应为 `backend_ip:backend_port:access_port` 或 `backend_ip:backend_port:hostname`"
+            ));
+        };
+        if backend_ip.is_empty() {
+            return Err(format!("隧道 `{s}` 缺少后端 ip"));
+        }
+        let parse_port = |port: &str| {
+            port.parse::<u16>()
+                .map_err(|_| format!("隧道 `{s}` 中的端口 `{port}` 无效"))
+        };
+        // 全部是数字时视为端口，主机名不会只包含数字
+        let access = if access.chars().all(|c| c.is_ascii_digit()) {
+            TunnelAccess::Port(parse_port(access)?)
+        } else if access.is_empty() {
+            return Err(format!("隧道 `{s}` 缺少访问端口或主机名"));
+        } else {
+            TunnelAccess::Host(access.to_string())
+        };
+        Ok(Self {
+            backend_ip: backend_ip
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            backend_port: parse_port(backend_port)?,
+            access,
+            host_header,
+            proxy_protocol,
+            mode,
+        })
+    }
+}
+
+/// 重新加载配置时找出新隧道对应的旧隧道，返回每个新隧道对应的旧隧道下标。
+/// 先对应完全相同的隧道，再对应访问方式相同的隧道；
+/// 服务器分配端口的隧道访问方式都相同，改为按后端地址对应
+pub fn pair_tunnels(old: &[TunnelSpec], new: &[TunnelSpec]) -> Vec<Option<usize>> {
+    let mut used = vec![false; old.len()];
+    let mut find = |pred: &dyn Fn(&TunnelSpec) -> bool| {
+        let index = (0..old.len()).find(|&i| !used[i] && pred(&old[i]))?;
+        used[index] = true;
+        Some(index)
+    };
+    let mut pairs: Vec<_> = new.iter().map(|spec| find(&|old| old == spec)).collect();
+    for (pair, spec) in pairs.iter_mut().zip(new) {
+        if pair.is_none() {
+            *pair = find(&|old| match spec.access {
+                TunnelAccess::Port(0) => {
+                    old.access == spec.access
+                        && old.backend_ip == spec.backend_ip
+                        && old.backend_port == spec.backend_port
+                }
+                _ => old.access == spec.access,
+            });
+        }
+    }
+    pairs
+}
+
+/// 新会话开始时按原来的顺序把隧道重新编号为 0..n。
+/// 隧道 id 只在一个会话中有效，重新加载配置移除过 0 号隧道时，重连后 id 最小的隧道成为通过 Initialize 注册的主隧道
+pub fn renumber_tunnels<T>(tunnels: HashMap<TunnelId, T>) -> HashMap<TunnelId, T> {
+    let mut tunnels: Vec<_> = tunnels.into_iter().collect();
+    tunnels.sort_unstable_by_key(|(id, _)| *id);
+    tunnels
+        .into_iter()
+        .enumerate()
+        .map(|(id, (_, tunnel))| (id as TunnelId, tunnel))
+        .collect()
+}
+
+#[cfg(test)]
+mod tunnel_test {
+    use super::*;
+
+    #[test]
+    fn test_parse_tunnel_spec() {
+        let spec: TunnelSpec = "127.0.0.1:3000:7002".parse().unwrap();
+        assert_eq!(
+            spec,
+            TunnelSpec {
+                backend_ip: "127.0.0.1".to_string(),
+                backend_port: 3000,
+                access: TunnelAccess::Port(7002),
+                host_header: HostHeader::Preserve,
+                proxy_protocol: None,
+                mode: TunnelMode::Http,
+            }
+        );
+        let spec: TunnelSpec = "127.0.0.1:3000:alice.example.com".parse().unwrap();
+        assert_eq!(
+            spec.access,
+            TunnelAccess::Host("alice.example.com".to_string())
+        );
+
+        let spec: TunnelSpec = "backend.internal:3000:7002".parse().unwrap();
+        assert_eq!(spec.backend_ip, "backend.internal");
+        let spec: TunnelSpec = "[::1]:3000:7002,host=backend".parse().unwrap();
+        assert_eq!(spec.backend_ip, "::1");
+        assert_eq!(
+            spec.host_rewrite(),
+            HostRewrite::Rewrite("[::1]:3000".to_string())
+        );
+
+        assert!("127.0.0.1:3000".parse::<TunnelSpec>().is_err());
+        assert!("127.0.0.1:3000:".parse::<TunnelSpec>().is_err());
+        assert!(":3000:7002".parse::<TunnelSpec>().is_err());
+        assert!("127.0.0.1:http:7002".parse::<TunnelSpec>().is_err());
+        assert!("127.0.0.1:3000:70000".parse::<TunnelSpec>().is_err());
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let spec: TunnelSpec = "127.0.0.1:3000:7002,host=backend".parse().unwrap();
+        assert_eq!(spec.access, TunnelAccess::Port(7002));
+        assert_eq!(
+            spec.host_rewrite(),
+            HostRewrite::Rewrite("127.0.0.1:3000".to_string())
+        );
+
+        let spec: TunnelSpec = "127.0.0.1:3000:alice,host=example.com:8080"
+            .parse()
+            .unwrap();
+        assert_eq!(spec.access, TunnelAccess::Host("alice".to_string()));
+        assert_eq!(
+            spec.host_rewrite(),
+            HostRewrite::Rewrite("example.com:8080".to_string())
+        );
+
+        let spec: TunnelSpec = "127.0.0.1:3000:7002,host=preserve".parse().unwrap();
+        assert_eq!(spec.host_rewrite(), HostRewrite::Preserve);
+
+        let spec: TunnelSpec = "127.0.0.1:22:7022,mode=tcp,proxy=v2".parse().unwrap();
+        assert_eq!(spec.proxy_protocol, Some(ProxyProtocol::V2));
+        assert_eq!(spec.mode, TunnelMode::Tcp);
+        assert_eq!(spec.host_rewrite(), HostRewrite::Preserve);
+
+        assert!("127.0.0.1:3000:7002,host=".parse::<TunnelSpec>().is_err());
+        assert!("127.0.0.1:3000:7002,proxy=v3"
+            .parse::<TunnelSpec>()
+            .is_err());
+        assert!("127.0.0.1:3000:7002,mode=udp"
+            .parse::<TunnelSpec>()
+            .is_err());
+        assert!("127.0.0.1:3000:7002,tls=on".parse::<TunnelSpec>().is_err());
+    }
+
+    #[test]
+    fn test_pair_tunnels() {
+        let parse = |specs: &[&str]| -> Vec<TunnelSpec> {
+            specs.iter().map(|spec| spec.parse().unwrap()).collect()
+        };
+        let old = parse(&[
+            "127.0.0.1:3000:0",
+            "127.0.0.1:3001:0",
+            "127.0.0.1:3002:7002",
+        ]);
+
+        // 完全相同的隧道优先对应，不会被访问方式相同的隧道抢走
+        let new = parse(&["127.0.0.1:3001:0", "127.0.0.1:3000:0"]);
+        assert_eq!(pair_tunnels(&old, &new), vec![Some(1), Some(0)]);
+
+        // 访问方式相同时改后端
+        let new = parse(&["127.0.0.1:4002:7002"]);
+        assert_eq!(pair_tunnels(&old, &new), vec![Some(2)]);
+
+        // 服务器分配端口的隧道只按后端地址对应
+        let new = parse(&["127.0.0.1:3001:0,mode=tcp", "127.0.0.1:4000:0"]);
+        assert_eq!(pair_tunnels(&old, &new), vec![Some(1), None]);
+
+        // 每个旧隧道只对应一次
+        let new = parse(&["127.0.0.1:3002:7002", "127.0.0.1:3002:7002"]);
+        assert_eq!(pair_tunnels(&old, &new), vec![Some(2), None]);
+    }
+
+    #[test]
+    fn test_renumber_tunnels() {
+        let a: TunnelSpec = "127.0.0.1:3000:7002".parse().unwrap();
+        let b: TunnelSpec = "127.0.0.1:3001:7003,mode=tcp".parse().unwrap();
+        // 重新加载时移除 0 号隧道 a 再加回来，a 使用新的 id 2
+        let tunnels = HashMap::from([(1, b.clone()), (2, a.clone())]);
+
+        // 重连后重新编号，每个隧道都会重新注册，id 最小的隧道通过 Initialize 注册
+        let tunnels = renumber_tunnels(tunnels);
+        assert_eq!(tunnels, HashMap::from([(0, b), (1, a)]));
+        assert!(matches!(
+            tunnels[&0].register_msgs(0)[..],
+            [
+                RTCPType::TunnelMode(0, TunnelMode::Tcp),
+                RTCPType::Initialize(7003)
+            ]
+        ));
+        assert!(matches!(
+            tunnels[&1].register_msgs(1)[..],
+            [RTCPType::TunnelAdd(1, 7002)]
+        ));
+
+        // 隧道全部移除之后没有需要注册的隧道
+        assert!(renumber_tunnels(HashMap::<TunnelId, TunnelSpec>::new()).is_empty());
+    }
+}
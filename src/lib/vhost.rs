@@ -0,0 +1,180 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{Arc, Mutex},
+};
+
+/// 注册主机名失败的原因
+#[derive(Debug, PartialEq, Eq)]
+pub enum RegisterError {
+    /// 主机名已被其他隧道注册
+    InUse(String),
+    /// 主机名无效或不在服务器的域名下
+    NotAllowed(String),
+}
+
+impl Display for RegisterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegisterError::InUse(host) => write!(f, "host {host} is already registered"),
+            RegisterError::NotAllowed(host) => write!(f, "host {host} is not allowed"),
+        }
+    }
+}
+
+/// 规范化主机名：去掉端口和末尾的点并转为小写，包含非法字符时返回 None
+pub fn normalize_host(host: &str) -> Option<String> {
+    let host = host.trim();
+    let host = match host.strip_prefix('[') {
+        // ipv6 字面量 `[::1]:8080`
+        Some(rest) => &host[..rest.find(']')? + 2],
+        None => host.split(':').next()?,
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let valid = !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '[' | ']' | ':'));
+    valid.then_some(host)
+}
+
+/// 按主机名把用户请求路由到隧道
+///
+/// 配置了域名时，client 只能注册该域名下的主机名，注册单个标签（如 `alice`）等同于 `alice.<domain>`。
+#[derive(Debug)]
+pub struct HostRouter<T> {
+    routes: Arc<Mutex<HashMap<String, T>>>,
+    domain: Option<String>,
+}
+
+impl<T: Clone> HostRouter<T> {
+    pub fn new(domain: Option<&str>) -> Self {
+        Self {
+            routes: Arc::new(Mutex::new(HashMap::new())),
+            domain: domain.and_then(normalize_host),
+        }
+    }
+
+    /// 注册主机名，返回的注册信息被丢弃时自动注销
+    pub fn register(&self, name: &str, target: T) -> Result<HostRoute<T>, RegisterError> {
+        let host = self.full_host(name)?;
+        let mut routes = self.routes.lock().unwrap();
+        if routes.contains_key(&host) {
+            return Err(RegisterError::InUse(host));
+        }
+        routes.insert(host.clone(), target);
+        Ok(HostRoute {
+            host,
+            routes: self.routes.clone(),
+        })
+    }
+
+    /// 根据请求的 Host 头查找隧道
+    pub fn lookup(&self, host: &str) -> Option<T> {
+        let host = normalize_host(host)?;
+        self.routes.lock().unwrap().get(&host).cloned()
+    }
+
+    /// 已注册的主机名数量
+    pub fn len(&self) -> usize {
+        self.routes.lock().unwrap().len()
+    }
+
+    /// 是否没有注册任何主机名
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn full_host(&self, name: &str) -> Result<String, RegisterError> {
+        let host =
+            normalize_host(name).ok_or_else(|| RegisterError::NotAllowed(name.to_string()))?;
+        let Some(domain) = &self.domain else {
+            return Ok(host);
+        };
+        if !host.contains('.') {
+            return Ok(format!("{host}.{domain}"));
+        }
+        match host.strip_suffix(domain.as_str()) {
+            Some(label) if label.ends_with('.') && label.len() > 1 => Ok(host),
+            _ => Err(RegisterError::NotAllowed(host)),
+        }
+    }
+}
+
+/// 已注册的主机名，丢弃时注销
+#[derive(Debug)]
+pub struct HostRoute<T> {
+    host: String,
+    routes: Arc<Mutex<HashMap<String, T>>>,
+}
+
+impl<T> HostRoute<T> {
+    /// 规范化之后的完整主机名
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+}
+
+impl<T> Drop for HostRoute<T> {
+    fn drop(&mut self) {
+        self.routes.lock().unwrap().remove(&self.host);
+    }
+}
+
+#[cfg(test)]
+mod vhost_test {
+    use super::*;
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(
+            normalize_host("Alice.Example.com:8080").unwrap(),
+            "alice.example.com"
+        );
+        assert_eq!(normalize_host("example.com.").unwrap(), "example.com");
+        assert_eq!(normalize_host("[::1]:8080").unwrap(), "[::1]");
+        assert!(normalize_host("").is_none());
+        assert!(normalize_host("bad host").is_none());
+        assert!(normalize_host("a/b").is_none());
+    }
+
+    #[test]
+    fn test_router_register_lookup() {
+        let router = HostRouter::new(None);
+        let route = router.register("Alice.example.com", 1).unwrap();
+        assert_eq!(route.host(), "alice.example.com");
+        assert_eq!(router.lookup("alice.example.com:80"), Some(1));
+        assert_eq!(router.lookup("bob.example.com"), None);
+        assert_eq!(
+            router.register("alice.example.com", 2).unwrap_err(),
+            RegisterError::InUse("alice.example.com".to_string())
+        );
+
+        // 注销之后可以被重新注册
+        drop(route);
+        assert!(router.is_empty());
+        assert!(router.register("alice.example.com", 2).is_ok());
+    }
+
+    #[test]
+    fn test_router_domain() {
+        let router = HostRouter::new(Some("tunnel.example.com"));
+        let alice = router.register("alice", 1).unwrap();
+        assert_eq!(alice.host(), "alice.tunnel.example.com");
+        assert_eq!(router.lookup("ALICE.tunnel.example.com"), Some(1));
+
+        assert!(router.register("bob.tunnel.example.com", 2).is_ok());
+        assert!(matches!(
+            router.register("evil.com", 3),
+            Err(RegisterError::NotAllowed(_))
+        ));
+        assert!(matches!(
+            router.register("tunnel.example.com", 3),
+            Err(RegisterError::NotAllowed(_))
+        ));
+        assert!(matches!(
+            router.register("xtunnel.example.com", 3),
+            Err(RegisterError::NotAllowed(_))
+        ));
+    }
+}